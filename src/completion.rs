@@ -0,0 +1,89 @@
+use std::collections::HashSet;
+
+use kilo_ed::Position;
+use crate::editor_syntax::Keyword;
+use crate::palette;
+use crate::row::Row;
+
+pub struct CompletionItem {
+    pub label: String,
+    pub insert: String,     // text that replaces the word being completed
+    pub detail: String,
+}
+
+// An open completion popup: candidates for the word that starts at `start`,
+// narrowed down as more of it is typed.
+pub struct Completion {
+    pub start: Position,
+    pub items: Vec<CompletionItem>,
+}
+
+impl Completion {
+    // Items matching what has been typed so far. Those starting with it
+    // come first; otherwise the items keep their order, which is already
+    // the best first.
+    pub fn matching(&self, typed: &str) -> Vec<&CompletionItem> {
+        let typed_lower = typed.to_lowercase();
+        let mut matches: Vec<(bool, &CompletionItem)> = self.items.iter()
+            .filter(|item| palette::fuzzy_score(typed, &item.label).is_some())
+            .map(|item| (item.label.to_lowercase().starts_with(&typed_lower), item))
+            .collect();
+        matches.sort_by_key(|(prefix, _)| !prefix);
+        matches.into_iter().map(|(_, item)| item).collect()
+    }
+}
+
+fn is_word_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+// identifiers in a line with their byte offsets
+fn identifiers(line: &str) -> Vec<(usize, &str)> {
+    let mut words = Vec::new();
+    let mut start = None;
+    for (i, c) in line.char_indices().chain([(line.len(), ' ')]) {
+        match (start, is_word_char(c)) {
+            (None, true) => start = Some(i),
+            (Some(s), false) => {
+                start = None;
+                if !line[s..].starts_with(|c: char| c.is_ascii_digit()) {
+                    words.push((s, &line[s..i]));
+                }
+            }
+            _ => {}
+        }
+    }
+    words
+}
+
+// Completions from the buffer itself: its identifiers, nearest to `at`
+// first, then the filetype's keywords. The word being typed at `at` isn't
+// offered.
+pub fn buffer_words(rows: &[Row], at: Position, keywords: &[Keyword]) -> Vec<CompletionItem> {
+    let y = at.y as usize;
+    let x = at.x as usize;
+    // rows by distance from the cursor: y, y-1, y+1, y-2, ...
+    let nearby = (0..rows.len()).flat_map(|d| [y.checked_sub(d), Some(y + d).filter(|_| d > 0)])
+        .flatten()
+        .filter(|row| *row < rows.len());
+    let mut seen = HashSet::new();
+    let mut items = Vec::new();
+    for row in nearby {
+        let mut words: Vec<(usize, &str)> = identifiers(&rows[row].chars).into_iter()
+            .filter(|(start, word)| row != y || !(*start..=start + word.len()).contains(&x))
+            .collect();
+        words.sort_by_key(|(start, _)| start.abs_diff(x));
+        for (_, word) in words {
+            if seen.insert(word) {
+                items.push(CompletionItem { label: word.to_string(), insert: word.to_string(), detail: String::new() });
+            }
+        }
+    }
+    for keyword in keywords {
+        let (Keyword::Basic(word) | Keyword::Type(word)) = keyword;
+        if seen.insert(word) {
+            items.push(CompletionItem { label: word.clone(), insert: word.clone(), detail: String::from("keyword") });
+        }
+    }
+    items
+}
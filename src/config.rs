@@ -0,0 +1,159 @@
+use std::env;
+use std::path::{Path, PathBuf};
+
+use kilo_ed::StdResult;
+use crate::fileio::Backup;
+use crate::statusline;
+
+// $XDG_CONFIG_HOME/kilo-ed, falling back to ~/.config/kilo-ed
+pub fn config_dir() -> Option<PathBuf> {
+    xdg_dir("XDG_CONFIG_HOME", ".config")
+}
+
+pub fn config_file(name: &str) -> Option<PathBuf> {
+    config_dir().map(|dir| dir.join(name))
+}
+
+// $XDG_STATE_HOME/kilo-ed, falling back to ~/.local/state/kilo-ed
+pub fn state_file(name: &str) -> Option<PathBuf> {
+    xdg_dir("XDG_STATE_HOME", ".local/state").map(|dir| dir.join(name))
+}
+
+fn xdg_dir(var: &str, fallback: &str) -> Option<PathBuf> {
+    let base = match env::var_os(var) {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(env::var_os("HOME")?).join(fallback),
+    };
+    Some(base.join("kilo-ed"))
+}
+
+// Calls f for every non-blank, non-comment line of a config file and reports
+// the first error with its line number. A missing file is not an error.
+pub fn for_each_line<F>(path: &Path, mut f: F) -> StdResult<(), String>
+    where F: FnMut(&str) -> StdResult<(), String> {
+    let text = match std::fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(format!("{}: {e}", path.display())),
+    };
+
+    let mut first_error = None;
+    for (lineno, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if let Err(msg) = f(line) {
+            first_error.get_or_insert(format!("{}:{}: {msg}", path.display(), lineno + 1));
+        }
+    }
+    match first_error {
+        Some(msg) => Err(msg),
+        None => Ok(()),
+    }
+}
+
+pub struct Settings {
+    pub vi_mode: bool,
+    pub tab_stop: usize,
+    pub backup: Backup,
+    pub swap: bool,
+    pub treesitter: bool,   // tree-sitter highlighting where there's a grammar
+    pub spell: bool,
+    pub spell_lang: String,
+    pub fold_method: String,    // auto, indent or brace
+    pub theme: String,
+    pub status_left: String,
+    pub status_right: String,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            vi_mode: false,
+            tab_stop: 8,
+            backup: Backup::None,
+            swap: true,
+            treesitter: true,
+            spell: false,
+            spell_lang: String::from("en_US"),
+            fold_method: String::from("auto"),
+            theme: String::from("default"),
+            status_left: String::from(statusline::DEFAULT_LEFT),
+            status_right: String::from(statusline::DEFAULT_RIGHT),
+        }
+    }
+}
+
+impl Settings {
+    // `name = value` lines; a bare `name` means `name = true`
+    pub fn load(&mut self, path: &Path) -> StdResult<(), String> {
+        for_each_line(path, |line| match line.split_once('=') {
+            Some((name, value)) => self.set(name.trim(), value.trim()),
+            None => self.set(line, "true"),
+        })
+    }
+
+    pub fn set(&mut self, name: &str, value: &str) -> StdResult<(), String> {
+        match name {
+            "vi" | "vi-mode" => self.vi_mode = parse_bool(value)?,
+            "tabstop" | "ts" => {
+                self.tab_stop = match value.parse::<usize>() {
+                    Ok(n) if (1..=32).contains(&n) => n,
+                    _ => return Err(format!("tabstop must be between 1 and 32, got '{value}'")),
+                }
+            }
+            "swap" => self.swap = parse_bool(value)?,
+            "treesitter" => self.treesitter = parse_bool(value)?,
+            "spell" => self.spell = parse_bool(value)?,
+            "foldmethod" => {
+                if !matches!(value, "auto" | "indent" | "brace") {
+                    return Err(format!("foldmethod must be auto, indent or brace, got '{value}'"));
+                }
+                self.fold_method = value.to_string();
+            }
+            "spelllang" => {
+                if value.is_empty() {
+                    return Err(String::from("spelllang needs a language, like en_US"));
+                }
+                self.spell_lang = value.to_string();
+            }
+            "backup" => {
+                self.backup = match value {
+                    "off" | "none" | "false" => Backup::None,
+                    "on" | "tilde" | "true" => Backup::Tilde,
+                    "timestamp" | "timestamped" => Backup::Timestamped,
+                    _ => return Err(format!("backup must be off, tilde or timestamp, got '{value}'")),
+                }
+            }
+            "theme" => {
+                if value.is_empty() {
+                    return Err(String::from("theme needs a name"));
+                }
+                self.theme = value.to_string();
+            }
+            "status-left" | "status-right" => {
+                statusline::validate(value)?;
+                if name == "status-left" {
+                    self.status_left = value.to_string();
+                } else {
+                    self.status_right = value.to_string();
+                }
+            }
+            _ => return Err(format!("unknown setting '{name}'")),
+        }
+        Ok(())
+    }
+
+    pub fn names() -> &'static [&'static str] {
+        &["backup", "foldmethod", "spell", "spelllang", "status-left", "status-right", "swap", "tabstop", "theme", "treesitter", "vi"]
+    }
+}
+
+fn parse_bool(value: &str) -> StdResult<bool, String> {
+    match value {
+        "true" | "on" | "yes" | "1" => Ok(true),
+        "false" | "off" | "no" | "0" => Ok(false),
+        _ => Err(format!("expected true or false, got '{value}'")),
+    }
+}
@@ -0,0 +1,245 @@
+// Line diff (Myers' O(ND) algorithm) and unified diff output.
+
+// The search keeps a copy of its state for every step, so it is given up
+// beyond this many differing lines and the rest reported as one change.
+const MAX_COST: isize = 500;
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Edit {
+    Equal(usize, usize),    // index in a, index in b
+    Delete(usize),          // index in a
+    Insert(usize),          // index in b
+}
+
+// A run of changed lines. A pure deletion has new_len 0 and sits before
+// line new_start of b; a pure insertion has old_len 0.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct Hunk {
+    pub old_start: usize,
+    pub old_len: usize,
+    pub new_start: usize,
+    pub new_len: usize,
+}
+
+impl Hunk {
+    // the row of b its marker is on; deletions are marked on the row above
+    pub fn row(&self) -> usize {
+        if self.new_len == 0 { self.new_start.saturating_sub(1) } else { self.new_start }
+    }
+
+    // the rows of b that are marked, at least one
+    pub fn marked(&self) -> std::ops::Range<usize> {
+        self.row()..self.row() + self.new_len.max(1)
+    }
+}
+
+// the marker row of the first hunk after row y, or the last one before it
+pub fn adjacent(hunks: &[Hunk], y: usize, forward: bool) -> Option<usize> {
+    if forward {
+        hunks.iter().map(Hunk::row).find(|row| *row > y)
+    } else {
+        hunks.iter().map(Hunk::row).rev().find(|row| *row < y)
+    }
+}
+
+pub fn diff<T: PartialEq>(a: &[T], b: &[T]) -> Vec<Edit> {
+    // common prefix and suffix don't need the expensive part
+    let prefix = a.iter().zip(b).take_while(|(x, y)| x == y).count();
+    let suffix = a[prefix..].iter().rev()
+        .zip(b[prefix..].iter().rev())
+        .take_while(|(x, y)| x == y)
+        .count();
+
+    let mut edits: Vec<Edit> = (0..prefix).map(|i| Edit::Equal(i, i)).collect();
+    let middle = myers(&a[prefix..a.len() - suffix], &b[prefix..b.len() - suffix]);
+    edits.extend(middle.into_iter().map(|edit| match edit {
+        Edit::Equal(x, y) => Edit::Equal(x + prefix, y + prefix),
+        Edit::Delete(x) => Edit::Delete(x + prefix),
+        Edit::Insert(y) => Edit::Insert(y + prefix),
+    }));
+    edits.extend((0..suffix).map(|i| Edit::Equal(a.len() - suffix + i, b.len() - suffix + i)));
+    edits
+}
+
+fn myers<T: PartialEq>(a: &[T], b: &[T]) -> Vec<Edit> {
+    let n = a.len() as isize;
+    let m = b.len() as isize;
+    let max = n + m;
+    let replace_all = || (0..a.len()).map(Edit::Delete).chain((0..b.len()).map(Edit::Insert)).collect();
+    if n == 0 || m == 0 {
+        return replace_all();
+    }
+    let offset = max + 1;
+    let mut v = vec![0isize; 2 * max as usize + 3];
+    // for each d, the part of v that step d can read: k in -d-1..=d+1
+    let mut trace: Vec<Vec<isize>> = Vec::new();
+
+    let mut found = false;
+    'outer: for d in 0..=max.min(MAX_COST) {
+        trace.push(v[(offset - d - 1) as usize..=(offset + d + 1) as usize].to_vec());
+        let mut k = -d;
+        while k <= d {
+            let i = (k + offset) as usize;
+            let mut x = if k == -d || (k != d && v[i - 1] < v[i + 1]) { v[i + 1] } else { v[i - 1] + 1 };
+            let mut y = x - k;
+            while x < n && y < m && a[x as usize] == b[y as usize] {
+                x += 1;
+                y += 1;
+            }
+            v[i] = x;
+            if x >= n && y >= m {
+                found = true;
+                break 'outer;
+            }
+            k += 2;
+        }
+    }
+
+    if !found {
+        return replace_all();
+    }
+
+    let mut edits = Vec::new();
+    let (mut x, mut y) = (n, m);
+    for (d, v) in trace.iter().enumerate().rev() {
+        let d = d as isize;
+        let at = |k: isize| v[(k + d + 1) as usize];
+        let k = x - y;
+        let prev_k = if k == -d || (k != d && at(k - 1) < at(k + 1)) { k + 1 } else { k - 1 };
+        let prev_x = at(prev_k);
+        let prev_y = prev_x - prev_k;
+        while x > prev_x && y > prev_y {
+            x -= 1;
+            y -= 1;
+            edits.push(Edit::Equal(x as usize, y as usize));
+        }
+        if d > 0 {
+            if x == prev_x {
+                y -= 1;
+                edits.push(Edit::Insert(y as usize));
+            } else {
+                x -= 1;
+                edits.push(Edit::Delete(x as usize));
+            }
+        }
+    }
+    edits.reverse();
+    edits
+}
+
+pub fn hunks(edits: &[Edit]) -> Vec<Hunk> {
+    let mut hunks = Vec::new();
+    let mut current: Option<Hunk> = None;
+    let (mut old, mut new) = (0, 0);
+
+    for edit in edits {
+        match edit {
+            Edit::Equal(..) => {
+                if let Some(hunk) = current.take() {
+                    hunks.push(hunk);
+                }
+                old += 1;
+                new += 1;
+            }
+            Edit::Delete(_) | Edit::Insert(_) => {
+                let hunk = current.get_or_insert(Hunk { old_start: old, old_len: 0, new_start: new, new_len: 0 });
+                if matches!(edit, Edit::Delete(_)) {
+                    hunk.old_len += 1;
+                    old += 1;
+                } else {
+                    hunk.new_len += 1;
+                    new += 1;
+                }
+            }
+        }
+    }
+    if let Some(hunk) = current {
+        hunks.push(hunk);
+    }
+    hunks
+}
+
+// `diff -u` style output with `context` lines around each change
+pub fn unified(a: &[&str], b: &[&str], a_name: &str, b_name: &str, context: usize) -> Vec<String> {
+    let hunks = hunks(&diff(a, b));
+    let mut out = Vec::new();
+    if hunks.is_empty() {
+        return out;
+    }
+    out.push(format!("--- {a_name}"));
+    out.push(format!("+++ {b_name}"));
+
+    let mut i = 0;
+    while i < hunks.len() {
+        // merge hunks whose context would overlap
+        let mut j = i;
+        while j + 1 < hunks.len() &&
+            hunks[j + 1].old_start <= hunks[j].old_start + hunks[j].old_len + 2 * context {
+            j += 1;
+        }
+        let old_start = hunks[i].old_start.saturating_sub(context);
+        let new_start = hunks[i].new_start - (hunks[i].old_start - old_start);
+        let old_end = (hunks[j].old_start + hunks[j].old_len + context).min(a.len());
+        let new_end = (hunks[j].new_start + hunks[j].new_len + context).min(b.len());
+        out.push(format!("@@ -{},{} +{},{} @@",
+                         old_start + 1, old_end - old_start, new_start + 1, new_end - new_start));
+
+        let mut old = old_start;
+        for hunk in &hunks[i..=j] {
+            while old < hunk.old_start {
+                out.push(format!(" {}", a[old]));
+                old += 1;
+            }
+            out.extend(a[hunk.old_start..hunk.old_start + hunk.old_len].iter().map(|line| format!("-{line}")));
+            out.extend(b[hunk.new_start..hunk.new_start + hunk.new_len].iter().map(|line| format!("+{line}")));
+            old = hunk.old_start + hunk.old_len;
+        }
+        while old < old_end {
+            out.push(format!(" {}", a[old]));
+            old += 1;
+        }
+        i = j + 1;
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn apply(a: &[usize], b: &[usize], edits: &[Edit]) -> Vec<usize> {
+        edits.iter()
+            .filter_map(|edit| match edit {
+                Edit::Equal(x, _) => Some(a[*x]),
+                Edit::Insert(y) => Some(b[*y]),
+                Edit::Delete(_) => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn finds_the_shortest_edit() {
+        let (a, b) = ([1, 2, 3, 4, 5, 6], [1, 3, 4, 7, 6, 8]);
+        let edits = diff(&a, &b);
+        assert_eq!(apply(&a, &b, &edits), b);
+        assert_eq!(hunks(&edits), [
+            Hunk { old_start: 1, old_len: 1, new_start: 1, new_len: 0 },
+            Hunk { old_start: 4, old_len: 1, new_start: 3, new_len: 1 },
+            Hunk { old_start: 6, old_len: 0, new_start: 5, new_len: 1 },
+        ]);
+    }
+
+    #[test]
+    fn gives_up_on_large_differences() {
+        // every other line changed: far more than MAX_COST apart
+        let a: Vec<usize> = (0..10_000).collect();
+        let b: Vec<usize> = (0..10_000).map(|i| if i % 2 == 0 { i } else { i + 100_000 }).collect();
+        let edits = diff(&a, &b);
+        assert_eq!(apply(&a, &b, &edits), b);
+        assert_eq!(hunks(&edits), [Hunk { old_start: 1, old_len: 9_999, new_start: 1, new_len: 9_999 }]);
+
+        // most of a file deleted costs nothing
+        let edits = diff(&a, &a[..10]);
+        assert_eq!(hunks(&edits), [Hunk { old_start: 10, old_len: 9_990, new_start: 10, new_len: 0 }]);
+    }
+}
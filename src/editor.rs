@@ -1,13 +1,43 @@
+use std::collections::HashMap;
 use std::io:: Result;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::time::{Instant, Duration};
-use crossterm::{terminal};
-use crossterm::event::{ KeyCode, KeyEvent, KeyModifiers};
+use crossterm::event::{ KeyCode, KeyEvent, KeyModifiers, MouseButton, MouseEvent, MouseEventKind};
 use errno::errno;
 use crate::keyboard::*;
 use crate::screen::*;
 use crate::row::*;
 use crate::editor_syntax::*;
+use crate::keymap::*;
+use crate::config;
+use crate::config::Settings;
+use crate::vi::*;
+use crate::macros::Macros;
+use crate::palette;
+use crate::palette::Arg;
+use crate::fileio;
+use crate::fileio::{Backup, FileStamp};
+use crate::swap;
+use crate::diff;
+use crate::fileformat::{FileFormat, LineEnding};
+use crate::theme::Theme;
+use crate::git;
+use crate::statusline;
+use crate::lsp;
+use crate::lsp::{Diagnostic, LspClient, Message, Request};
+use crate::completion;
+use crate::completion::{Completion, CompletionItem};
+use crate::treesitter::SyntaxTree;
+use crate::spell;
+use crate::spell::Dictionary;
+use crate::snippet;
+use crate::fold;
+use crate::fold::Folds;
+use crate::marks::{JumpList, Marks};
+use crate::history;
+use crate::history::{FileState, History};
+use crate::session;
+use crate::session::Session;
 
 use kilo_ed::*;
 use crate::editor::SearchDirection::{Backward, Forward};
@@ -17,7 +47,8 @@ enum PromptKey {
     Escape,
     Next,
     Previous,
-    Char(char),
+    Complete,
+    Edit,
 }
 
 enum SearchDirection {
@@ -26,6 +57,11 @@ enum SearchDirection {
 }
 
 const KILO_QUIT_TIMES: usize = 3;
+const MAX_SEARCHES: usize = 50;
+const SWAP_IDLE: Duration = Duration::from_secs(1);
+const SWAP_INTERVAL: Duration = Duration::from_secs(10);
+// how often to look for language server messages while waiting for keys
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
 
 #[derive(Copy, Clone)]
 pub enum EditorKey {
@@ -53,24 +89,139 @@ pub struct Editor {
     saved_hl: Option<usize>,
     hldb: Vec<EditorSyntax>,
     syntax_ind: Option<usize>,   // index into hldb
+    keymap: Keymap,
+    pending: Vec<Key>,           // keys of an unfinished chord
+    vi: Option<Vi>,              // modal editing, when enabled
+    anchor: Option<Position>,    // other end of the selection from the cursor
+    register: String,            // last deleted or yanked text
+    register_linewise: bool,
+    macros: Macros,
+    search_failed: bool,
+    settings: Settings,
+    popup: Option<Popup>,
+    drag_start: Option<Position>,
+    version: u64,                // bumped on every change to the buffer
+    swap_version: u64,           // version last written to the swap file
+    swap_time: Instant,
+    swap_file: Option<PathBuf>,  // swap file this editor wrote
+    found_swap: Option<PathBuf>, // swap file left over from another session
+    disk_stamp: Option<FileStamp>,  // the file as last loaded or saved
+    format: FileFormat,          // line endings etc. to write back
+    branch: Option<String>,      // git branch of the file, for the status bar
+    head_lines: Option<Vec<String>>, // the file as committed in HEAD
+    hunks: Vec<diff::Hunk>,      // changes against head_lines
+    hunks_version: u64,
+    lsp_servers: HashMap<String, String>,  // filetype -> server command
+    lsp: Option<LspClient>,
+    lsp_version: u64,            // version the server last heard about
+    diagnostics: Vec<Diagnostic>,
+    completion: Option<Completion>,
+    hover_open: bool,            // the popup shows hover text
+    syntax_tree: Option<SyntaxTree>,
+    dictionary: Option<Dictionary>,
+    snippet: Option<snippet::Session>,
+    folds: Folds,
+    marks: Marks,
+    jumps: JumpList,
+    history: History,
+    searches: Vec<String>,      // oldest first
+    search_recall: usize,       // how far back Tab has gone in the search prompt
+    session: Option<String>,    // saved on exit
+    session_view: Option<session::Buffer>,  // restored when the editor starts
+    session_unopened: Vec<session::Buffer>, // the session's other files, kept in it
 }
 
 impl Editor {
     pub fn with_file <P: AsRef<Path> + ToString>(filename: P) -> Result<Self> {
         let fn_filename = filename.to_string();
-        let lines:Vec<String> = std::fs::read_to_string(filename)
-            .expect("Unable to open file")
-            .split('\n')
-            .map(|x| x.into())
-            .collect();
-       Editor::build(&lines, fn_filename)
+        let (lines, format) = match Editor::read_lines(&filename) {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                let mut editor = Editor::build(&[], fn_filename)?;
+                editor.set_status_msg(format!("New file: {}", editor.filename));
+                return Ok(editor);
+            }
+            Err(e) => {
+                // start empty and unnamed so a save can't clobber the file
+                let mut editor = Editor::new()?;
+                editor.set_status_msg(format!("Can't open {fn_filename}: {e}"));
+                return Ok(editor);
+            }
+        };
+       let mut editor = Editor::build(&lines, fn_filename)?;
+       editor.format = format;
+       editor.found_swap = swap::swap_path(&editor.filename).filter(|path| path.exists());
+       editor.disk_stamp = FileStamp::of(Path::new(&editor.filename));
+       Ok(editor)
+    }
+
+    // Starts with a saved session's file and searches. A name with nothing
+    // saved under it starts a new session. There is one buffer, so only the
+    // first file that still exists is opened; the others are reported, and
+    // saved with the session again.
+    pub fn with_session(name: &str) -> Result<Self> {
+        let session = match Session::load(name) {
+            Ok(session) => session.unwrap_or_default(),
+            Err(msg) => {
+                let mut editor = Editor::new()?;
+                editor.set_status_msg(msg);
+                return Ok(editor);
+            }
+        };
+        let (found, missing): (Vec<_>, Vec<_>) = session.buffers.into_iter()
+            .partition(|buffer| buffer.path.exists());
+        let mut found = found.into_iter();
+        let mut editor = match found.next() {
+            Some(buffer) => {
+                let mut editor = Editor::with_file(buffer.path.display().to_string())?;
+                editor.session_view = Some(buffer);
+                editor
+            }
+            None => Editor::new()?,
+        };
+        editor.searches = session.searches;
+        editor.session = Some(name.to_string());
+        let paths = |buffers: &[session::Buffer]| {
+            buffers.iter().map(|buffer| buffer.path.display().to_string()).collect::<Vec<_>>().join(", ")
+        };
+        let unopened: Vec<_> = found.collect();
+        let mut skipped = Vec::new();
+        if !missing.is_empty() {
+            skipped.push(format!("skipped missing {}", paths(&missing)));
+        }
+        if !unopened.is_empty() {
+            skipped.push(format!("didn't open {} (one file at a time)", paths(&unopened)));
+        }
+        if !skipped.is_empty() {
+            editor.set_status_msg(format!("Session {name}: {}", skipped.join("; ")));
+        }
+        editor.session_unopened = unopened;
+        Ok(editor)
     }
 
     pub fn new() -> Result<Self> {
         Editor::build(&[], "")
     }
 
+    fn read_lines<P: AsRef<Path>>(filename: P) -> Result<(Vec<String>, FileFormat)> {
+        FileFormat::decode_bytes(&std::fs::read(filename)?)
+    }
+
+    fn make_rows(data: &[String], syntax: Option<&EditorSyntax>) -> Vec<Row> {
+        let mut rows = Vec::new();
+        for line in  data {
+             let row = Row::new(line.to_string(), syntax);
+             rows.push(row);
+        };
+        rows
+    }
+
     fn build<T: Into<String>>(data: &[String], filename: T) -> Result<Self> {
+        Ok(Editor::build_on(Screen::new()?, data, filename))
+    }
+
+    // everything but finding out the terminal's size
+    fn build_on<T: Into<String>>(mut screen: Screen, data: &[String], filename: T) -> Self {
         let filename: String = filename.into();
         let hldb = EditorSyntax::new();
         let syntax_ind = Editor::find_highlight(&hldb, filename.as_str());
@@ -82,28 +233,54 @@ impl Editor {
             None
         };
 
-        Ok(Self {
+        let mut status_msg = String::from("HELP: Ctrl-S = Save | Ctrl-Q = Quit");
+        let mut keymap = Keymap::default();
+        if let Some(path) = config::config_file("keymap") {
+            if let Err(msg) = keymap.load(&path) {
+                status_msg = msg;
+            }
+        }
+        let mut settings = Settings::default();
+        if let Some(path) = config::config_file("config") {
+            if let Err(msg) = settings.load(&path) {
+                status_msg = msg;
+            }
+        }
+        let vi = if settings.vi_mode { Some(Vi::new()) } else { None };
+        set_tab_stop(settings.tab_stop);
+        match Theme::load(&settings.theme) {
+            Ok(theme) => screen.set_theme(theme),
+            Err(msg) => status_msg = msg,
+        }
+        let dictionary = match settings.spell.then(|| Dictionary::load(&settings.spell_lang)) {
+            Some(Ok(dictionary)) => Some(dictionary),
+            Some(Err(msg)) => {
+                status_msg = msg;
+                None
+            }
+            None => None,
+        };
+        let lsp_servers = lsp::servers().unwrap_or_else(|msg| {
+            status_msg = msg;
+            HashMap::new()
+        });
+        let mut macros = Macros::new();
+        if let Some(path) = config::config_file("macros") {
+            if let Err(msg) = macros.load(&path) {
+                status_msg = msg;
+            }
+        }
+
+        Self {
             filename,
-            status_msg: String::from("HELP: Ctrl-S = Save | Ctrl-Q = Quit"),
+            status_msg,
             status_time: Instant::now(),
-            screen: Screen::new()?,
-            keyboard: Keyboard {},
+            screen,
+            keyboard: Keyboard::new(),
             cursor: Position::default(),
             render_x: 0,
             dirty: false,
-            rows: if data.is_empty() {
-                Vec::new()
-            } else {
-                let mut rows = Vec::new();
-                for line in  data {
-                     let row = Row::new(line.to_string(), syntax);
-                     rows.push(row);
-                };
-                if rows.last().unwrap().len() == 0 {
-                    rows.pop();
-                }
-                rows
-            },
+            rows: Editor::make_rows(data, syntax),
             rowoff: 0,
             coloff: 0,
             quit_time: KILO_QUIT_TIMES,
@@ -112,286 +289,2438 @@ impl Editor {
             saved_hl: None,
             hldb,
             syntax_ind,
-        })
+            keymap,
+            pending: Vec::new(),
+            vi,
+            anchor: None,
+            register: String::new(),
+            register_linewise: false,
+            macros,
+            search_failed: false,
+            settings,
+            popup: None,
+            drag_start: None,
+            version: 0,
+            swap_version: 0,
+            swap_time: Instant::now(),
+            swap_file: None,
+            found_swap: None,
+            disk_stamp: None,
+            format: FileFormat::default(),
+            branch: None,
+            head_lines: None,
+            hunks: Vec::new(),
+            hunks_version: 0,
+            lsp_servers,
+            lsp: None,
+            lsp_version: 0,
+            diagnostics: Vec::new(),
+            completion: None,
+            hover_open: false,
+            syntax_tree: None,
+            dictionary,
+            snippet: None,
+            folds: Folds::default(),
+            marks: Marks::default(),
+            jumps: JumpList::default(),
+            history: History::load(),
+            searches: Vec::new(),
+            search_recall: 0,
+            session: None,
+            session_view: None,
+            session_unopened: Vec::new(),
+        }
+    }
+
+    // keyboard
+    pub fn process_keypress(&mut self) -> Result<bool> {
+        let key = match self.keyboard.read() {
+            Ok(Input::Key(c)) => Key::from(c),
+            Ok(Input::Mouse(mouse_event)) => {
+                self.process_mouse(mouse_event);
+                return Ok(false);
+            }
+            Ok(Input::Resize(width, height)) => {
+                self.resize(width, height);
+                return Ok(false);
+            }
+            Ok(Input::FocusGained) => {
+                // there may have been a commit or checkout in another window
+                self.update_git();
+                self.check_disk_changes();
+                return Ok(false);
+            }
+            Err(_) => {
+                self.die("Unable to read from keyboard");
+                unreachable!();
+            }
+        };
+
+        if self.hover_open {
+            self.hover_open = false;
+            self.popup = None;
+        }
+        if self.completion.is_some() && self.completion_key(key) {
+            return Ok(false);
+        }
+        if self.snippet.is_some() && self.snippet_key(key) {
+            return Ok(false);
+        }
+
+        if self.pending.is_empty() {
+            if let Some(vi) = self.vi.as_mut() {
+                match vi.feed(key) {
+                    Action::PassThrough => {},
+                    action => return self.vi_action(action),
+                }
+            }
+        }
+
+        self.pending.push(key);
+        match self.keymap.lookup(&self.pending) {
+            Lookup::Command(command) => {
+                let keys = std::mem::take(&mut self.pending);
+                if command == Command::RecordMacro && self.keyboard.is_recording() {
+                    // keep the keys that stop the recording out of the macro
+                    self.macros.last = self.keyboard.stop_recording(keys.len());
+                    self.set_status_msg(format!("Recorded macro of {} keys", self.macros.last.len()));
+                    return Ok(false);
+                }
+                return self.execute(command);
+            }
+            Lookup::Prefix => {
+                self.set_status_msg(format!("{}-", chord_to_string(&self.pending)));
+            }
+            Lookup::Unbound => {
+                let keys = std::mem::take(&mut self.pending);
+                match keys[..] {
+                    [key] if key.printable().is_some() => {
+                        self.anchor = None;
+                        self.insert_char(key.printable().unwrap());
+                    }
+                    _ => self.set_status_msg(format!("{} is not bound", chord_to_string(&keys))),
+                }
+            }
+        }
+        Ok(false)
+    }
+
+    // mouse
+    fn process_mouse(&mut self, event: MouseEvent) {
+        const SCROLL_LINES: u16 = 3;
+        let bounds = self.screen.bounds();
+        match event.kind {
+            MouseEventKind::Down(MouseButton::Left) => {
+                if let Some(pos) = self.screen_to_buffer(event.column, event.row) {
+                    self.cursor = pos;
+                    self.drag_start = Some(pos);
+                    self.anchor = None;
+                    if let Some(vi) = self.vi.as_mut() {
+                        if vi.mode == Mode::Visual {
+                            vi.mode = Mode::Normal;
+                        }
+                    }
+                }
+            }
+            MouseEventKind::Drag(MouseButton::Left) => {
+                // keep dragging even when the pointer leaves the text area
+                let row = event.row.min(bounds.y.saturating_sub(1));
+                if let (Some(start), Some(pos)) = (self.drag_start, self.screen_to_buffer(event.column, row)) {
+                    self.cursor = pos;
+                    self.anchor = Some(start);
+                    if let Some(vi) = self.vi.as_mut() {
+                        if vi.mode == Mode::Normal {
+                            vi.mode = Mode::Visual;
+                        }
+                    }
+                }
+            }
+            MouseEventKind::Up(MouseButton::Left) => self.drag_start = None,
+            MouseEventKind::ScrollUp => {
+                let rowoff = (0..SCROLL_LINES).fold(self.rowoff as usize, |y, _| self.folds.prev_visible(y));
+                self.rowoff = rowoff as u16;
+                let bottom = self.folds.skip_visible(rowoff, bounds.y.saturating_sub(1) as usize);
+                self.cursor.y = self.cursor.y.min(bottom as u16);
+            }
+            MouseEventKind::ScrollDown => {
+                let max_rowoff = self.rows.len().saturating_sub(1);
+                let rowoff = self.folds.skip_visible(self.rowoff as usize, SCROLL_LINES as usize).min(max_rowoff);
+                self.rowoff = self.visible_row(rowoff as u16);
+                self.cursor.y = self.cursor.y.max(self.rowoff).min(self.rows.len() as u16);
+            }
+            _ => {}
+        }
+        self.cursor = self.clamp_position(self.cursor);
+    }
+
+    fn resize(&mut self, width: u16, height: u16) {
+        self.screen.resize(width, height);
+        let bounds = self.screen.bounds();
+        if let Some(popup) = self.popup.as_mut() {
+            popup.y = popup.y.min(bounds.y.saturating_sub(popup.height()));
+        }
+        self.scroll();
+    }
+
+    // buffer position under a screen cell; None outside the text area
+    fn screen_to_buffer(&self, column: u16, row: u16) -> Option<Position> {
+        if row >= self.screen.bounds().y {
+            return None;
+        }
+        if self.rows.is_empty() {
+            return Some(Position::default());
+        }
+        let y = self.folds.skip_visible(self.rowoff as usize, row as usize).min(self.rows.len() - 1) as u16;
+        let column = column.saturating_sub(self.screen.gutter_width());
+        let x = self.rows[y as usize].rx_to_cx((column + self.coloff) as usize);
+        Some(Position { x, y })
+    }
+
+    // returns true when the editor should quit
+    pub fn execute(&mut self, command: Command) -> Result<bool> {
+        if self.vi.is_none() && !matches!(command, Command::Copy | Command::Cut) {
+            self.anchor = None;
+        }
+        match command {
+            Command::Quit => {
+                if self.dirty && self.quit_time > 0 {
+                    let keys = self.keymap.keys_for(Command::Quit)
+                        .map(|keys| chord_to_string(&keys))
+                        .unwrap_or_else(|| "quit".to_string());
+                    self.set_status_msg(
+                        format!("Warning!!!  File has unsaved changes.\
+                                Press {} {} more time to quit", keys, self.quit_time));
+                    self.quit_time -= 1;
+                    return Ok(false)
+                } else {
+                    return Ok(true)
+                }
+            }
+            Command::Save => self.save(),
+            Command::Find => self.find(),
+            Command::Redraw => self.screen.invalidate(),
+            Command::DeleteBack => self.del_char(),
+            Command::DeleteForward => {
+                self.move_cursor(EditorKey::Right);
+                self.del_char();
+            }
+            Command::Newline => self.insert_newline(),
+            Command::InsertTab => {
+                if !self.expand_snippet() {
+                    self.insert_char('\t');
+                }
+            }
+            Command::Home => self.move_to_home(),
+            Command::End => self.move_to_end(),
+            Command::MoveUp => self.move_cursor(EditorKey::Up),
+            Command::MoveDown => self.move_cursor(EditorKey::Down),
+            Command::MoveLeft => self.move_cursor(EditorKey::Left),
+            Command::MoveRight => self.move_cursor(EditorKey::Right),
+            Command::PageUp | Command::PageDown => {
+                self.jumps.push(self.cursor);
+                let bounds = self.screen.bounds();
+                if command == Command::PageUp {
+                    self.cursor.y = self.rowoff;
+                } else {
+                    let bottom = self.folds.skip_visible(self.rowoff as usize, bounds.y.saturating_sub(1) as usize);
+                    self.cursor.y = bottom.min(self.rows.len()) as u16;
+                }
+
+                for _ in 0..bounds.y {
+                    self.move_cursor(
+                        if command == Command::PageUp {
+                            EditorKey::Up
+                        } else {
+                            EditorKey::Down
+                        }
+                    );
+                }
+            }
+            Command::ToggleVi => {
+                self.anchor = None;
+                if self.vi.take().is_none() {
+                    self.vi = Some(Vi::new());
+                    self.set_status_msg("vi mode on");
+                } else {
+                    self.set_status_msg("vi mode off");
+                }
+            }
+            Command::RecordMacro => {
+                if !self.keyboard.is_playing() {
+                    self.keyboard.start_recording();
+                    self.set_status_msg("Recording macro...");
+                }
+            }
+            Command::PlayMacro => {
+                if let Some(times) = self.prompt_count("Play macro how many times") {
+                    self.keyboard.play(&self.macros.last, times);
+                }
+            }
+            Command::SaveMacro => self.save_macro(),
+            Command::RunMacro => {
+                let Some(name) = self.prompt("Run macro".to_string(), None) else {
+                    return Ok(false);
+                };
+                let Some(keys) = self.macros.get(name.trim()).cloned() else {
+                    self.set_status_msg(format!("No macro named '{}'", name.trim()));
+                    return Ok(false);
+                };
+                if let Some(times) = self.prompt_count("Run macro how many times") {
+                    self.keyboard.play(&keys, times);
+                }
+            }
+            Command::Palette => {
+                if let Some(line) = self.command_line("Command") {
+                    return self.run_command_line(line.trim());
+                }
+            }
+            Command::Copy | Command::Cut => {
+                if let Some((start, end)) = self.selection() {
+                    let end = self.char_end(end);
+                    let (op, verb) = if command == Command::Copy {
+                        (Operator::Yank, "copied")
+                    } else {
+                        (Operator::Delete, "cut")
+                    };
+                    self.operate_range(op, start, end);
+                    self.set_status_msg(format!("{} characters {verb}", self.register.len()));
+                } else {
+                    self.set_status_msg("Nothing selected");
+                }
+                self.anchor = None;
+            }
+            Command::Paste => self.paste(false, 1),
+            Command::LineEndingsLf => self.set_line_ending(LineEnding::Lf),
+            Command::LineEndingsCrlf => self.set_line_ending(LineEnding::CrLf),
+            Command::NextHunk => self.goto_hunk(true),
+            Command::PreviousHunk => self.goto_hunk(false),
+            Command::RevertHunk => self.revert_hunk(),
+            Command::Hover => self.ask_lsp(Request::Hover),
+            // without a language server the buffer's words will do
+            Command::Complete if self.lsp.is_none() => self.complete_word(),
+            Command::Complete => self.ask_lsp(Request::Completion),
+            Command::CompleteWord => self.complete_word(),
+            Command::GotoDefinition => self.ask_lsp(Request::Definition),
+            Command::SpellSuggest => self.spell_suggest(),
+            Command::NextMisspelling => self.next_misspelling(),
+            Command::Fold => self.fold(),
+            Command::Unfold => {
+                if !self.folds.open(self.cursor.y as usize) {
+                    self.set_status_msg("No fold here");
+                }
+            }
+            Command::ToggleFold => {
+                if !self.folds.open(self.cursor.y as usize) {
+                    self.fold();
+                }
+            }
+            Command::FoldAll => self.fold_all(0),
+            Command::UnfoldAll => self.folds.clear(),
+            Command::SetMark => {
+                if let Some(name) = self.prompt("Set mark".to_string(), None) {
+                    self.set_mark(name.trim());
+                }
+            }
+            Command::GotoMark => {
+                let names: Vec<&str> = self.marks.iter().map(|(name, _)| name.as_str()).collect();
+                if names.is_empty() {
+                    self.set_status_msg("No marks set");
+                } else if let Some(name) = self.prompt(format!("Go to mark ({})", names.join(" ")), None) {
+                    self.goto_mark(name.trim());
+                }
+            }
+            Command::JumpBack => match self.jumps.back(self.cursor) {
+                Some(pos) => self.cursor = self.clamp_position(pos),
+                None => self.set_status_msg("At the start of the jump list"),
+            },
+            Command::JumpForward => match self.jumps.forward() {
+                Some(pos) => self.cursor = self.clamp_position(pos),
+                None => self.set_status_msg("At the end of the jump list"),
+            },
+            Command::RecentFiles => self.recent_files(),
+            Command::SaveSession => {
+                let name = match self.session.clone() {
+                    Some(name) => Some(name),
+                    None => self.prompt("Save session as".to_string(), None).map(|name| name.trim().to_string()),
+                };
+                if let Some(name) = name {
+                    self.save_session(&name);
+                }
+            }
+            Command::Nop => {}
+        }
+        Ok(false)
+    }
+
+    // command line
+    fn command_line(&mut self, prompt_str: &str) -> Option<String> {
+        self.update_command_popup("");
+        let line = self.prompt(prompt_str.to_string(), Some(Editor::command_line_callback));
+        self.popup = None;
+        line
+    }
+
+    fn command_line_callback(&mut self, buffer: &mut String, key: PromptKey) {
+        match key {
+            PromptKey::Escape => return,
+            PromptKey::Enter => {
+                // a partial name runs the best match
+                let word = buffer.split_whitespace().next().unwrap_or("");
+                if !word.is_empty() && !buffer.contains(' ') && !self.is_command(word) {
+                    if let Some(value) = self.popup.as_ref().and_then(|popup| popup.selected_value()) {
+                        *buffer = value.to_string();
+                    }
+                }
+                return;
+            }
+            PromptKey::Next => {
+                if let Some(popup) = self.popup.as_mut() {
+                    popup.select_next();
+                }
+                return;
+            }
+            PromptKey::Previous => {
+                if let Some(popup) = self.popup.as_mut() {
+                    popup.select_previous();
+                }
+                return;
+            }
+            PromptKey::Complete => self.complete_command_line(buffer),
+            PromptKey::Edit => {}
+        }
+        self.update_command_popup(buffer);
+    }
+
+    fn is_command(&self, word: &str) -> bool {
+        palette::command_names().contains(&word) ||
+            (self.vi.is_some() && (EX_COMMANDS.contains(&word) || word.parse::<usize>().is_ok()))
+    }
+
+    fn argument_candidates(&self, command: &str, arg: &str) -> Vec<String> {
+        let names: Vec<String> = match palette::arg_for(command) {
+            Arg::File => return palette::complete_path(arg),
+            Arg::Setting => Settings::names().iter().map(|name| format!("{name}=")).collect(),
+            Arg::Syntax => self.hldb.iter()
+                .map(|syntax| syntax.filetype.clone())
+                .chain(std::iter::once("none".to_string()))
+                .collect(),
+            Arg::Mark => self.marks.iter().map(|(name, _)| name.clone()).collect(),
+            Arg::Session => session::names(),
+            Arg::Number | Arg::None => Vec::new(),
+        };
+        names.into_iter().filter(|name| name.starts_with(arg)).collect()
+    }
+
+    fn update_command_popup(&mut self, buffer: &str) {
+        let (items, selected) = match buffer.split_once(' ') {
+            None => {
+                let names = palette::command_names();
+                let items: Vec<(String, String)> = palette::fuzzy_filter(buffer, &names).into_iter()
+                    .map(|name| {
+                        let detail = match Command::from_name(name).and_then(|c| self.keymap.keys_for(c)) {
+                            Some(keys) => chord_to_string(&keys),
+                            None => palette::help(name).to_string(),
+                        };
+                        (name.to_string(), detail)
+                    })
+                    .collect();
+                (items, Some(0))
+            }
+            Some((command, arg)) => {
+                let items = self.argument_candidates(command, arg.trim_start()).into_iter()
+                    .map(|candidate| (candidate, String::new()))
+                    .collect();
+                (items, None)
+            }
+        };
+
+        self.popup = if items.is_empty() {
+            None
+        } else {
+            let mut popup = Popup { items, selected, x: 0, y: 0 };
+            popup.y = self.screen.bounds().y.saturating_sub(popup.height());
+            Some(popup)
+        };
+    }
+
+    fn complete_command_line(&mut self, buffer: &mut String) {
+        let selected = self.popup.as_ref()
+            .and_then(|popup| popup.selected_value())
+            .map(|value| value.to_string());
+
+        match buffer.clone().split_once(' ') {
+            None => {
+                let names = palette::command_names();
+                let best = selected.or_else(|| {
+                    palette::fuzzy_filter(buffer, &names).first().map(|name| name.to_string())
+                });
+                if let Some(name) = best {
+                    let space = if palette::arg_for(&name) == Arg::None { "" } else { " " };
+                    *buffer = format!("{name}{space}");
+                }
+            }
+            Some((command, arg)) => {
+                let candidates = self.argument_candidates(command, arg.trim_start());
+                let completion = match selected {
+                    Some(value) => value,
+                    None if candidates.len() == 1 => candidates[0].clone(),
+                    None => palette::common_prefix(&candidates),
+                };
+                if completion.len() > arg.trim_start().len() {
+                    *buffer = format!("{command} {completion}");
+                }
+            }
+        }
+    }
+
+    fn run_command_line(&mut self, line: &str) -> Result<bool> {
+        let (name, arg) = match line.split_once(' ') {
+            Some((name, arg)) => (name, arg.trim()),
+            None => (line, ""),
+        };
+        match name {
+            "" => {}
+            "open" => self.open(arg),
+            "saveas" => {
+                if arg.is_empty() {
+                    self.set_status_msg("saveas needs a file name");
+                } else {
+                    self.filename = arg.to_string();
+                    self.disk_stamp = None;
+                    self.select_syntax_highlight();
+                    self.save();
+                }
+            }
+            "set" => self.set_option(arg),
+            "goto" => match arg.parse::<usize>() {
+                Ok(line) => self.jump_to(self.motion_target(Motion::GotoLine(line), 1)),
+                Err(_) => self.set_status_msg(format!("goto needs a line number, got '{arg}'")),
+            },
+            "syntax" => self.set_syntax(arg),
+            "foldlevel" => match arg.parse::<usize>() {
+                Ok(level) => self.fold_all(level),
+                Err(_) => self.set_status_msg(format!("foldlevel needs a number, got '{arg}'")),
+            },
+            "mark" => self.set_mark(arg),
+            "jump" => self.goto_mark(arg),
+            "session" => self.save_session(arg),
+            _ => match Command::from_name(name) {
+                Some(command) => return self.execute(command),
+                None => self.set_status_msg(format!("Unknown command: {name}")),
+            }
+        }
+        Ok(false)
+    }
+
+    fn set_option(&mut self, arg: &str) {
+        if arg.is_empty() {
+            let backup = match self.settings.backup {
+                Backup::None => "off",
+                Backup::Tilde => "tilde",
+                Backup::Timestamped => "timestamp",
+            };
+            self.set_status_msg(format!("backup={backup} tabstop={} theme={} vi={}",
+                                        self.settings.tab_stop, self.settings.theme, self.settings.vi_mode));
+            return;
+        }
+        let result = match arg.split_once('=') {
+            Some((name, value)) => self.settings.set(name.trim(), value.trim()),
+            None => match arg.strip_prefix("no") {
+                Some(name) if Settings::names().contains(&name) => self.settings.set(name, "false"),
+                _ => self.settings.set(arg, "true"),
+            }
+        };
+        if let Err(msg) = result {
+            self.set_status_msg(msg);
+            return;
+        }
+
+        set_tab_stop(self.settings.tab_stop);
+        match Theme::load(&self.settings.theme) {
+            Ok(theme) => self.screen.set_theme(theme),
+            Err(msg) => self.set_status_msg(msg),
+        }
+        let syntax = self.get_syntax_data();
+        for row in self.rows.iter_mut() {
+            row.render_row(syntax.as_ref());
+        }
+        self.reset_syntax_tree();
+        self.update_dictionary();
+        if self.settings.vi_mode != self.vi.is_some() {
+            self.vi = if self.settings.vi_mode { Some(Vi::new()) } else { None };
+            self.anchor = None;
+        }
+    }
+
+    fn set_syntax(&mut self, name: &str) {
+        if name == "none" || name == "off" {
+            self.syntax_ind = None;
+        } else if let Some(ind) = self.hldb.iter().position(|syntax| syntax.filetype == name) {
+            self.syntax_ind = Some(ind);
+        } else {
+            self.set_status_msg(format!("Unknown filetype: {name}"));
+            return;
+        }
+        self.rehighlight();
+        self.start_lsp();
+    }
+
+    fn open(&mut self, filename: &str) {
+        if filename.is_empty() {
+            self.set_status_msg("open needs a file name");
+            return;
+        }
+        if self.dirty {
+            self.set_status_msg("No write since last change, save first");
+            return;
+        }
+        let (lines, format) = match Editor::read_lines(filename) {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => (Vec::new(), FileFormat::default()),
+            Err(e) => {
+                self.set_status_msg(format!("Can't open {filename}: {e}"));
+                return;
+            }
+        };
+        self.remember_file();
+        self.remove_swap();
+        self.filename = filename.to_string();
+        self.syntax_ind = Editor::find_highlight(&self.hldb, filename);
+        let syntax = self.get_syntax_data();
+        self.rows = Editor::make_rows(&lines, syntax.as_ref());
+        self.reset_syntax_tree();
+        self.folds.clear();
+        self.marks.clear();
+        self.jumps.clear();
+        self.format = format;
+        self.cursor = Position::default();
+        self.rowoff = 0;
+        self.coloff = 0;
+        self.anchor = None;
+        self.dirty = false;
+        self.restore_file();
+        self.found_swap = swap::swap_path(filename).filter(|path| path.exists());
+        self.disk_stamp = FileStamp::of(Path::new(filename));
+        self.update_git();
+        self.start_lsp();
+        self.check_swap();
+    }
+
+    // external changes
+    fn changed_on_disk(&self) -> bool {
+        match &self.disk_stamp {
+            Some(stamp) => stamp.changed(Path::new(&self.filename)) == Some(true),
+            None => false,
+        }
+    }
+
+    // Reloads an unmodified buffer when the file changed on disk; asks
+    // what to do when there are unsaved changes too.
+    fn check_disk_changes(&mut self) {
+        if !self.changed_on_disk() {
+            return;
+        }
+        if !self.dirty {
+            self.reload();
+            self.set_status_msg("File changed on disk, reloaded");
+            return;
+        }
+        loop {
+            match self.prompt_choice(
+                "File changed on disk: (r)eload and lose changes, (k)eep buffer, (d)iff", "rkd") {
+                Some('r') => {
+                    self.reload();
+                    break;
+                }
+                Some('d') => self.show_disk_diff(),
+                _ => {
+                    // don't ask again until it changes once more
+                    self.disk_stamp = FileStamp::of(Path::new(&self.filename));
+                    break;
+                }
+            }
+        }
+    }
+
+    // false if the save should not go ahead
+    fn confirm_overwrite(&mut self) -> bool {
+        if !self.changed_on_disk() {
+            return true;
+        }
+        loop {
+            match self.prompt_choice(
+                "File changed on disk since it was read: (o)verwrite, (r)eload, (d)iff, (Esc) cancel", "ord") {
+                Some('o') => return true,
+                Some('r') => {
+                    self.reload();
+                    return false;
+                }
+                Some('d') => self.show_disk_diff(),
+                _ => {
+                    self.set_status_msg("Save aborted");
+                    return false;
+                }
+            }
+        }
+    }
+
+    fn show_disk_diff(&mut self) {
+        let disk = Editor::read_lines(&self.filename).map(|(lines, _)| lines).unwrap_or_default();
+        let disk: Vec<&str> = disk.iter().map(|line| line.as_str()).collect();
+        let buffer: Vec<&str> = self.rows.iter().map(|row| row.chars.as_str()).collect();
+        let lines = diff::unified(&disk, &buffer, &format!("{} (disk)", self.filename), "buffer", 3);
+        if lines.is_empty() {
+            self.set_status_msg("No differences");
+        } else {
+            self.view_text("diff against disk", lines);
+        }
+    }
+
+    fn reload(&mut self) {
+        let (lines, format) = match Editor::read_lines(&self.filename) {
+            Ok(file) => file,
+            Err(e) => {
+                self.set_status_msg(format!("Can't reload {}: {e}", self.filename));
+                return;
+            }
+        };
+        let syntax = self.get_syntax_data();
+        self.rows = Editor::make_rows(&lines, syntax.as_ref());
+        self.reset_syntax_tree();
+        self.folds.clear();
+        self.format = format;
+        self.cursor.y = self.cursor.y.min(self.rows.len() as u16);
+        self.cursor = self.clamp_position(self.cursor);
+        self.anchor = None;
+        self.version = self.version.wrapping_add(1);
+        self.dirty = false;
+        self.remove_swap();
+        self.disk_stamp = FileStamp::of(Path::new(&self.filename));
+    }
+
+    fn set_line_ending(&mut self, line_ending: LineEnding) {
+        if self.format.line_ending != line_ending {
+            self.format.line_ending = line_ending;
+            self.mark_dirty();
+        }
+        self.set_status_msg(format!("Line endings: {}", line_ending.name()));
+    }
+
+    pub fn mark_dirty(&mut self) {
+        self.dirty = true;
+        self.version = self.version.wrapping_add(1);
+    }
+
+    // swap file
+    // Writes unsaved changes to the swap file when idle, or every
+    // SWAP_INTERVAL while typing continuously.
+    fn update_swap(&mut self, idle: bool) {
+        if !self.settings.swap || !self.dirty || self.version == self.swap_version {
+            return;
+        }
+        if !idle && self.swap_time.elapsed() < SWAP_INTERVAL {
+            return;
+        }
+        let Some(path) = swap::swap_path(&self.filename) else {
+            return;
+        };
+        if self.swap_file.as_ref().is_some_and(|old| *old != path) {
+            self.remove_swap();
+        }
+        if swap::write(&path, self.cursor, &self.rows_to_string()).is_ok() {
+            self.swap_file = Some(path);
+            self.swap_version = self.version;
+            self.swap_time = Instant::now();
+        }
+    }
+
+    fn remove_swap(&mut self) {
+        if let Some(path) = self.swap_file.take() {
+            swap::remove(&path);
+        }
+        self.swap_version = self.version;
+    }
+
+    fn check_swap(&mut self) {
+        let Some(path) = self.found_swap.take() else {
+            return;
+        };
+        let swap = match swap::read(&path) {
+            Ok(swap) => swap,
+            Err(e) => {
+                self.set_status_msg(format!("Ignoring {}: {e}", path.display()));
+                return;
+            }
+        };
+        if swap::is_running(swap.pid) {
+            self.settings.swap = false;
+            self.set_status_msg(format!("Also being edited by kilo-ed (pid {}), swap file disabled", swap.pid));
+            return;
+        }
+
+        loop {
+            let choice = self.prompt_choice(
+                "Found a swap file with unsaved changes: (r)ecover, (d)iff, (x) delete, (Esc) ignore", "rdx");
+            match choice {
+                Some('r') => {
+                    let (lines, _) = FileFormat::decode(&swap.text);
+                    let syntax = self.get_syntax_data();
+                    self.rows = Editor::make_rows(&lines, syntax.as_ref());
+                    self.reset_syntax_tree();
+                    self.folds.clear();
+                    self.cursor = swap.cursor;
+                    self.cursor.y = self.cursor.y.min(self.rows.len() as u16);
+                    self.cursor = self.clamp_position(self.cursor);
+                    self.mark_dirty();
+                    // the next swap write takes over the old file
+                    self.swap_file = Some(path);
+                    self.set_status_msg("Recovered from swap file, save to keep the changes");
+                    break;
+                }
+                Some('d') => {
+                    let current = self.rows_to_string();
+                    let current: Vec<&str> = current.lines().collect();
+                    let recovered: Vec<&str> = swap.text.lines().collect();
+                    let lines = diff::unified(&current, &recovered, &self.filename, "swap file", 3);
+                    if lines.is_empty() {
+                        self.set_status_msg("The swap file matches the file on disk");
+                    } else {
+                        self.view_text("diff against swap file", lines);
+                    }
+                }
+                Some('x') => {
+                    swap::remove(&path);
+                    self.set_status_msg("Swap file deleted");
+                    break;
+                }
+                _ => break,
+            }
+        }
+    }
+
+    // Waits for one of the keys in choices; None on Esc.
+    fn prompt_choice(&mut self, prompt_str: &str, choices: &str) -> Option<char> {
+        loop {
+            self.set_status_msg(prompt_str);
+            let _ = self.refresh_screen();
+            let _ = self.screen.flush();
+            match self.keyboard.read() {
+                Ok(Input::Key(KeyEvent { code: KeyCode::Esc, .. })) => {
+                    self.set_status_msg("");
+                    return None;
+                }
+                Ok(Input::Key(KeyEvent { code: KeyCode::Char(c), .. })) if choices.contains(c) => {
+                    self.set_status_msg("");
+                    return Some(c);
+                }
+                Ok(Input::Resize(width, height)) => self.resize(width, height),
+                Ok(_) => {}
+                Err(_) => return None,
+            }
+        }
+    }
+
+    // Shows read-only text in place of the buffer until q or Esc.
+    fn view_text(&mut self, title: &str, lines: Vec<String>) {
+        let rows = std::mem::replace(&mut self.rows, Editor::make_rows(&lines, None));
+        let filename = std::mem::replace(&mut self.filename, title.to_string());
+        let syntax_ind = self.syntax_ind.take();
+        let syntax_tree = self.syntax_tree.take();
+        let head_lines = self.head_lines.take();
+        let hunks = std::mem::take(&mut self.hunks);
+        let folds = std::mem::take(&mut self.folds);
+        let jumps = std::mem::take(&mut self.jumps);
+        let (cursor, rowoff, coloff, dirty) = (self.cursor, self.rowoff, self.coloff, self.dirty);
+        self.cursor = Position::default();
+        self.rowoff = 0;
+        self.coloff = 0;
+        self.dirty = false;
+        self.anchor = None;
+
+        loop {
+            self.set_status_msg("q or Esc to close");
+            if self.refresh_screen().is_err() {
+                break;
+            }
+            let _ = self.screen.move_to(self.screen_row(self.cursor.y), self.render_x, self.coloff);
+            let _ = self.screen.flush();
+            match self.keyboard.read() {
+                Ok(Input::Key(key_event)) => {
+                    let key = Key::from(key_event);
+                    if key.code == KeyCode::Esc || key.code == KeyCode::Char('q') {
+                        break;
+                    }
+                    if let Lookup::Command(command) = self.keymap.lookup(&[key]) {
+                        if matches!(command, Command::MoveUp | Command::MoveDown | Command::MoveLeft |
+                            Command::MoveRight | Command::PageUp | Command::PageDown |
+                            Command::Home | Command::End) {
+                            let _ = self.execute(command);
+                        }
+                    }
+                }
+                Ok(Input::Mouse(mouse_event)) => self.process_mouse(mouse_event),
+                Ok(Input::Resize(width, height)) => self.resize(width, height),
+                Ok(Input::FocusGained) => {}
+                Err(_) => break,
+            }
+        }
+
+        self.rows = rows;
+        self.filename = filename;
+        self.syntax_ind = syntax_ind;
+        self.syntax_tree = syntax_tree;
+        self.head_lines = head_lines;
+        self.hunks = hunks;
+        self.folds = folds;
+        self.jumps = jumps;
+        self.cursor = cursor;
+        self.rowoff = rowoff;
+        self.coloff = coloff;
+        self.dirty = dirty;
+        self.anchor = None;
+        self.set_status_msg("");
+    }
+
+    // macros
+    fn prompt_count(&mut self, prompt_str: &str) -> Option<usize> {
+        let answer = self.prompt(format!("{prompt_str} (Enter = 1)"), None)?;
+        if answer.trim().is_empty() {
+            return Some(1);
+        }
+        match answer.trim().parse::<usize>() {
+            Ok(times) => Some(times),
+            Err(_) => {
+                self.set_status_msg(format!("Not a number: {answer}"));
+                None
+            }
+        }
+    }
+
+    fn save_macro(&mut self) {
+        if self.macros.last.is_empty() {
+            self.set_status_msg("No macro recorded");
+            return;
+        }
+        let name = match self.prompt("Save macro as".to_string(), None) {
+            Some(name) if !name.trim().is_empty() => name.trim().to_string(),
+            _ => return,
+        };
+        self.macros.insert(name.clone(), self.macros.last.clone());
+        match config::config_file("macros").map(|path| self.macros.save(&path)) {
+            Some(Ok(())) => self.set_status_msg(format!("Macro '{name}' saved")),
+            Some(Err(e)) => self.set_status_msg(format!("Can't save macros: {e}")),
+            None => self.set_status_msg("Can't save macros: no config directory"),
+        }
+    }
+
+    // vi
+    fn vi_action(&mut self, action: Action) -> Result<bool> {
+        match action {
+            Action::None | Action::PassThrough => {}
+            Action::Move(motion, count) => {
+                let target = self.motion_target(motion, count);
+                if matches!(motion, Motion::GotoLine(_) | Motion::FirstLine | Motion::LastLine) {
+                    self.jump_to(target);
+                } else {
+                    self.cursor = target;
+                }
+            }
+            Action::Operate(op, motion, count) => {
+                if self.rows.is_empty() {
+                    return Ok(false);
+                }
+                let target = self.motion_target(motion, count);
+                if motion.is_linewise() {
+                    let first = self.cursor.y.min(target.y) as usize;
+                    let last = (self.cursor.y.max(target.y) as usize).min(self.rows.len() - 1);
+                    self.operate_lines(op, first, last);
+                } else {
+                    let (start, mut end) = if target < self.cursor {
+                        (target, self.cursor)
+                    } else {
+                        (self.cursor, target)
+                    };
+                    if motion == Motion::WordEnd {
+                        end = self.char_end(end);
+                    }
+                    self.operate_range(op, start, end);
+                }
+            }
+            Action::OperateSelection(op) => {
+                if let Some((start, end)) = self.selection() {
+                    let end = self.char_end(end);
+                    self.operate_range(op, start, end);
+                }
+                self.anchor = None;
+            }
+            Action::DeleteChar(count) => {
+                let end = (0..count).fold(self.cursor, |end, _| self.char_end(end));
+                self.operate_range(Operator::Delete, self.cursor, end);
+            }
+            Action::Insert(at) => {
+                match at {
+                    InsertAt::Cursor => {}
+                    InsertAt::After => self.cursor = self.char_end(self.cursor),
+                    InsertAt::LineStart => self.cursor.x = self.first_non_blank(self.cursor.y),
+                    InsertAt::LineEnd => self.cursor.x = self.current_row_len(),
+                    InsertAt::LineBelow | InsertAt::LineAbove => {
+                        let at = if at == InsertAt::LineBelow && !self.rows.is_empty() {
+                            self.cursor.y + 1
+                        } else {
+                            self.cursor.y
+                        };
+                        self.insert_row(at as usize, String::new());
+                        self.cursor = Position { x: 0, y: at };
+                    }
+                }
+            }
+            Action::ExitInsert => {
+                self.cursor = self.char_start(self.cursor);
+            }
+            Action::Paste { after, count } => self.paste(after, count),
+            Action::EnterVisual => self.anchor = Some(self.cursor),
+            Action::ExitVisual => self.anchor = None,
+            Action::CommandLine => {
+                let command = self.command_line(":");
+                if let Some(vi) = self.vi.as_mut() {
+                    vi.mode = Mode::Normal;
+                }
+                if let Some(command) = command {
+                    return self.ex_command(command.trim());
+                }
+            }
+        }
+        Ok(false)
+    }
+
+    fn ex_command(&mut self, command: &str) -> Result<bool> {
+        match command {
+            "" => {}
+            "w" => self.save(),
+            "q" => {
+                if self.dirty {
+                    self.set_status_msg("No write since last change (add ! to override)");
+                } else {
+                    return Ok(true);
+                }
+            }
+            "q!" => return Ok(true),
+            "wq" | "x" => {
+                self.save();
+                return Ok(!self.dirty);
+            }
+            _ if command.starts_with("w ") => {
+                self.filename = command[2..].trim().to_string();
+                self.disk_stamp = None;
+                self.select_syntax_highlight();
+                self.save();
+            }
+            _ => match command.parse::<usize>() {
+                Ok(line) => self.jump_to(self.motion_target(Motion::GotoLine(line), 1)),
+                Err(_) => return self.run_command_line(command),
+            }
+        }
+        Ok(false)
+    }
+
+    fn motion_target(&self, motion: Motion, count: usize) -> Position {
+        let count = count.min(u16::MAX as usize) as u16;
+        let last_row = (self.rows.len() as u16).saturating_sub(1);
+        let mut pos = self.cursor;
+        match motion {
+            Motion::Left => pos = (0..count).fold(pos, |pos, _| self.char_start(pos)),
+            Motion::Right => pos = (0..count).fold(pos, |pos, _| self.char_end(pos)),
+            Motion::Up => pos.y = pos.y.saturating_sub(count),
+            Motion::Down => pos.y = pos.y.saturating_add(count).min(last_row),
+            Motion::Line => pos.y = pos.y.saturating_add(count - 1).min(last_row),
+            Motion::WordForward => {
+                for _ in 0..count {
+                    pos = self.next_word_start(pos);
+                }
+            }
+            Motion::WordBackward => {
+                for _ in 0..count {
+                    pos = self.prev_word_start(pos);
+                }
+            }
+            Motion::WordEnd => {
+                for _ in 0..count {
+                    pos = self.word_end(pos);
+                }
+            }
+            Motion::LineStart => pos.x = 0,
+            Motion::LineEnd => pos.x = self.row_len(pos.y),
+            Motion::FirstLine => pos = Position { x: self.first_non_blank(0), y: 0 },
+            Motion::LastLine => pos = Position { x: self.first_non_blank(last_row), y: last_row },
+            Motion::GotoLine(line) => {
+                let y = (line.saturating_sub(1)).min(last_row as usize) as u16;
+                pos = Position { x: self.first_non_blank(y), y };
+            }
+        }
+        if motion.is_linewise() {
+            pos.x = pos.x.min(self.row_len(pos.y));
+        }
+        pos
+    }
+
+    fn operate_lines(&mut self, op: Operator, first: usize, last: usize) {
+        let mut text = String::new();
+        for row in &self.rows[first..=last] {
+            text.push_str(&row.chars);
+            text.push('\n');
+        }
+        self.register = text;
+        self.register_linewise = true;
+
+        match op {
+            Operator::Yank => self.cursor.y = first as u16,
+            Operator::Delete => {
+                for _ in first..=last {
+                    self.del_row(first);
+                }
+                let y = first.min(self.rows.len().saturating_sub(1)) as u16;
+                self.cursor = Position { x: self.first_non_blank(y), y };
+            }
+            Operator::Change => {
+                for _ in first..last {
+                    self.del_row(first + 1);
+                }
+                let end = Position { x: self.row_len(first as u16), y: first as u16 };
+                self.delete_range(Position { x: 0, y: first as u16 }, end);
+                self.cursor = Position { x: 0, y: first as u16 };
+            }
+        }
+        self.cursor = self.clamp_position(self.cursor);
+    }
+
+    fn operate_range(&mut self, op: Operator, start: Position, end: Position) {
+        if op == Operator::Yank {
+            self.register = self.range_text(start, end);
+            self.cursor = start;
+        } else {
+            self.register = self.delete_range(start, end);
+        }
+        self.register_linewise = false;
+    }
+
+    fn paste(&mut self, after: bool, count: usize) {
+        if self.register.is_empty() {
+            return;
+        }
+        if self.register_linewise {
+            let lines: Vec<String> = self.register
+                .trim_end_matches('\n')
+                .split('\n')
+                .map(|line| line.to_string())
+                .collect();
+            let at = if after && !self.rows.is_empty() {
+                self.cursor.y as usize + 1
+            } else {
+                self.cursor.y as usize
+            };
+            for _ in 0..count {
+                for line in lines.iter().rev() {
+                    self.insert_row(at, line.clone());
+                }
+            }
+            self.cursor = Position { x: self.first_non_blank(at as u16), y: at as u16 };
+        } else {
+            let mut at = self.cursor;
+            if after && self.current_row_len() > 0 {
+                at = self.char_end(at);
+            }
+            let text = self.register.repeat(count);
+            let end = self.insert_text(at, &text);
+            self.cursor = self.char_start(end);
+        }
+    }
+
+    pub fn selection(&self) -> Option<(Position, Position)> {
+        let anchor = self.anchor?;
+        if anchor < self.cursor {
+            Some((anchor, self.cursor))
+        } else {
+            Some((self.cursor, anchor))
+        }
+    }
+
+    fn row_len(&self, y: u16) -> u16 {
+        self.rows.get(y as usize).map_or(0, |row| row.len() as u16)
+    }
+
+    // the position just after the char at pos, and of the char before it,
+    // on the same row
+    fn char_end(&self, pos: Position) -> Position {
+        let x = self.rows.get(pos.y as usize).map_or(0, |row| row.next_boundary(pos.x as usize));
+        Position { x: x as u16, y: pos.y }
+    }
+
+    fn char_start(&self, pos: Position) -> Position {
+        let x = self.rows.get(pos.y as usize).map_or(0, |row| row.prev_boundary(pos.x as usize));
+        Position { x: x as u16, y: pos.y }
+    }
+
+    fn first_non_blank(&self, y: u16) -> u16 {
+        self.rows.get(y as usize).map_or(0, |row| {
+            row.chars.chars().take_while(|c| c.is_whitespace()).count() as u16
+        })
+    }
+
+    // 0 = blank (or end of line), 1 = word character, 2 = punctuation
+    fn char_class(&self, pos: Position) -> u8 {
+        let row = self.rows.get(pos.y as usize);
+        match row.and_then(|row| row.chars.get(pos.x as usize..)).and_then(|rest| rest.chars().next()) {
+            None => 0,
+            Some(c) if c.is_whitespace() => 0,
+            Some(c) if c.is_alphanumeric() || c == '_' => 1,
+            Some(_) => 2,
+        }
+    }
+
+    fn next_pos(&self, pos: Position) -> Option<Position> {
+        if pos.x < self.row_len(pos.y) {
+            Some(self.char_end(pos))
+        } else if (pos.y as usize) + 1 < self.rows.len() {
+            Some(Position { x: 0, y: pos.y + 1 })
+        } else {
+            None
+        }
+    }
+
+    fn prev_pos(&self, pos: Position) -> Option<Position> {
+        if pos.x > 0 {
+            Some(self.char_start(pos))
+        } else if pos.y > 0 {
+            Some(Position { x: self.row_len(pos.y - 1), y: pos.y - 1 })
+        } else {
+            None
+        }
+    }
+
+    fn next_word_start(&self, mut pos: Position) -> Position {
+        let class = self.char_class(pos);
+        while class != 0 && self.char_class(pos) == class {
+            match self.next_pos(pos) {
+                Some(next) => pos = next,
+                None => return pos,
+            }
+        }
+        while self.char_class(pos) == 0 {
+            match self.next_pos(pos) {
+                Some(next) => pos = next,
+                None => return pos,
+            }
+        }
+        pos
+    }
+
+    fn prev_word_start(&self, mut pos: Position) -> Position {
+        match self.prev_pos(pos) {
+            Some(prev) => pos = prev,
+            None => return pos,
+        }
+        while self.char_class(pos) == 0 {
+            match self.prev_pos(pos) {
+                Some(prev) => pos = prev,
+                None => return pos,
+            }
+        }
+        let class = self.char_class(pos);
+        while let Some(prev) = self.prev_pos(pos) {
+            if self.char_class(prev) != class {
+                break;
+            }
+            pos = prev;
+        }
+        pos
+    }
+
+    fn word_end(&self, mut pos: Position) -> Position {
+        match self.next_pos(pos) {
+            Some(next) => pos = next,
+            None => return pos,
+        }
+        while self.char_class(pos) == 0 {
+            match self.next_pos(pos) {
+                Some(next) => pos = next,
+                None => return pos,
+            }
+        }
+        let class = self.char_class(pos);
+        while let Some(next) = self.next_pos(pos) {
+            if self.char_class(next) != class {
+                break;
+            }
+            pos = next;
+        }
+        pos
+    }
+
+    pub fn start(&mut self) -> Result<()> {
+        install_panic_hook();
+        if let Err(e) = self.screen.enter() {
+            restore_terminal();
+            return Err(e);
+        }
+        let result = self.run();
+        if result.is_ok() {
+            self.remove_swap();
+        }
+        self.remember_file();
+        if let Some(name) = self.session.clone() {
+            self.save_session(&name);
+        }
+        self.stop_lsp();
+        restore_terminal();
+        self.quit_time = KILO_QUIT_TIMES;
+        result
+    }
+
+    fn run(&mut self) -> Result<()> {
+        self.update_git();
+        self.reset_syntax_tree();
+        self.start_lsp();
+        match self.session_view.take() {
+            Some(view) => self.restore_view(view),
+            None => self.restore_file(),
+        }
+        self.check_swap();
+        loop {
+            // don't redraw for every key of a macro
+            if !self.keyboard.is_playing() {
+                if self.refresh_screen().is_err() {
+                    self.die("Clear Screen");
+                }
+                self.screen.move_to(self.screen_row(self.cursor.y), self.render_x, self.coloff)?;
+                self.screen.flush()?;
+            }
+            let idle_since = Instant::now();
+            while !self.keyboard.poll(POLL_INTERVAL) {
+                if self.process_lsp() {
+                    self.refresh_screen()?;
+                    self.screen.move_to(self.screen_row(self.cursor.y), self.render_x, self.coloff)?;
+                    self.screen.flush()?;
+                }
+                if idle_since.elapsed() >= SWAP_IDLE {
+                    self.update_swap(true);
+                }
+            }
+            if self.process_keypress()? {
+                break;
+            }
+            self.update_swap(false);
+            self.update_snippet();
+            self.sync_lsp();
+        }
+        Ok(())
+    }
+
+
+    pub fn die<S: Into<String>>(&mut self, message : S) {
+        let error = errno();
+        self.update_swap(true);
+        restore_terminal();
+        eprintln!("{}: {}", message.into(), error);
+        std::process::exit(1);
+    }
+
+    pub fn move_to_home(&mut self) {
+        self.cursor.y = 0;
+    }
+
+    pub fn move_to_end(&mut self) {
+        if self.cursor.y < self.rows.len() as u16 {
+            self.cursor.x = self.current_row_len();
+        }
+    }
+
+    pub fn move_cursor(&mut self, key: EditorKey)  {
+        match key {
+            EditorKey::Left => {
+                if self.cursor.x != 0 {
+                    self.cursor = self.char_start(self.cursor);
+                } else if self.cursor.y > 0  {
+                    self.cursor.y = self.folds.prev_visible(self.cursor.y as usize) as u16;
+                    self.cursor.x = self.current_row_len();
+                }
+            },
+            EditorKey::Right if self.cursor.y < self.rows.len() as u16 => {
+                let ind = self.cursor.y as usize;
+                if  self.cursor.x  <  self.rows[ind].len() as u16 {
+                    self.cursor = self.char_end(self.cursor);
+                } else {
+                    self.cursor.y = self.folds.next_visible(ind).min(self.rows.len()) as u16;
+                    self.cursor.x = 0;
+                }
+            },
+            EditorKey::Up => {
+                self.cursor.y = self.folds.prev_visible(self.cursor.y as usize) as u16;
+            },
+            EditorKey::Down if self.cursor.y < self.rows.len() as u16  => {
+                self.cursor.y = self.folds.next_visible(self.cursor.y as usize).min(self.rows.len()) as u16;
+            }
+            _ => {}
+        }
+
+        self.cursor = self.clamp_position(self.cursor);
+    }
+
+    pub fn insert_char(&mut self, c: char) {
+        let syntax = self.get_syntax_data();  // get a clone of syntax
+        let syntax = if let Some(syntax) = &syntax {
+            Some(syntax)
+        } else {
+            None
+        };
+
+
+        if self.cursor.y == self.rows.len() as u16 {
+            self.insert_row(self.cursor.y as usize, String::new());
+        }
+
+        let (y, x) = (self.cursor.y as usize, self.cursor.x as usize);
+        self.text_changed(y, x.min(self.rows[y].len()), "", c.encode_utf8(&mut [0; 4]));
+        self.rows[y].insert_char(x, c, syntax);
+        self.cursor.x += c.len_utf8() as u16;
+        self.mark_dirty();
+    }
+
+    pub fn del_char(&mut self) {
+
+        if self.cursor.y == self.rows.len() as u16 {
+            return;
+        }
+        if self.cursor.x == 0 && self.cursor.y == 0 {
+            return;
+        }
+
+        let syntax: Option<EditorSyntax> = self.get_syntax_data();
+        let syntax = if let Some(syntax) = &syntax {
+            Some(syntax)
+        } else {
+            None
+        };
+
+        let current_row = self.cursor.y as usize;
+        if self.cursor.x > 0 {
+            let x = self.rows[current_row].prev_boundary(self.cursor.x as usize);
+            let removed = self.rows[current_row].chars[x..].chars().next().map(String::from).unwrap_or_default();
+            if self.rows[current_row].del_char(x, syntax) {
+                self.text_changed(current_row, x, &removed, "");
+                self.cursor.x = x as u16;
+                self.mark_dirty();
+           }
+        } else {
+            self.cursor.x = self.rows[current_row-1].len() as u16;
+            self.row_joined(current_row, self.cursor.x, 0);
+            if let Some(row) = self.del_row(current_row) {
+                self.text_changed(current_row - 1, self.cursor.x as usize, "", &row);
+                self.rows[current_row-1].append_string(&row, syntax);
+                self.cursor.y -= 1;
+                self.mark_dirty();
+            }
+        }
+    }
+
+    pub fn insert_row(&mut self, at: usize, s: String) {
+        if at > self.rows.len() {
+           return;
+        }
+        let syntax = self.get_syntax_data();
+        let syntax = if let Some(syntax) = &syntax {
+            Some(syntax)
+        } else {
+            None
+        };
+        self.text_changed(at, 0, "", &(s.clone() + "\n"));
+        self.rows.insert(at,Row::new(s, syntax));
+        self.row_inserted(at);
+        self.mark_dirty();
+    }
+
+    pub fn insert_newline(&mut self) {
+        let syntax = self.get_syntax_data();
+        let syntax = if let Some(syntax) = &syntax {
+            Some(syntax)
+        } else {
+            None
+        };
+
+        let row = self.cursor.y as usize;
+        if self.cursor.x == 0 {
+            self.insert_row(row, "".to_string());
+        } else {
+            let new_row_str = self.rows[row].split(self.cursor.x as usize, syntax);
+            self.text_changed(row, self.cursor.x as usize, &new_row_str, "");
+            self.insert_row(row+1, new_row_str);
+            self.row_split(row, self.cursor.x);
+        }
+        self.cursor.y += 1;
+        self.cursor.x = 0;
+    }
+
+    pub fn del_row(&mut self, at: usize) -> Option<String> {
+        if at > self.rows.len() {
+            None
+        } else{
+            self.mark_dirty();
+            let row = self.rows.remove(at);
+            self.row_removed(at);
+            self.text_changed(at, 0, &(row.chars.clone() + "\n"), "");
+            Some(row.chars)
+        }
+    }
+
+    // keeps state tied to rows in place when rows come and go
+    fn row_inserted(&mut self, at: usize) {
+        self.folds.row_inserted(at);
+        self.marks.row_inserted(at);
+        self.jumps.row_inserted(at);
+    }
+
+    fn row_removed(&mut self, at: usize) {
+        self.folds.row_removed(at);
+        self.marks.row_removed(at);
+        self.jumps.row_removed(at);
+    }
+
+    // after the row split off row `at` at column x has been inserted
+    fn row_split(&mut self, at: usize, x: u16) {
+        self.marks.row_split(at, x);
+        self.jumps.row_split(at, x);
+    }
+
+    // before row `at` is removed and what it has from column `from` on is
+    // appended to the row above, `len` long
+    fn row_joined(&mut self, at: usize, len: u16, from: u16) {
+        self.marks.row_joined(at, len, from);
+        self.jumps.row_joined(at, len, from);
+    }
+
+    // `removed` at (y, x) was or is about to be replaced by `inserted`;
+    // only the rows above y need to be as they were
+    fn text_changed(&mut self, y: usize, x: usize, removed: &str, inserted: &str) {
+        let Some(tree) = self.syntax_tree.as_mut().filter(|tree| tree.is_parsed()) else { return };
+        let byte = self.rows[..y].iter().map(|row| row.len() + 1).sum::<usize>() + x;
+        tree.edit(byte, (y, x), removed, inserted);
+    }
+
+    // text between start and end (exclusive), rows joined with '\n'
+    pub fn range_text(&self, start: Position, end: Position) -> String {
+        let mut text = String::new();
+        let last = (end.y as usize).min(self.rows.len());
+        for y in start.y as usize..last + 1 {
+            let Some(row) = self.rows.get(y) else { break };
+            let from = if y == start.y as usize { row.floor_boundary(start.x as usize) } else { 0 };
+            let to = if y == end.y as usize { row.floor_boundary(end.x as usize).max(from) } else { row.len() };
+            text.push_str(&row.chars[from..to]);
+            if y != end.y as usize && y + 1 < self.rows.len() {
+                text.push('\n');
+            }
+        }
+        text
+    }
+
+    // removes the text between start and end (exclusive) and leaves the
+    // cursor at start
+    pub fn delete_range(&mut self, start: Position, end: Position) -> String {
+        if start >= end || start.y as usize >= self.rows.len() {
+            return String::new();
+        }
+        let syntax = self.get_syntax_data();
+        let syntax = syntax.as_ref();
+
+        let first = start.y as usize;
+        let last = (end.y as usize).min(self.rows.len() - 1);
+        let end_x = if end.y as usize > last { usize::MAX } else { end.x as usize };
+        let start_x = self.rows[first].floor_boundary(start.x as usize);
+        let text = if first == last {
+            self.rows[first].delete_str(start.x as usize, end_x, syntax)
+        } else {
+            let mut text = self.rows[first].delete_str(start.x as usize, usize::MAX, syntax);
+            for _ in first + 1..last {
+                text.push('\n');
+                text.push_str(&self.rows.remove(first + 1).chars);
+                self.row_removed(first + 1);
+            }
+            let end_x = self.rows[first + 1].floor_boundary(end_x);
+            self.row_joined(first + 1, start.x, end_x as u16);
+            let mut tail = self.rows.remove(first + 1).chars;
+            self.row_removed(first + 1);
+            let rest = tail.split_off(end_x);
+            text.push('\n');
+            text.push_str(&tail);
+            self.rows[first].append_string(&rest, syntax);
+            text
+        };
+        self.text_changed(first, start_x, &text, "");
+        self.cursor = start;
+        self.cursor = self.clamp_position(self.cursor);
+        self.mark_dirty();
+        text
+    }
+
+    // inserts text, which may span several lines, and returns the position
+    // just after it
+    pub fn insert_text(&mut self, at: Position, text: &str) -> Position {
+        if at.y as usize >= self.rows.len() {
+            self.insert_row(self.rows.len(), String::new());
+        }
+        let syntax = self.get_syntax_data();
+        let syntax = syntax.as_ref();
+
+        let y = (at.y as usize).min(self.rows.len() - 1);
+        let x = self.rows[y].floor_boundary(at.x as usize);
+        let mut lines = text.split('\n');
+        let first = lines.next().unwrap_or("");
+        let rest: Vec<&str> = lines.collect();
+        self.mark_dirty();
+
+        if rest.is_empty() {
+            self.text_changed(y, x, "", first);
+            self.rows[y].insert_str(x, first, syntax);
+            return Position { x: (x + first.len()) as u16, y: y as u16 };
+        }
+
+        let tail = self.rows[y].split(x, syntax);
+        self.text_changed(y, x, &tail, first);
+        self.rows[y].append_string(first, syntax);
+        for (i, line) in rest.iter().enumerate() {
+            self.insert_row(y + 1 + i, line.to_string());
+        }
+        let last = y + rest.len();
+        let end_x = self.rows[last].len();
+        self.text_changed(last, end_x, "", &tail);
+        self.rows[last].append_string(&tail, syntax);
+        Position { x: end_x as u16, y: last as u16 }
+    }
+
+
+    pub fn refresh_screen(&mut self) -> Result<()> {
+        self.update_hunks();
+        self.update_syntax_tree();
+        let gutter = self.head_lines.is_some() || self.lsp.is_some();
+        self.screen.set_gutter(if gutter { 2 } else { 0 });
+        self.scroll();
+        self.update_completion_popup();
+        self.screen.clear()?;
+        let selection = self.selection();
+        let mut signs = self.git_signs();
+        let mut marks = Vec::new();
+        self.add_diagnostics(&mut signs, &mut marks);
+        self.add_misspellings(&mut marks);
+        self.screen.draw_row(&self.rows, self.rowoff, self.coloff, selection, &signs, &marks, &self.folds)?;
+        if let Some(popup) = &self.popup {
+            self.screen.draw_popup(popup)?;
+        }
+
+        if !self.status_msg.is_empty() && self.status_time.elapsed() > Duration::from_secs(5) {
+                self.status_msg.clear();
+        }
+
+        let left = statusline::expand(&self.settings.status_left, |name| self.status_segment(name));
+        let right = statusline::expand(&self.settings.status_right, |name| self.status_segment(name));
+        self.screen.draw_status_bar(left, right, self.status_msg.clone())
+    }
+
+    fn status_segment(&self, name: &str) -> String {
+        match name {
+            "mode" => self.vi.as_ref().map_or(String::new(), |vi| format!("-- {} -- ", vi.mode.name())),
+            "recording" => if self.keyboard.is_recording() { "(rec) ".to_string() } else { String::new() },
+            "filename" => if self.filename.is_empty() { "[No Name]".to_string() } else { self.filename.clone() },
+            "modified" => if self.dirty { "{Modified}".to_string() } else { String::new() },
+            "lines" => self.rows.len().to_string(),
+            "filetype" => self.syntax_ind.map_or("No FileType", |ft| &self.hldb[ft].filetype).to_string(),
+            "line" => (self.cursor.y + 1).to_string(),
+            "column" => (self.render_x + 1).to_string(),
+            "percentage" => {
+                let line = (self.cursor.y as usize + 1).min(self.rows.len());
+                format!("{}%", (line * 100).checked_div(self.rows.len()).unwrap_or(100))
+            }
+            "encoding" => format!("{}{}", self.format.encoding.name(), if self.format.bom { " BOM" } else { "" }),
+            "eol" => format!("{}{}", self.format.line_ending.name(),
+                             if self.format.final_newline { "" } else { " noeol" }),
+            "selection" => match self.selection() {
+                Some((start, end)) => {
+                    let end = self.char_end(end);
+                    format!("{} selected | ", self.range_text(start, end).chars().count())
+                }
+                None => String::new(),
+            },
+            "branch" => self.branch.as_ref().map_or(String::new(), |branch| format!("{branch} | ")),
+            _ => String::new(),
+        }
+    }
+
+    // git
+    // Rereads the branch and the committed version of the file.
+    fn update_git(&mut self) {
+        let dir = Path::new(&self.filename).parent().unwrap_or(Path::new(""));
+        let root = git::find_root(dir);
+        self.branch = root.as_ref().and_then(|root| git::branch(root));
+        self.head_lines = root.as_ref()
+            .filter(|_| !self.filename.is_empty())
+            .and_then(|root| git::head_blob(root, Path::new(&self.filename)))
+            .and_then(|blob| FileFormat::decode_bytes(&blob).ok())
+            .map(|(lines, _)| lines);
+        self.hunks.clear();
+        self.hunks_version = self.version.wrapping_sub(1);
+        self.update_hunks();
+    }
+
+    fn update_hunks(&mut self) {
+        if self.hunks_version == self.version {
+            return;
+        }
+        self.hunks_version = self.version;
+        self.hunks = match &self.head_lines {
+            Some(head) => {
+                let current: Vec<&str> = self.rows.iter().map(|row| row.chars.as_str()).collect();
+                let head: Vec<&str> = head.iter().map(|line| line.as_str()).collect();
+                diff::hunks(&diff::diff(&head, &current))
+            }
+            None => Vec::new(),
+        };
+    }
+
+    // language server
+    fn file_uri(&self) -> String {
+        let path = Path::new(&self.filename);
+        lsp::path_to_uri(&std::fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf()))
+    }
+
+    // (Re)starts the language server for the file's filetype, if one is
+    // configured. A server that isn't installed is silently skipped.
+    fn start_lsp(&mut self) {
+        self.stop_lsp();
+        let Some(filetype) = self.syntax_ind.map(|ind| self.hldb[ind].filetype.clone()) else { return };
+        let Some(command) = self.lsp_servers.get(&filetype) else { return };
+        if self.filename.is_empty() {
+            return;
+        }
+        let path = std::fs::canonicalize(&self.filename).unwrap_or_else(|_| PathBuf::from(&self.filename));
+        let dir = path.parent().unwrap_or(Path::new("/")).to_path_buf();
+        let root = git::find_root(&dir).unwrap_or(dir);
+        match LspClient::start(command, &root, &path, &filetype, self.rows_to_string()) {
+            Ok(client) => {
+                self.lsp = Some(client);
+                self.lsp_version = self.version;
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => self.set_status_msg(format!("Can't start {command}: {e}")),
+        }
+    }
+
+    fn stop_lsp(&mut self) {
+        if let Some(mut lsp) = self.lsp.take() {
+            lsp.stop();
+        }
+        self.diagnostics.clear();
+        self.close_completion();
+    }
+
+    // tells the server about edits since the last call
+    fn sync_lsp(&mut self) {
+        if self.version == self.lsp_version {
+            return;
+        }
+        let text = self.rows_to_string();
+        if let Some(lsp) = self.lsp.as_mut() {
+            let _ = lsp.did_change(text);
+        }
+        self.lsp_version = self.version;
+    }
+
+    fn lsp_position(&self, pos: Position) -> lsp::LspPosition {
+        let line = self.rows.get(pos.y as usize).map_or("", |row| row.chars.as_str());
+        (pos.y as usize, lsp::byte_to_utf16(line, pos.x as usize))
+    }
+
+    fn buffer_position(&self, (line, column): lsp::LspPosition) -> Position {
+        let x = self.rows.get(line).map_or(0, |row| lsp::utf16_to_byte(&row.chars, column));
+        Position { x: x as u16, y: line.min(self.rows.len()) as u16 }
+    }
+
+    fn ask_lsp(&mut self, kind: Request) {
+        if self.lsp.is_none() {
+            self.set_status_msg("No language server for this file");
+            return;
+        }
+        self.sync_lsp();
+        let position = self.lsp_position(self.cursor);
+        if let Some(lsp) = self.lsp.as_mut() {
+            if let Err(e) = lsp.ask(kind, position) {
+                self.set_status_msg(format!("Language server: {e}"));
+            }
+        }
+    }
+
+    // handles whatever the server sent; true if the screen needs redrawing
+    fn process_lsp(&mut self) -> bool {
+        let Some(lsp) = self.lsp.as_mut() else { return false };
+        let messages = lsp.poll();
+        let redraw = !messages.is_empty();
+        for message in messages {
+            match message {
+                Message::Diagnostics(diagnostics) => self.diagnostics = diagnostics,
+                Message::Hover(text) => self.show_hover(&text),
+                Message::Completion(items) => self.show_completion(items),
+                Message::Definition(path, position) => self.goto_location(&path, position),
+                Message::Error(msg) => self.set_status_msg(msg),
+                Message::Exited => {
+                    let name = self.lsp.take().map(|lsp| lsp.name).unwrap_or_default();
+                    self.diagnostics.clear();
+                    self.set_status_msg(format!("{name} exited"));
+                    break;
+                }
+            }
+        }
+        redraw
+    }
+
+    fn add_diagnostics(&self, signs: &mut [Option<Sign>], marks: &mut Vec<Mark>) {
+        for diagnostic in &self.diagnostics {
+            let start = self.buffer_position(diagnostic.start);
+            let end = self.buffer_position(diagnostic.end);
+            marks.push(Mark { start, end, element: "diagnostic-underline" });
+            // the worst diagnostic on a row gets the sign
+            let Some(sign) = signs.get_mut(start.y as usize) else { continue };
+            let worse = self.diagnostics.iter()
+                .any(|other| other.start.0 == diagnostic.start.0 && other.severity < diagnostic.severity);
+            if !worse {
+                let (ch, element) = diagnostic.severity.sign();
+                *sign = Some(Sign { ch, element });
+            }
+        }
+    }
+
+    // a popup under the cursor, or above it if there's no room below
+    fn popup_at_cursor(&self, items: Vec<(String, String)>, x: u16, selected: Option<usize>) -> Popup {
+        let mut popup = Popup { items, selected, x, y: 0 };
+        let row = self.screen_row(self.cursor.y);
+        popup.y = if row + 1 + popup.height() <= self.screen.bounds().y {
+            row + 1
+        } else {
+            row.saturating_sub(popup.height())
+        };
+        popup
+    }
+
+    fn show_hover(&mut self, text: &str) {
+        const WIDTH: usize = 72;
+        let mut lines: Vec<String> = self.diagnostics.iter()
+            .filter(|diagnostic| diagnostic.start.0 == self.cursor.y as usize)
+            .map(|diagnostic| diagnostic.message.clone())
+            .collect();
+        lines.extend(text.lines().map(|line| line.to_string()));
+        let mut items = Vec::new();
+        for line in lines.iter().filter(|line| !line.trim().is_empty()) {
+            let chars: Vec<char> = line.chars().collect();
+            for chunk in chars.chunks(WIDTH) {
+                items.push((chunk.iter().collect(), String::new()));
+            }
+        }
+        if items.is_empty() {
+            self.set_status_msg("Nothing to show here");
+            return;
+        }
+        let x = self.screen.gutter_width() + self.render_x.saturating_sub(self.coloff);
+        self.close_completion();
+        self.popup = Some(self.popup_at_cursor(items, x, None));
+        self.hover_open = true;
+    }
+
+    fn goto_location(&mut self, path: &Path, position: lsp::LspPosition) {
+        let same_file = std::fs::canonicalize(&self.filename).is_ok_and(|current| current == path);
+        if same_file {
+            self.jumps.push(self.cursor);
+        } else {
+            if self.dirty {
+                self.set_status_msg(format!("Definition is in {}, save first", path.display()));
+                return;
+            }
+            self.open(&path.to_string_lossy());
+        }
+        self.cursor = self.buffer_position(position);
+        self.anchor = None;
+    }
+
+    // completion popup
+    fn show_completion(&mut self, items: Vec<CompletionItem>) {
+        if items.is_empty() {
+            self.set_status_msg("No completions");
+            return;
+        }
+        let row = self.rows.get(self.cursor.y as usize).map_or("", |row| row.chars.as_str());
+        let before = &row[..(self.cursor.x as usize).min(row.len())];
+        let word_len = before.chars().rev().take_while(|c| c.is_alphanumeric() || *c == '_').map(char::len_utf8).sum::<usize>();
+        let start = Position { x: self.cursor.x - word_len as u16, y: self.cursor.y };
+        self.completion = Some(Completion { start, items });
+        self.update_completion_popup();
+    }
+
+    fn complete_word(&mut self) {
+        let keywords = self.syntax_ind.map_or(&[][..], |ind| &self.hldb[ind].keywords);
+        let items = completion::buffer_words(&self.rows, self.cursor, keywords);
+        self.show_completion(items);
+    }
+
+    fn close_completion(&mut self) {
+        if self.completion.take().is_some() {
+            self.popup = None;
+        }
+    }
+
+    // Keeps the popup in step with what's typed; closes it when the cursor
+    // leaves the word or nothing matches any more.
+    fn update_completion_popup(&mut self) {
+        let Some(completion) = &self.completion else { return };
+        let start = completion.start;
+        let typed = match self.rows.get(start.y as usize) {
+            Some(row) if self.cursor.y == start.y && self.cursor.x >= start.x && self.cursor.x as usize <= row.len() =>
+                &row.chars[start.x as usize..self.cursor.x as usize],
+            _ => "",
+        };
+        if self.cursor.y != start.y || self.cursor.x < start.x ||
+            typed.chars().any(|c| !(c.is_alphanumeric() || c == '_')) {
+            self.close_completion();
+            return;
+        }
+        let items: Vec<(String, String)> = completion.matching(typed).iter()
+            .map(|item| (item.label.clone(), item.detail.clone()))
+            .collect();
+        if items.is_empty() {
+            self.close_completion();
+            return;
+        }
+        let previous = self.popup.as_ref().and_then(|popup| popup.selected_value()).map(|value| value.to_string());
+        let selected = previous.and_then(|value| items.iter().position(|(label, _)| *label == value)).unwrap_or(0);
+        let rx = self.rows[start.y as usize].cx_to_rx(start.x);
+        let x = self.screen.gutter_width() + rx.saturating_sub(self.coloff);
+        self.popup = Some(self.popup_at_cursor(items, x, Some(selected)));
+    }
+
+    // Keys the popup handles itself; anything else goes on to the editor,
+    // with typing and Backspace narrowing the list.
+    fn completion_key(&mut self, key: Key) -> bool {
+        let popup = self.popup.as_mut();
+        match key.code {
+            KeyCode::Esc => self.close_completion(),
+            KeyCode::Up => popup.into_iter().for_each(|popup| popup.select_previous()),
+            KeyCode::Down => popup.into_iter().for_each(|popup| popup.select_next()),
+            KeyCode::Enter | KeyCode::Tab => self.accept_completion(),
+            KeyCode::Char(_) | KeyCode::Backspace if key.modifiers - KeyModifiers::SHIFT == KeyModifiers::NONE => return false,
+            _ => {
+                self.close_completion();
+                return false;
+            }
+        }
+        true
+    }
+
+    fn accept_completion(&mut self) {
+        let label = self.popup.as_ref().and_then(|popup| popup.selected_value()).map(|value| value.to_string());
+        let Some(completion) = self.completion.take() else { return };
+        self.popup = None;
+        let Some(item) = completion.items.iter().find(|item| Some(&item.label) == label.as_ref()) else { return };
+        self.delete_range(completion.start, self.cursor);
+        self.cursor = self.insert_text(completion.start, &item.insert);
+    }
+
+    // spell checking
+    fn update_dictionary(&mut self) {
+        if !self.settings.spell {
+            self.dictionary = None;
+        } else if self.dictionary.as_ref().is_none_or(|dictionary| dictionary.lang != self.settings.spell_lang) {
+            match Dictionary::load(&self.settings.spell_lang) {
+                Ok(dictionary) => self.dictionary = Some(dictionary),
+                Err(msg) => {
+                    self.dictionary = None;
+                    self.set_status_msg(msg);
+                }
+            }
+        }
+    }
+
+    // Misspelled words on a row, by byte range. Only comments and strings
+    // are checked, unless the file is plain text.
+    fn misspellings(&mut self, y: usize) -> Vec<(usize, usize)> {
+        let Some(dictionary) = self.dictionary.as_mut() else { return Vec::new() };
+        let row = &self.rows[y];
+        let plain_text = self.syntax_ind.is_none();
+        spell::words(&row.chars).into_iter()
+            .filter(|(start, _)| plain_text || {
+                let rx = row.cx_to_rx(*start as u16) as usize;
+                matches!(row.hl.get(rx), Some(Highlight::Comment | Highlight::String))
+            })
+            .filter(|(start, end)| !dictionary.check(&row.chars[*start..*end]))
+            .collect()
+    }
+
+    fn add_misspellings(&mut self, marks: &mut Vec<Mark>) {
+        if self.dictionary.is_none() {
+            return;
+        }
+        let mut y = self.rowoff as usize;
+        for _ in 0..self.screen.bounds().y {
+            if y >= self.rows.len() {
+                break;
+            }
+            let row = y;
+            y = self.folds.next_visible(y);
+            for (start, end) in self.misspellings(row) {
+                marks.push(Mark {
+                    start: Position { x: start as u16, y: row as u16 },
+                    end: Position { x: end as u16, y: row as u16 },
+                    element: "misspelled",
+                });
+            }
+        }
+    }
+
+    fn next_misspelling(&mut self) {
+        if self.dictionary.is_none() {
+            self.set_status_msg("Spell checking is off (set spell)");
+            return;
+        }
+        // from just after the cursor, wrapping around to it
+        let (y, x) = (self.cursor.y as usize, self.cursor.x as usize);
+        let rows = self.rows.len();
+        for i in (0..=rows).take_while(|_| rows > 0) {
+            let row = (y + i) % rows;
+            let Some((start, _)) = self.misspellings(row).into_iter()
+                .find(|(start, _)| i > 0 || *start > x) else { continue };
+            self.cursor = Position { x: start as u16, y: row as u16 };
+            return;
+        }
+        self.set_status_msg("No misspellings");
+    }
+
+    fn spell_suggest(&mut self) {
+        if self.dictionary.is_none() {
+            self.set_status_msg("Spell checking is off (set spell)");
+            return;
+        }
+        let y = self.cursor.y as usize;
+        let Some(row) = self.rows.get(y) else { return };
+        let x = self.cursor.x as usize;
+        let Some((start, end)) = spell::words(&row.chars).into_iter().find(|(start, end)| (*start..=*end).contains(&x)) else {
+            self.set_status_msg("No word at the cursor");
+            return;
+        };
+        let word = row.chars[start..end].to_string();
+        let Some(dictionary) = self.dictionary.as_mut() else { return };
+        if dictionary.check(&word) {
+            self.set_status_msg(format!("'{word}' is spelled correctly"));
+            return;
+        }
+        let suggestions = dictionary.suggest(&word);
+        let mut prompt = format!("{word}:");
+        let mut choices = String::from("a");
+        for (i, suggestion) in suggestions.iter().enumerate() {
+            prompt.push_str(&format!(" {} {suggestion}", i + 1));
+            choices.push_str(&(i + 1).to_string());
+        }
+        if suggestions.is_empty() {
+            prompt.push_str(" no suggestions");
+        }
+        prompt.push_str(" | (a)dd to dictionary, Esc");
+        match self.prompt_choice(&prompt, &choices) {
+            Some('a') => {
+                let result = self.dictionary.as_mut().map(|dictionary| dictionary.add(&word));
+                match result {
+                    Some(Err(msg)) => self.set_status_msg(msg),
+                    _ => self.set_status_msg(format!("Added '{word}' to the personal dictionary")),
+                }
+            }
+            Some(c) => {
+                let Some(suggestion) = c.to_digit(10).and_then(|n| suggestions.get(n as usize - 1)) else { return };
+                let (start, end) = (Position { x: start as u16, y: y as u16 }, Position { x: end as u16, y: y as u16 });
+                self.delete_range(start, end);
+                self.cursor = self.insert_text(start, suggestion);
+                self.anchor = None;
+            }
+            None => {}
+        }
     }
 
-    // keyboard
-    pub fn process_keypress(&mut self) -> Result<bool> {
-        if let Ok(c) = self.keyboard.read() {
-            match c {
-               KeyEvent {
-                   code: KeyCode::Char('q'),
-                   modifiers: KeyModifiers::CONTROL, ..
-               } => {
-                   if self.dirty && self.quit_time > 0 {
-                       self.set_status_msg(
-                           format!("Warning!!!  File has unsaved changes.\
-                                   Press Ctrl-Q {} more time to quit", self.quit_time));
-                       self.quit_time -= 1;
-                       return Ok(false)
-                   } else  {
-                       return Ok(true)
-                   }
-               },
-               KeyEvent {
-                    code: KeyCode::Char('s'),
-                    modifiers: KeyModifiers::CONTROL, ..
-               } => self.save(),
-                KeyEvent {
-                    code: KeyCode::Char('f'),
-                    modifiers: KeyModifiers::CONTROL, ..
-                } => self.find(),
-               KeyEvent {
-                   code: KeyCode::Char('l'),
-                   modifiers: KeyModifiers::CONTROL, ..
-               } => {}, // DO NOTHING
-               KeyEvent {
-                   code: KeyCode::Char('h'),
-                   modifiers: KeyModifiers::CONTROL, ..
-               } => self.del_char(),
-
-                KeyEvent {
-                    code: KeyCode::Char(key),
-                    modifiers: KeyModifiers::NONE, ..
-                } => self.insert_char(key),
-               KeyEvent {
-                   code: KeyCode::Tab,
-                   modifiers: KeyModifiers::NONE, ..
-               } => self.insert_char('\t'),
-                KeyEvent {
-                    code: KeyCode::Char(key),
-                    modifiers: KeyModifiers::SHIFT, ..
-                } => self.insert_char(key),
-
-               KeyEvent { code, .. } => match code {
-                   KeyCode::Delete => {
-                       self.move_cursor(EditorKey::Right);
-                       self.del_char();
-                   },
-                   KeyCode::Backspace => {
-                       self.del_char();
-                   },
-                   KeyCode::Enter => self.insert_newline(),
-                   KeyCode::Esc => {},
-                   KeyCode::Home => self.move_to_home(),
-                   KeyCode::End => self.move_to_end(),
-                   KeyCode::Up => { self.move_cursor(EditorKey::Up); },
-                   KeyCode::Down => { self.move_cursor(EditorKey::Down); }
-                   KeyCode::Left => { self.move_cursor(EditorKey::Left); }
-                   KeyCode::Right => { self.move_cursor(EditorKey::Right); }
-                   KeyCode::PageUp | KeyCode::PageDown => {
-                       let bounds = self.screen.bounds();
-                       match code {
-                           KeyCode::PageUp => self.cursor.y = self.rowoff,
-                           KeyCode::PageDown => self.cursor.y  = (self.rowoff + bounds.y-1).min(self.rows.len() as u16),
-                           _ => panic!("rust compiler broke")
-                       }
-
-                       for _ in 0..bounds.y {
-                           self.move_cursor(
-                               if code == KeyCode::PageUp {
-                                   EditorKey::Up
-                               } else {
-                                   EditorKey::Down
-                               }
-                           );
-                       }
-                   }
-                   _ => { self.set_status_msg("NOTHING")}
-               }
-            }
-        } else {
-            self.die("Unable to read from keyboard");
-            unreachable!();
+    // snippets
+    // Expands the snippet whose trigger ends at the cursor, if any.
+    fn expand_snippet(&mut self) -> bool {
+        let Some(row) = self.rows.get(self.cursor.y as usize) else { return false };
+        let before = &row.chars[..(self.cursor.x as usize).min(row.len())];
+        if before.is_empty() || before.ends_with(char::is_whitespace) {
+            return false;
         }
-        Ok(false)
+        let filetype = self.syntax_ind.map(|ind| self.hldb[ind].filetype.as_str());
+        let snippets = match snippet::load(filetype) {
+            Ok(snippets) => snippets,
+            Err(msg) => {
+                self.set_status_msg(msg);
+                return false;
+            }
+        };
+        // the longest trigger that ends the text before the cursor and
+        // doesn't start mid-word
+        let Some(snippet) = snippets.iter()
+            .filter(|snippet| before.strip_suffix(snippet.trigger.as_str())
+                .is_some_and(|rest| !rest.ends_with(|c: char| c.is_alphanumeric() || c == '_')))
+            .max_by_key(|snippet| snippet.trigger.len()) else { return false };
+
+        let indent: String = row.chars.chars().take_while(|c| c.is_whitespace()).collect();
+        let filename = self.filename.clone();
+        let variable = |name: &str| match name {
+            "FILENAME" => Path::new(&filename).file_name().map(|name| name.to_string_lossy().into_owned()),
+            "FILEPATH" => Some(filename.clone()),
+            "DATE" => Some(DateTime::now_utc().date()),
+            "YEAR" => Some(DateTime::now_utc().year.to_string()),
+            _ => None,
+        };
+        let (text, stops) = snippet::expand(&snippet.body, &indent, &variable);
+
+        let start = Position { x: self.cursor.x - snippet.trigger.len() as u16, y: self.cursor.y };
+        self.delete_range(start, self.cursor);
+        self.insert_text(start, &text);
+        let stops = stops.iter()
+            .map(|stop| stop.ranges.iter()
+                .map(|(from, to)| (snippet::position_in(&text, start, *from), snippet::position_in(&text, start, *to)))
+                .collect())
+            .collect();
+        self.snippet = Some(snippet::Session { stops, current: 0, version: 0, row_len: 0 });
+        self.enter_stop(0);
+        true
     }
 
-    pub fn start(&mut self) -> Result<()> {
-        terminal::enable_raw_mode()?;
-        loop {
+    // Puts the cursor at the end of a stop with its text selected, so typing
+    // replaces it. Reaching the last stop ($0) ends the snippet.
+    fn enter_stop(&mut self, index: usize) {
+        let Some(session) = self.snippet.as_mut() else { return };
+        let index = index.min(session.stops.len() - 1);
+        let (start, end) = session.stops[index][0];
+        session.current = index;
+        session.version = self.version;
+        session.row_len = self.rows.get(start.y as usize).map_or(0, |row| row.len());
+        self.cursor = end;
+        self.anchor = (start != end).then_some(start);
+        if index == session.stops.len() - 1 {
+            self.snippet = None;
+        }
+    }
 
-            if self.refresh_screen().is_err() {
-                self.die("Clear Screen");
+    fn snippet_key(&mut self, key: Key) -> bool {
+        let inserting = self.vi.as_ref().is_none_or(|vi| vi.mode == Mode::Insert);
+        let Some(session) = self.snippet.as_ref() else { return false };
+        if !inserting || !key.modifiers.is_empty() {
+            return false;
+        }
+        let current = session.current;
+        let (start, end) = session.stops[current][0];
+        match key.code {
+            KeyCode::Tab => self.enter_stop(current + 1),
+            KeyCode::BackTab => self.enter_stop(current.saturating_sub(1)),
+            KeyCode::Esc => {
+                self.snippet = None;
+                return false;
             }
-            self.screen.move_to(&self.cursor, self.render_x, self.rowoff, self.coloff)?;
-            self.screen.flush()?;
-            if self.process_keypress()? {
-                break;
+            // typing over a placeholder replaces it
+            KeyCode::Char(_) | KeyCode::Backspace | KeyCode::Delete
+                if self.anchor == Some(start) && self.cursor == end => {
+                self.delete_range(start, end);
+                self.anchor = None;
+                return !matches!(key.code, KeyCode::Char(_));
             }
+            _ => return false,
         }
-        let _ = self.screen.clear();
-        terminal::disable_raw_mode()?;
-        self.quit_time = KILO_QUIT_TIMES;
-        Ok(())
+        true
     }
 
-
-    pub fn die<S: Into<String>>(&mut self, message : S) {
-        let _ = self.screen.clear();
-        let _ =  terminal::disable_raw_mode();
-        eprintln!("{}: {}", message.into(), errno());
-        std::process::exit(1);
+    // After an edit in the current stop: works out its new extent from the
+    // change in its row's length, then copies its text to the mirrors. An
+    // edit anywhere else ends the snippet.
+    fn update_snippet(&mut self) {
+        let Some(session) = self.snippet.as_mut() else { return };
+        if session.version == self.version {
+            return;
+        }
+        let current = session.current;
+        let (start, end) = session.stops[current][0];
+        let row_len = self.rows.get(start.y as usize).map_or(0, |row| row.len());
+        let delta = row_len as isize - session.row_len as isize;
+        let new_end = end.x as isize + delta;
+        if self.cursor.y != start.y || new_end < start.x as isize || self.cursor.x < start.x || self.cursor.x as isize > new_end {
+            self.snippet = None;
+            return;
+        }
+        session.shift(start.y, end.x, delta, (current, 0));
+        let end = Position { x: new_end as u16, y: start.y };
+        session.stops[current][0].1 = end;
+
+        let text = self.rows[start.y as usize].chars[start.x as usize..end.x as usize].to_string();
+        let cursor = self.cursor;
+        for mirror in 1..session.stops[current].len() {
+            let Some(session) = self.snippet.as_mut() else { return };
+            let (from, to) = session.stops[current][mirror];
+            let delta = text.len() as isize - (to.x as isize - from.x as isize);
+            session.shift(from.y, to.x, delta, (current, mirror));
+            session.stops[current][mirror].1 = Position { x: from.x + text.len() as u16, y: from.y };
+            self.delete_range(from, to);
+            self.insert_text(from, &text);
+        }
+        // mirrors before the cursor on its row move it along
+        let Some(session) = self.snippet.as_mut() else { return };
+        self.cursor = session.stops[current][0].0;
+        self.cursor.x += cursor.x - start.x;
+        session.version = self.version;
+        session.row_len = self.rows[start.y as usize].len();
     }
 
-    pub fn move_to_home(&mut self) {
-        self.cursor.y = 0;
+    // folding
+    fn fold_regions(&self) -> Vec<fold::Region> {
+        let braces = match self.settings.fold_method.as_str() {
+            "brace" => true,
+            "indent" => false,
+            _ => self.syntax_ind.is_some(),
+        };
+        if braces { fold::brace_regions(&self.rows) } else { fold::indent_regions(&self.rows) }
     }
 
-    pub fn move_to_end(&mut self) {
-        if self.cursor.y < self.rows.len() as u16 {
-            self.cursor.x = self.current_row_len();
+    // Closes the innermost region around the cursor, or the one around a
+    // fold already closed there.
+    fn fold(&mut self) {
+        let y = self.cursor.y as usize;
+        let closed = self.folds.at(y).copied();
+        let regions: Vec<fold::Region> = self.fold_regions().into_iter()
+            .filter(|region| closed.is_none_or(|fold| region.start <= fold.start && region.end >= fold.end &&
+                (region.start, region.end) != (fold.start, fold.end)))
+            .collect();
+        match fold::region_at(&regions, y) {
+            Some(region) => {
+                self.folds.close(region);
+                self.cursor = Position { x: 0, y: region.start as u16 };
+            }
+            None => self.set_status_msg("Nothing to fold here"),
         }
     }
 
-    pub fn move_cursor(&mut self, key: EditorKey)  {
-        match key {
-            EditorKey::Left => {
-                if self.cursor.x != 0 {
-                    self.cursor.x -= 1;
-                } else if self.cursor.y > 0  {
-                    self.cursor.y -= 1;
-                    self.cursor.x = self.current_row_len();
-                }
-            },
-            EditorKey::Right => {
-                if self.cursor.y < self.rows.len() as u16 {
-                    let ind = self.cursor.y as usize;
-                    if  self.cursor.x  <  self.rows[ind].len() as u16 {
-                        self.cursor.x += 1;
-                    } else if self.cursor.y < self.rows.len() as u16{
-                        self.cursor.y += 1;
-                        self.cursor.x = 0;
-                    }
-                }
-            } ,
-            EditorKey::Up => {
-                self.cursor.y  = self.cursor.y.saturating_sub(1);
-            },
-            EditorKey::Down if self.cursor.y < self.rows.len() as u16  => self.cursor.y +=1,
-            _ => {}
+    // Closes every region nested `level` deep, 0 being the outermost, and
+    // opens the rest.
+    fn fold_all(&mut self, level: usize) {
+        self.folds.clear();
+        for region in self.fold_regions().into_iter().filter(|region| region.depth == level) {
+            self.folds.close(region);
         }
+        if let Some(fold) = self.folds.hiding(self.cursor.y as usize) {
+            self.cursor = Position { x: 0, y: fold.start as u16 };
+        }
+    }
 
-        self.cursor.x = self.cursor.x.min(self.current_row_len());
+    // the row a hidden row is folded into
+    fn visible_row(&self, y: u16) -> u16 {
+        self.folds.hiding(y as usize).map_or(y, |fold| fold.start as u16)
     }
 
-    pub fn insert_char(&mut self, c: char) {
-        let syntax = self.get_syntax_data();  // get a clone of syntax
-        let syntax = if let Some(syntax) = &syntax {
-            Some(syntax)
-        } else {
-            None
-        };
+    // where a row on screen is drawn, counting from the top of the text
+    fn screen_row(&self, y: u16) -> u16 {
+        self.folds.count_visible(self.rowoff as usize, y as usize).saturating_sub(1) as u16
+    }
 
+    // marks and jumps
+    fn set_mark(&mut self, name: &str) {
+        if name.is_empty() {
+            self.set_status_msg("A mark needs a name");
+            return;
+        }
+        self.marks.set(name, self.cursor);
+        self.set_status_msg(format!("Mark '{name}' set"));
+    }
 
-        if self.cursor.y == self.rows.len() as u16 {
-            self.insert_row(self.cursor.y as usize, String::new());
+    fn goto_mark(&mut self, name: &str) {
+        match self.marks.get(name) {
+            Some(pos) => self.jump_to(pos),
+            None => self.set_status_msg(format!("No mark named '{name}'")),
         }
+    }
 
-        self.rows[self.cursor.y as usize].insert_char(self.cursor.x as usize, c, syntax);
-        self.cursor.x += 1;
-        self.dirty = true;
+    // moves the cursor, remembering where it was in the jump list
+    fn jump_to(&mut self, pos: Position) {
+        self.jumps.push(self.cursor);
+        self.cursor = self.clamp_position(pos);
+        self.anchor = None;
     }
 
-    pub fn del_char(&mut self) {
+    // a position that may be from before an edit, moved inside the buffer
+    fn clamp_position(&self, pos: Position) -> Position {
+        let y = pos.y.min(self.rows.len() as u16);
+        let x = self.rows.get(y as usize).map_or(0, |row| row.floor_boundary(pos.x as usize) as u16);
+        Position { x, y }
+    }
 
-        if self.cursor.y == self.rows.len() as u16 {
+    // file history
+    // Records where the cursor is in the file being left. Like the swap
+    // file, this is best effort.
+    fn remember_file(&mut self) {
+        let Some(path) = history::key(&self.filename) else { return };
+        let state = FileState { path, cursor: self.cursor, rowoff: self.rowoff, coloff: self.coloff };
+        let _ = self.history.remember(state);
+    }
+
+    // puts the cursor back where it was when the file was last left
+    fn restore_file(&mut self) {
+        let Some(state) = history::key(&self.filename).and_then(|path| self.history.get(&path)) else { return };
+        let (cursor, rowoff, coloff) = (state.cursor, state.rowoff, state.coloff);
+        self.cursor = self.clamp_position(cursor);
+        self.rowoff = rowoff.min(self.cursor.y);
+        self.coloff = coloff;
+    }
+
+    // picks a recently edited file to open
+    fn recent_files(&mut self) {
+        self.history = History::load();
+        if self.recent_candidates("").is_empty() {
+            self.set_status_msg("No recent files");
             return;
         }
-        if self.cursor.x == 0 && self.cursor.y == 0 {
-            return;
+        self.update_recent_popup("");
+        let choice = self.prompt("Recent file".to_string(), Some(Editor::recent_files_callback));
+        self.popup = None;
+        if let Some(filename) = choice {
+            self.open(filename.trim());
         }
+    }
 
-        let syntax: Option<EditorSyntax> = self.get_syntax_data();
-        let syntax = if let Some(syntax) = &syntax {
-            Some(syntax)
-        } else {
+    // files that still exist, other than this one, best match first
+    fn recent_candidates(&self, pattern: &str) -> Vec<String> {
+        let current = history::key(&self.filename);
+        let paths: Vec<String> = self.history.recent()
+            .filter(|path| Some(*path) != current.as_deref() && path.exists())
+            .map(|path| path.display().to_string())
+            .collect();
+        if pattern.is_empty() {
+            return paths;
+        }
+        let names: Vec<&str> = paths.iter().map(String::as_str).collect();
+        palette::fuzzy_filter(pattern, &names).into_iter().map(String::from).collect()
+    }
+
+    fn update_recent_popup(&mut self, buffer: &str) {
+        let items: Vec<(String, String)> = self.recent_candidates(buffer).into_iter()
+            .map(|path| (path, String::new()))
+            .collect();
+        self.popup = if items.is_empty() {
             None
+        } else {
+            let mut popup = Popup { items, selected: Some(0), x: 0, y: 0 };
+            popup.y = self.screen.bounds().y.saturating_sub(popup.height());
+            Some(popup)
         };
+    }
 
-        let current_row = self.cursor.y as usize;
-        if self.cursor.x > 0 {
-            if self.rows[current_row].del_char(self.cursor.x as usize-1, syntax) {
-                self.cursor.x -= 1;
-                self.dirty = true;
-           }
-        } else {
-            self.cursor.x = self.rows[current_row-1].len() as u16;
-            if let Some(row) = self.del_row(current_row) {
-                self.rows[current_row-1].append_string(&row, syntax);
-                self.cursor.y -= 1;
-                self.dirty = true;
+    fn recent_files_callback(&mut self, buffer: &mut String, key: PromptKey) {
+        match key {
+            PromptKey::Escape => {}
+            // what was typed is opened as it is when nothing matches it
+            PromptKey::Enter | PromptKey::Complete => {
+                if let Some(value) = self.popup.as_ref().and_then(|popup| popup.selected_value()) {
+                    *buffer = value.to_string();
+                }
+            }
+            PromptKey::Next => {
+                if let Some(popup) = self.popup.as_mut() {
+                    popup.select_next();
+                }
+            }
+            PromptKey::Previous => {
+                if let Some(popup) = self.popup.as_mut() {
+                    popup.select_previous();
+                }
             }
+            PromptKey::Edit => self.update_recent_popup(buffer),
         }
     }
 
-    pub fn insert_row(&mut self, at: usize, s: String) {
-        if at > self.rows.len() {
-           return;
+    // sessions
+    fn save_session(&mut self, name: &str) {
+        let mut session = Session { buffers: Vec::new(), searches: self.searches.clone() };
+        if let Some(path) = history::key(&self.filename) {
+            session.buffers.push(session::Buffer {
+                path,
+                cursor: self.cursor,
+                rowoff: self.rowoff,
+                coloff: self.coloff,
+                marks: self.marks.iter().map(|(name, pos)| (name.clone(), *pos)).collect(),
+            });
+        }
+        let open = session.buffers.first().map(|buffer| buffer.path.clone());
+        session.buffers.extend(self.session_unopened.iter()
+            .filter(|buffer| Some(&buffer.path) != open.as_ref())
+            .cloned());
+        match session.save(name) {
+            Ok(()) => {
+                self.session = Some(name.to_string());
+                self.set_status_msg(format!("Session {name} saved"));
+            }
+            Err(msg) => self.set_status_msg(msg),
         }
-        let syntax = self.get_syntax_data();
-        let syntax = if let Some(syntax) = &syntax {
-            Some(syntax)
-        } else {
-            None
-        };
-        self.rows.insert(at,Row::new(s, syntax));
-        self.dirty = true;
     }
 
-    pub fn insert_newline(&mut self) {
-        let syntax = self.get_syntax_data();
-        let syntax = if let Some(syntax) = &syntax {
-            Some(syntax)
-        } else {
-            None
-        };
-
-        let row = self.cursor.y as usize;
-        if self.cursor.x == 0 {
-            self.insert_row(row, "".to_string());
-        } else {
-            let new_row_str = self.rows[row].split(self.cursor.x as usize, syntax);
-            self.insert_row(row+1, new_row_str);
+    fn restore_view(&mut self, view: session::Buffer) {
+        self.cursor = self.clamp_position(view.cursor);
+        self.rowoff = view.rowoff.min(self.cursor.y);
+        self.coloff = view.coloff;
+        for (name, pos) in view.marks {
+            self.marks.set(&name, self.clamp_position(pos));
         }
-        self.cursor.y += 1;
-        self.cursor.x = 0;
     }
 
-    pub fn del_row(&mut self, at: usize) -> Option<String> {
-        if at > self.rows.len() {
-            None
-        } else{
-            self.dirty = true;
-            Some(self.rows.remove(at).chars)
+    fn git_signs(&self) -> Vec<Option<Sign>> {
+        let mut signs = vec![None; self.rows.len()];
+        for hunk in &self.hunks {
+            let (ch, element) = match (hunk.old_len, hunk.new_len) {
+                (_, 0) => ('_', "git-deleted"),
+                (0, _) => ('+', "git-added"),
+                _ => ('~', "git-modified"),
+            };
+            let marked = hunk.marked();
+            for sign in signs.iter_mut().skip(marked.start).take(marked.len()) {
+                *sign = Some(Sign { ch, element });
+            }
         }
+        signs
     }
 
-
-    pub fn refresh_screen(&mut self) -> Result<()> {
-        self.scroll();
-        self.screen.clear()?;
-        self.screen.draw_row(&self.rows, self.rowoff, self.coloff)?;
-
-        if !self.status_msg.is_empty() && self.status_time.elapsed() > Duration::from_secs(5) {
-                self.status_msg.clear();
+    fn goto_hunk(&mut self, forward: bool) {
+        if self.head_lines.is_none() {
+            self.set_status_msg("Not a file tracked by git");
+            return;
+        }
+        self.update_hunks();
+        match diff::adjacent(&self.hunks, self.cursor.y as usize, forward) {
+            Some(row) => {
+                self.cursor = Position { x: 0, y: row as u16 };
+                self.anchor = None;
+            }
+            None => self.set_status_msg("No more changes"),
         }
+    }
 
-        self.screen.draw_status_bar(
-            format!("{:20} - {} lines {}",
-                    if self.filename.is_empty() {"[No Name]"} else { &self.filename },
-                    self.rows.len(),
-                    if self.dirty {
-                "{Modified}" } else { "" }
-            ),
-            format!("{} | {}/{}",
-                if let Some(ft)= self.syntax_ind {
-                    &self.hldb[ft].filetype
-                } else {
-                    "No FileType"
-                }
-                ,self.cursor.y, self.rows.len()),
-        self.status_msg.clone())
+    fn revert_hunk(&mut self) {
+        self.update_hunks();
+        let y = self.cursor.y as usize;
+        let hunk = self.hunks.iter().find(|hunk| hunk.marked().contains(&y)).copied();
+        let (Some(hunk), Some(head)) = (hunk, &self.head_lines) else {
+            self.set_status_msg("No change here");
+            return;
+        };
+        let old = head[hunk.old_start..hunk.old_start + hunk.old_len].to_vec();
+        for _ in 0..hunk.new_len {
+            self.del_row(hunk.new_start);
+        }
+        for (i, line) in old.into_iter().enumerate() {
+            self.insert_row(hunk.new_start + i, line);
+        }
+        self.cursor = Position { x: 0, y: (hunk.new_start as u16).min(self.rows.len() as u16) };
+        self.anchor = None;
+        self.set_status_msg("Change reverted");
     }
 
     fn scroll(&mut self) {
@@ -403,11 +2732,16 @@ impl Editor {
             0
         };
 
+        // jumps into a closed fold open it
+        self.folds.open_hiding(self.cursor.y as usize);
+        self.rowoff = self.visible_row(self.rowoff);
         if self.cursor.y < self.rowoff  {
             self.rowoff = self.cursor.y;
         }
-        if self.cursor.y >= self.rowoff  + bounds.y {
-            self.rowoff = self.cursor.y - bounds.y + 1;
+        // the top row if the cursor were on the bottom one
+        let top = (1..bounds.y).fold(self.cursor.y as usize, |y, _| self.folds.prev_visible(y));
+        if top > self.rowoff as usize {
+            self.rowoff = top as u16;
         }
 
         if self.render_x < self.coloff {
@@ -439,6 +2773,7 @@ impl Editor {
         if self.filename.is_empty() {
             if let Some(filename) = self.prompt("Save as".to_string(), None) {
                 self.filename = filename;
+                self.disk_stamp = None;
             } else {
                 self.set_status_msg("Save aborted");
                 return;
@@ -448,95 +2783,125 @@ impl Editor {
             // self.syntax = Editor::select_syntax_highlight(&self.hldb, &self.filename);
         }
 
-        let buf = self.rows_to_string();
+        if !self.confirm_overwrite() {
+            return;
+        }
+        let buf = match self.format.encode_bytes(self.rows.iter().map(|row| row.chars.as_str())) {
+            Ok(buf) => buf,
+            Err(e) => {
+                self.set_status_msg(format!("Can't save as {}: {e}", self.format.encoding.name()));
+                return;
+            }
+        };
         let len = buf.len();
-        if std::fs::write(&self.filename, &buf).is_ok() {
-            self.set_status_msg(&format!("{len} bytes written to disk"));
-            self.dirty = false;
-        } else {
-            self.set_status_msg(&format!("Can;t save I/O error: {}", errno()));
+        match fileio::write_atomic(Path::new(&self.filename), &buf, self.settings.backup) {
+            Ok(()) => {
+                self.set_status_msg(format!("{len} bytes written to disk"));
+                self.dirty = false;
+                self.remove_swap();
+                self.disk_stamp = FileStamp::of(Path::new(&self.filename));
+                self.update_git();
+                if self.lsp.as_ref().is_some_and(|lsp| lsp.uri == self.file_uri()) {
+                    if let Some(lsp) = self.lsp.as_mut() {
+                        let _ = lsp.did_save();
+                    }
+                } else {
+                    self.start_lsp();
+                }
+            }
+            Err(e) => self.set_status_msg(format!("Can't save! I/O error: {e}")),
         }
     }
 
-    fn prompt(&mut self, prompt_str: String, _callback: Option<fn(&mut Editor, &str, PromptKey)>) -> Option<String> {
+    fn prompt(&mut self, prompt_str: String, _callback: Option<fn(&mut Editor, &mut String, PromptKey)>) -> Option<String> {
         let mut buffer = String::from("");
 
         loop {
             self.set_status_msg(format!("{}: {}", prompt_str, buffer));
             let _ = self.refresh_screen();
             let _ = self.screen.flush();
-            if let Ok(c) = self.keyboard.read() {
-                let mut prompt_key: Option<PromptKey> = None;
-                match c {
-                    KeyEvent {
-                        code: KeyCode::Esc,
-                        ..
-                    } =>  {
-                        if let Some(callback) = _callback {
-                            callback(self, &buffer, PromptKey::Escape);
-                        }
-                        self.set_status_msg("");
-                        return  None;
-                    },
-                    KeyEvent {
-                        code: KeyCode::Backspace,
-                        ..
-                    } | KeyEvent {
-                        code : KeyCode::Char('h'),
-                        modifiers: KeyModifiers::CONTROL,
-                        ..}
-
-                        =>  {
-                        buffer.pop();
+            let c = match self.keyboard.read() {
+                Ok(Input::Key(c)) => c,
+                Ok(Input::Resize(width, height)) => {
+                    self.resize(width, height);
+                    continue;
+                }
+                _ => continue,
+            };
+            let mut prompt_key: Option<PromptKey> = None;
+            match c {
+                KeyEvent {
+                    code: KeyCode::Esc,
+                    ..
+                } =>  {
+                    if let Some(callback) = _callback {
+                        callback(self, &mut buffer, PromptKey::Escape);
                     }
+                    self.set_status_msg("");
+                    return  None;
+                },
+                KeyEvent {
+                    code: KeyCode::Backspace,
+                    ..
+                } | KeyEvent {
+                    code : KeyCode::Char('h'),
+                    modifiers: KeyModifiers::CONTROL,
+                    ..}
+
+                    =>  {
+                    buffer.pop();
+                    prompt_key = Some(PromptKey::Edit);
+                }
+                KeyEvent {
+                    code: KeyCode::Tab,
+                    ..
+                } => prompt_key = Some(PromptKey::Complete),
 
-                    KeyEvent {
-                        code: KeyCode::Enter,
-                        modifiers: KeyModifiers::NONE,..
-                    } => {
-                        if let Some(callback) = _callback {
-                            callback(self, &buffer, PromptKey::Enter);
-                        }
-                        self.set_status_msg("");
-                        return Some(buffer);
-                    }
-                    KeyEvent {
-                        code: KeyCode::Left | KeyCode::Up,
-                        ..} => {
-                            if let Some(callback) = _callback {
-                               callback(self, &buffer, PromptKey::Previous);
-                        }
+                KeyEvent {
+                    code: KeyCode::Enter,
+                    modifiers: KeyModifiers::NONE,..
+                } => {
+                    if let Some(callback) = _callback {
+                        callback(self, &mut buffer, PromptKey::Enter);
                     }
-                    KeyEvent {
-                        code: KeyCode::Right | KeyCode::Down,
-                        ..} => {
+                    self.set_status_msg("");
+                    return Some(buffer);
+                }
+                KeyEvent {
+                    code: KeyCode::Left | KeyCode::Up,
+                    ..} => {
                         if let Some(callback) = _callback {
-                            callback(self, &buffer, PromptKey::Next);
-                        }
-                    }
-
-                    KeyEvent {
-                        code: KeyCode::Char(c),
-                        modifiers,
-                        ..
-                    } => {
-                        if modifiers == KeyModifiers::NONE || modifiers == KeyModifiers::SHIFT {
-                            prompt_key = Some(PromptKey::Char(c));
-                            buffer.push(c);
-                        }
+                           callback(self, &mut buffer, PromptKey::Previous);
                     }
-                    _ =>  {}
                 }
-                if let Some(callback) = _callback {
-                    if let Some(key) = prompt_key {
-                        callback(self, &buffer, key);
+                KeyEvent {
+                    code: KeyCode::Right | KeyCode::Down,
+                    ..} => {
+                    if let Some(callback) = _callback {
+                        callback(self, &mut buffer, PromptKey::Next);
                     }
                 }
-            };
+
+                KeyEvent {
+                    code: KeyCode::Char(c),
+                    modifiers,
+                    ..
+                } if modifiers == KeyModifiers::NONE || modifiers == KeyModifiers::SHIFT => {
+                    prompt_key = Some(PromptKey::Edit);
+                    buffer.push(c);
+                }
+                _ =>  {}
+            }
+            if let Some(callback) = _callback {
+                if let Some(key) = prompt_key {
+                    callback(self, &mut buffer, key);
+                }
+            }
         }
     }
 
-   fn find_callback(&mut self, query: &str, event: PromptKey) {
+   #[allow(clippy::ptr_arg)]   // all prompt callbacks share one signature
+   fn find_callback(&mut self, query: &mut String, event: PromptKey) {
        if let Some(saved_hl) = self.saved_hl {
          self.rows[saved_hl].reset_match();
            self.saved_hl = None;
@@ -550,6 +2915,17 @@ impl Editor {
           }
           PromptKey::Next => self.direction = Forward,
           PromptKey::Previous => self.direction = Backward,
+          // earlier searches, most recent first
+          PromptKey::Complete => {
+              if self.searches.is_empty() {
+                  return;
+              }
+              let back = self.search_recall % self.searches.len();
+              *query = self.searches[self.searches.len() - 1 - back].clone();
+              self.search_recall += 1;
+              self.last_match = None;
+              self.direction = Forward;
+          }
           _ => {
               self.last_match = None;
               self.direction = Forward;
@@ -563,6 +2939,7 @@ impl Editor {
            0
        };
 
+       self.search_failed = true;
        for _ in 0..self.rows.len() {
            match self.direction {
                Forward => {
@@ -580,13 +2957,15 @@ impl Editor {
                }
            }
 
-           if let Some(ind) = self.rows[current].render.find(query) {
+           if let Some(ind) = self.rows[current].render.find(query.as_str()) {
+               let rx = self.rows[current].render[..ind].chars().count();
                self.last_match = Some(current);
                self.cursor.y = current as u16;
-               self.cursor.x = self.rows[current].rx_to_cx(ind);
+               self.cursor.x = self.rows[current].rx_to_cx(rx);
                self.rowoff = self.rows.len() as u16;
-               self.rows[current].highlight_match(ind, query.len());
+               self.rows[current].highlight_match(rx, query.chars().count());
                self.saved_hl = Some(current);
+               self.search_failed = false;
                break;
            }
        }
@@ -597,11 +2976,36 @@ impl Editor {
         let save_coloff = self.coloff;
         let save_rowoff= self.rowoff;
 
-        if self.prompt("Search(ESC/Arrows/Enter)".to_string(), Some(Editor::find_callback)).is_none() {
-            self.cursor = save_cursor;
-            self.coloff = save_coloff;
-            self.rowoff = save_rowoff;
-        };
+        self.search_failed = false;
+        self.search_recall = 0;
+        match self.prompt("Search(ESC/Arrows/Enter/Tab)".to_string(), Some(Editor::find_callback)) {
+            None => {
+                self.cursor = save_cursor;
+                self.coloff = save_coloff;
+                self.rowoff = save_rowoff;
+            }
+            Some(query) => {
+                if self.cursor != save_cursor {
+                    self.jumps.push(save_cursor);
+                }
+                self.remember_search(query);
+            }
+        }
+        if self.search_failed && self.keyboard.is_playing() {
+            self.keyboard.stop_playback();
+            self.set_status_msg("Search failed, macro stopped");
+        }
+    }
+
+    fn remember_search(&mut self, query: String) {
+        if query.is_empty() {
+            return;
+        }
+        self.searches.retain(|search| *search != query);
+        self.searches.push(query);
+        if self.searches.len() > MAX_SEARCHES {
+            self.searches.remove(0);
+        }
     }
 
     pub fn set_status_msg<T: Into<String>>(&mut self, msg: T) {
@@ -610,27 +3014,47 @@ impl Editor {
     }
 
     pub fn get_syntax_data(&self) -> Option<EditorSyntax> {
-        if let Some(ind) = self.syntax_ind {
-            Some(self.hldb[ind].clone())
-        } else {
-            None
-        }
+        self.syntax_ind.map(|ind| self.hldb[ind].clone())
     }
 
     fn select_syntax_highlight(&mut self) {
         let old_syntax = self.syntax_ind;
         self.syntax_ind = Editor::find_highlight(&self.hldb, self.filename.as_str());
         if self.syntax_ind != old_syntax {
-            let syntax = self.get_syntax_data();
-            let syntax = if let Some(syntax) = &syntax {
-                Some(syntax)
-            } else {
-                None
-            };
+            self.rehighlight();
+        }
+    }
 
-            for row in self.rows.iter_mut() {
-                row.update_syntax(syntax);
-            }
+    fn rehighlight(&mut self) {
+        let syntax = self.get_syntax_data();
+        let syntax = if let Some(syntax) = &syntax {
+            Some(syntax)
+        } else {
+            None
+        };
+
+        for row in self.rows.iter_mut() {
+            row.update_syntax(syntax);
+        }
+        self.reset_syntax_tree();
+    }
+
+    // A new tree-sitter parser for the filetype, if it has a grammar. The
+    // first update parses and highlights the whole buffer.
+    fn reset_syntax_tree(&mut self) {
+        self.syntax_tree = match self.syntax_ind {
+            Some(ind) if self.settings.treesitter => SyntaxTree::for_filetype(&self.hldb[ind].filetype),
+            _ => None,
+        };
+    }
+
+    fn update_syntax_tree(&mut self) {
+        if !self.syntax_tree.as_ref().is_some_and(SyntaxTree::needs_update) {
+            return;
+        }
+        let text = self.rows_to_string();
+        if let Some(tree) = self.syntax_tree.as_mut() {
+            tree.update(text, &mut self.rows);
         }
     }
 
@@ -644,7 +3068,6 @@ impl Editor {
             Some(ext ) => {
                 for (j,entry ) in hldb.iter().enumerate() {
                     for ext_ in entry.filematch.iter() {
-                        eprintln!("{ext}:{ext_}");
                         if ext == ext_ {
                             return Some(j);
                         }
@@ -654,4 +3077,98 @@ impl Editor {
             }
         }
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use crate::test_util::{git, TempDir};
+
+    fn open(path: &Path) -> Editor {
+        let (lines, _) = Editor::read_lines(path).unwrap();
+        let mut editor = Editor::build_on(Screen::with_size(80, 24), &lines, path.display().to_string());
+        editor.update_git();
+        editor
+    }
+
+    fn lines(count: usize) -> Vec<String> {
+        (0..count).map(|i| format!("line {i}")).collect()
+    }
+
+    fn text(editor: &Editor) -> Vec<&str> {
+        editor.rows.iter().map(|row| row.chars.as_str()).collect()
+    }
+
+    #[test]
+    fn moves_between_and_reverts_changes_against_head() {
+        let dir = TempDir::new("hunks");
+        let file = dir.path().join("notes.txt");
+        fs::write(&file, "one\ntwo\nthree\nfour\nfive\n").unwrap();
+        git(dir.path(), &["init", "-q"]);
+        git(dir.path(), &["add", "notes.txt"]);
+        git(dir.path(), &["commit", "-q", "-m", "notes"]);
+        // the first line deleted, the third changed and one added at the end
+        fs::write(&file, "two\nTHREE\nfour\nfive\nsix\n").unwrap();
+
+        let mut editor = open(&file);
+        assert_eq!(editor.hunks, [
+            diff::Hunk { old_start: 0, old_len: 1, new_start: 0, new_len: 0 },
+            diff::Hunk { old_start: 2, old_len: 1, new_start: 1, new_len: 1 },
+            diff::Hunk { old_start: 5, old_len: 0, new_start: 4, new_len: 1 },
+        ]);
+
+        let mut visited = Vec::new();
+        for _ in 0..3 {
+            editor.goto_hunk(true);
+            visited.push(editor.cursor.y);
+        }
+        assert_eq!(visited, [1, 4, 4]);
+        assert_eq!(editor.status_msg, "No more changes");
+        editor.goto_hunk(false);
+        editor.goto_hunk(false);
+        // the deletion before row 0 is marked on row 0
+        assert_eq!(editor.cursor.y, 0);
+
+        editor.revert_hunk();
+        assert_eq!(text(&editor), ["one", "two", "THREE", "four", "five", "six"]);
+        editor.cursor = Position { x: 0, y: 5 };
+        editor.revert_hunk();
+        editor.cursor = Position { x: 2, y: 2 };
+        editor.revert_hunk();
+        assert_eq!(text(&editor), ["one", "two", "three", "four", "five"]);
+        editor.update_hunks();
+        assert!(editor.hunks.is_empty());
+        assert!(editor.dirty);
+    }
+
+    #[test]
+    fn pages_in_a_terminal_too_small_for_text() {
+        for height in 0..4 {
+            let mut editor = Editor::build_on(Screen::with_size(20, height), &lines(10), "");
+            editor.execute(Command::PageDown).unwrap();
+            editor.execute(Command::PageDown).unwrap();
+            editor.execute(Command::PageUp).unwrap();
+            editor.scroll();
+            assert!((editor.cursor.y as usize) < 10);
+        }
+    }
+
+    #[test]
+    fn restores_a_session_view_inside_the_buffer() {
+        let mut editor = Editor::build_on(Screen::with_size(20, 10), &lines(3), "");
+        let at = |x, y| Position { x, y };
+        editor.restore_view(session::Buffer {
+            path: PathBuf::from("notes.txt"),
+            cursor: at(40, 9),
+            rowoff: 8,
+            coloff: 0,
+            marks: vec![(String::from("a"), at(2, 1)), (String::from("b"), at(30, 2)), (String::from("c"), at(1, 7))],
+        });
+        // the file has shrunk since: everything past its end comes back to it
+        assert!(editor.cursor == at(0, 3));
+        assert_eq!(editor.rowoff, 3);
+        assert!(editor.marks.get("a") == Some(at(2, 1)));
+        assert!(editor.marks.get("b") == Some(at(6, 2)));
+        assert!(editor.marks.get("c") == Some(at(0, 3)));
+    }
+}
@@ -56,6 +56,50 @@ impl EditorSyntax {
                 Keyword::Type("void".to_string())
             ],
 
+        },
+        EditorSyntax {
+            filetype: "rust".to_string(),
+            filematch: vec!["rs".to_string()],
+            singleline_comment_start: Some("//".to_string()),
+            flags: highlightflags::NUMBERS | highlightflags::STRINGS,
+            keywords: vec![
+                Keyword::Basic("fn".to_string()),
+                Keyword::Basic("let".to_string()),
+                Keyword::Basic("mut".to_string()),
+                Keyword::Basic("if".to_string()),
+                Keyword::Basic("else".to_string()),
+                Keyword::Basic("match".to_string()),
+                Keyword::Basic("while".to_string()),
+                Keyword::Basic("loop".to_string()),
+                Keyword::Basic("for".to_string()),
+                Keyword::Basic("in".to_string()),
+                Keyword::Basic("break".to_string()),
+                Keyword::Basic("continue".to_string()),
+                Keyword::Basic("return".to_string()),
+                Keyword::Basic("struct".to_string()),
+                Keyword::Basic("enum".to_string()),
+                Keyword::Basic("impl".to_string()),
+                Keyword::Basic("trait".to_string()),
+                Keyword::Basic("pub".to_string()),
+                Keyword::Basic("use".to_string()),
+                Keyword::Basic("mod".to_string()),
+                Keyword::Basic("const".to_string()),
+                Keyword::Basic("static".to_string()),
+                Keyword::Basic("self".to_string()),
+                Keyword::Type("i32".to_string()),
+                Keyword::Type("i64".to_string()),
+                Keyword::Type("u8".to_string()),
+                Keyword::Type("u16".to_string()),
+                Keyword::Type("u32".to_string()),
+                Keyword::Type("u64".to_string()),
+                Keyword::Type("usize".to_string()),
+                Keyword::Type("f64".to_string()),
+                Keyword::Type("bool".to_string()),
+                Keyword::Type("char".to_string()),
+                Keyword::Type("str".to_string()),
+                Keyword::Type("String".to_string()),
+                Keyword::Type("Self".to_string())
+            ],
         }]
     }
 }
\ No newline at end of file
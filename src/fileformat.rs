@@ -0,0 +1,293 @@
+use std::io;
+
+// How a file's text is laid out on disk, so saving writes it back the way
+// it was read.
+
+#[derive(Copy, Clone, PartialEq)]
+pub enum LineEnding {
+    Lf,
+    CrLf,
+}
+
+impl LineEnding {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LineEnding::Lf => "\n",
+            LineEnding::CrLf => "\r\n",
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            LineEnding::Lf => "LF",
+            LineEnding::CrLf => "CRLF",
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq)]
+pub enum Encoding {
+    Utf8,
+    Utf16Le,
+    Utf16Be,
+    Latin1,
+}
+
+impl Encoding {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Encoding::Utf8 => "UTF-8",
+            Encoding::Utf16Le => "UTF-16LE",
+            Encoding::Utf16Be => "UTF-16BE",
+            Encoding::Latin1 => "Latin-1",
+        }
+    }
+}
+
+#[derive(Copy, Clone)]
+pub struct FileFormat {
+    pub encoding: Encoding,
+    pub line_ending: LineEnding,
+    pub bom: bool,
+    pub final_newline: bool,
+}
+
+impl Default for FileFormat {
+    fn default() -> Self {
+        Self {
+            encoding: Encoding::Utf8,
+            line_ending: LineEnding::Lf,
+            bom: false,
+            final_newline: true,
+        }
+    }
+}
+
+const BOM: char = '\u{feff}';
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+// UTF-16 without a BOM shows up as text with every other byte zero
+fn guess_utf16(bytes: &[u8]) -> Option<Encoding> {
+    if bytes.len() < 2 || !bytes.len().is_multiple_of(2) {
+        return None;
+    }
+    let pairs = bytes.len() / 2;
+    let even_zeros = bytes.iter().step_by(2).filter(|b| **b == 0).count();
+    let odd_zeros = bytes.iter().skip(1).step_by(2).filter(|b| **b == 0).count();
+    if odd_zeros * 2 > pairs && even_zeros == 0 {
+        Some(Encoding::Utf16Le)
+    } else if even_zeros * 2 > pairs && odd_zeros == 0 {
+        Some(Encoding::Utf16Be)
+    } else {
+        None
+    }
+}
+
+fn decode_utf16(bytes: &[u8], encoding: Encoding) -> io::Result<String> {
+    if !bytes.len().is_multiple_of(2) {
+        return Err(invalid("invalid UTF-16"));
+    }
+    let units: Vec<u16> = bytes.chunks(2)
+        .map(|pair| match (encoding, pair) {
+            (Encoding::Utf16Le, [lo, hi]) => u16::from_le_bytes([*lo, *hi]),
+            (_, [hi, lo]) => u16::from_be_bytes([*hi, *lo]),
+            _ => unreachable!(),
+        })
+        .collect();
+    String::from_utf16(&units).map_err(|_| invalid("invalid UTF-16"))
+}
+
+impl FileFormat {
+    // Works out the encoding of raw file contents and splits them into
+    // lines. UTF-8 is tried first, then UTF-16 (by BOM or by its zero
+    // bytes); anything else without NUL bytes is taken to be Latin-1, which
+    // maps every byte to a character and so always round-trips. NUL bytes
+    // otherwise mean a binary file, which isn't supported.
+    pub fn decode_bytes(bytes: &[u8]) -> io::Result<(Vec<String>, FileFormat)> {
+        let (text, encoding) = if let Some(rest) = bytes.strip_prefix(&[0xff, 0xfe]) {
+            (format!("{BOM}{}", decode_utf16(rest, Encoding::Utf16Le)?), Encoding::Utf16Le)
+        } else if let Some(rest) = bytes.strip_prefix(&[0xfe, 0xff]) {
+            (format!("{BOM}{}", decode_utf16(rest, Encoding::Utf16Be)?), Encoding::Utf16Be)
+        } else if let Some(encoding) = guess_utf16(bytes) {
+            (decode_utf16(bytes, encoding)?, encoding)
+        } else if bytes.contains(&0) {
+            return Err(invalid("binary file"));
+        } else if let Ok(text) = std::str::from_utf8(bytes) {
+            (text.to_string(), Encoding::Utf8)
+        } else {
+            (bytes.iter().map(|b| *b as char).collect(), Encoding::Latin1)
+        };
+        let (lines, mut format) = FileFormat::decode(&text);
+        format.encoding = encoding;
+        Ok((lines, format))
+    }
+
+    // The text as it goes to disk. Fails on characters the encoding can't
+    // hold rather than writing something different from the buffer.
+    pub fn encode_bytes<'a, I: Iterator<Item = &'a str>>(&self, lines: I) -> io::Result<Vec<u8>> {
+        let text = self.encode(lines);
+        match self.encoding {
+            Encoding::Utf8 => Ok(text.into_bytes()),
+            Encoding::Utf16Le => Ok(text.encode_utf16().flat_map(|unit| unit.to_le_bytes()).collect()),
+            Encoding::Utf16Be => Ok(text.encode_utf16().flat_map(|unit| unit.to_be_bytes()).collect()),
+            Encoding::Latin1 => text.chars()
+                .map(|c| u8::try_from(c)
+                    .map_err(|_| invalid(&format!("'{c}' can't be written as Latin-1"))))
+                .collect(),
+        }
+    }
+
+    // Splits text into lines, noting the format. A file is CRLF when most
+    // of its line breaks are; the trailing '\r' is then removed from every
+    // line that has one.
+    pub fn decode(text: &str) -> (Vec<String>, FileFormat) {
+        let mut format = FileFormat::default();
+        let text = match text.strip_prefix(BOM) {
+            Some(rest) => {
+                format.bom = true;
+                rest
+            }
+            None => text,
+        };
+
+        let breaks = text.matches('\n').count();
+        let crlf = text.matches("\r\n").count();
+        if breaks > 0 && crlf * 2 > breaks {
+            format.line_ending = LineEnding::CrLf;
+        }
+        format.final_newline = text.is_empty() || text.ends_with('\n');
+
+        let mut lines: Vec<String> = text.split('\n')
+            .map(|line| {
+                if format.line_ending == LineEnding::CrLf {
+                    line.strip_suffix('\r').unwrap_or(line).to_string()
+                } else {
+                    line.to_string()
+                }
+            })
+            .collect();
+        if format.final_newline {
+            lines.pop();
+        }
+        (lines, format)
+    }
+
+    pub fn encode<'a, I: Iterator<Item = &'a str>>(&self, lines: I) -> String {
+        let mut buf = String::new();
+        if self.bom {
+            buf.push(BOM);
+        }
+        let mut first = true;
+        for line in lines {
+            if !first {
+                buf.push_str(self.line_ending.as_str());
+            }
+            buf.push_str(line);
+            first = false;
+        }
+        if self.final_newline && !first {
+            buf.push_str(self.line_ending.as_str());
+        }
+        buf
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // decodes the bytes and checks that saving them again changes nothing
+    fn round_trip(bytes: &[u8]) -> (Vec<String>, FileFormat) {
+        let (lines, format) = FileFormat::decode_bytes(bytes).unwrap();
+        let saved = format.encode_bytes(lines.iter().map(String::as_str)).unwrap();
+        assert_eq!(saved, bytes);
+        (lines, format)
+    }
+
+    fn utf16(text: &str, encoding: Encoding) -> Vec<u8> {
+        text.encode_utf16()
+            .flat_map(|unit| match encoding {
+                Encoding::Utf16Le => unit.to_le_bytes(),
+                _ => unit.to_be_bytes(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn detects_line_endings() {
+        let (lines, format) = FileFormat::decode("a\r\nb\r\n");
+        assert_eq!(lines, ["a", "b"]);
+        assert!(format.line_ending == LineEnding::CrLf && format.final_newline);
+
+        // mostly LF, so a stray '\r' is part of the line
+        let (lines, format) = FileFormat::decode("a\r\nb\nc\n");
+        assert_eq!(lines, ["a\r", "b", "c"]);
+        assert!(format.line_ending == LineEnding::Lf);
+
+        let (lines, format) = FileFormat::decode("a\r\nb\r\nc\n");
+        assert_eq!(lines, ["a", "b", "c"]);
+        assert!(format.line_ending == LineEnding::CrLf);
+    }
+
+    #[test]
+    fn detects_bom_and_final_newline() {
+        let (lines, format) = FileFormat::decode("\u{feff}a\nb");
+        assert_eq!(lines, ["a", "b"]);
+        assert!(format.bom && !format.final_newline);
+
+        let (lines, format) = FileFormat::decode("");
+        assert!(lines.is_empty());
+        assert!(!format.bom && format.final_newline);
+
+        let (lines, format) = FileFormat::decode("\n");
+        assert_eq!(lines, [""]);
+        assert!(format.final_newline);
+    }
+
+    #[test]
+    fn round_trips_utf8() {
+        let (lines, format) = round_trip(b"caf\xc3\xa9\r\nx\r\n");
+        assert_eq!(lines, ["café", "x"]);
+        assert!(format.encoding == Encoding::Utf8 && format.line_ending == LineEnding::CrLf);
+        round_trip(b"\xef\xbb\xbfa\nb");
+        round_trip(b"");
+    }
+
+    #[test]
+    fn round_trips_utf16() {
+        for encoding in [Encoding::Utf16Le, Encoding::Utf16Be] {
+            let (lines, format) = round_trip(&utf16("\u{feff}añ\r\n😀\r\n", encoding));
+            assert_eq!(lines, ["añ", "😀"]);
+            assert!(format.encoding == encoding && format.bom && format.line_ending == LineEnding::CrLf);
+
+            // no BOM: found from the zero bytes
+            let (lines, format) = round_trip(&utf16("ab\ncd", encoding));
+            assert_eq!(lines, ["ab", "cd"]);
+            assert!(format.encoding == encoding && !format.bom && !format.final_newline);
+        }
+        assert!(FileFormat::decode_bytes(&[0xff, 0xfe, b'a']).is_err());
+    }
+
+    #[test]
+    fn round_trips_latin1() {
+        let (lines, format) = round_trip(b"caf\xe9\n\xff\n");
+        assert_eq!(lines, ["café", "ÿ"]);
+        assert!(format.encoding == Encoding::Latin1);
+    }
+
+    #[test]
+    fn refuses_what_it_cannot_write() {
+        let (_, format) = FileFormat::decode_bytes(b"caf\xe9\n").unwrap();
+        let err = format.encode_bytes(["caf€"].into_iter()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(err.to_string(), "'€' can't be written as Latin-1");
+
+        match FileFormat::decode_bytes(b"a\0\0b\0") {
+            Err(err) => assert_eq!(err.to_string(), "binary file"),
+            Ok(_) => panic!("decoded a binary file"),
+        }
+    }
+}
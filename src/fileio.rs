@@ -0,0 +1,123 @@
+use std::collections::hash_map::DefaultHasher;
+use std::fs::{self, File, OpenOptions};
+use std::hash::{Hash, Hasher};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use kilo_ed::DateTime;
+
+#[derive(Copy, Clone, PartialEq)]
+pub enum Backup {
+    None,
+    Tilde,          // file~
+    Timestamped,    // file.20240131-235959~
+}
+
+// Follows symlinks so a save replaces the file they point at rather than
+// the link itself. A dangling link resolves to where it points.
+pub fn resolve_symlinks(path: &Path) -> PathBuf {
+    let mut path = path.to_path_buf();
+    for _ in 0..40 {
+        match fs::symlink_metadata(&path) {
+            Ok(meta) if meta.file_type().is_symlink() => {
+                let Ok(target) = fs::read_link(&path) else { break };
+                path = match path.parent() {
+                    Some(dir) if target.is_relative() => dir.join(target),
+                    _ => target,
+                };
+            }
+            _ => break,
+        }
+    }
+    path
+}
+
+fn backup_path(path: &Path, backup: Backup) -> Option<PathBuf> {
+    let mut name = path.file_name()?.to_os_string();
+    match backup {
+        Backup::None => return None,
+        Backup::Tilde => name.push("~"),
+        Backup::Timestamped => name.push(format!(".{}~", DateTime::now_utc().stamp())),
+    }
+    Some(path.with_file_name(name))
+}
+
+// Writes data to a temporary file next to path, syncs it and renames it
+// over path, so a crash or full disk never leaves a truncated file behind.
+// The original permissions are kept.
+pub fn write_atomic(path: &Path, data: &[u8], backup: Backup) -> io::Result<()> {
+    let path = resolve_symlinks(path);
+    let permissions = fs::metadata(&path).ok().map(|meta| meta.permissions());
+
+    if permissions.is_some() {
+        if let Some(backup_path) = backup_path(&path, backup) {
+            fs::copy(&path, backup_path)?;
+        }
+    }
+
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+    let name = path.file_name()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "not a file name"))?;
+    let mut tmp_name = std::ffi::OsString::from(".");
+    tmp_name.push(name);
+    tmp_name.push(format!(".kilo-tmp-{}", std::process::id()));
+    let tmp_path = dir.join(tmp_name);
+
+    let result = (|| {
+        let mut file = OpenOptions::new().write(true).create(true).truncate(true).open(&tmp_path)?;
+        file.write_all(data)?;
+        if let Some(permissions) = permissions {
+            file.set_permissions(permissions)?;
+        }
+        file.sync_all()?;
+        fs::rename(&tmp_path, &path)
+    })();
+
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp_path);
+        return result;
+    }
+    // make the rename itself durable; not every platform can open a directory
+    if let Ok(dir) = File::open(&dir) {
+        let _ = dir.sync_all();
+    }
+    Ok(())
+}
+
+// What a file on disk looked like when it was loaded or saved, to notice
+// other programs changing it.
+#[derive(Copy, Clone, PartialEq)]
+pub struct FileStamp {
+    modified: Option<SystemTime>,
+    len: u64,
+    hash: u64,
+}
+
+impl FileStamp {
+    pub fn of(path: &Path) -> Option<FileStamp> {
+        let meta = fs::metadata(path).ok()?;
+        let data = fs::read(path).ok()?;
+        let mut hasher = DefaultHasher::new();
+        data.hash(&mut hasher);
+        Some(FileStamp {
+            modified: meta.modified().ok(),
+            len: meta.len(),
+            hash: hasher.finish(),
+        })
+    }
+
+    // None when the file is gone. A touched but otherwise identical file
+    // doesn't count as changed.
+    pub fn changed(&self, path: &Path) -> Option<bool> {
+        let meta = fs::metadata(path).ok()?;
+        if meta.modified().ok() == self.modified && meta.len() == self.len {
+            return Some(false);
+        }
+        let now = FileStamp::of(path)?;
+        Some(now.len != self.len || now.hash != self.hash)
+    }
+}
@@ -0,0 +1,177 @@
+use crate::row::{Highlight, Row};
+
+// Folding: regions of rows found from indentation or from matching braces,
+// and the ones currently closed. A closed fold is drawn as its first row,
+// summarised; the rest of its rows are hidden.
+
+// rows start..=end, nested inside `depth` other regions
+#[derive(Copy, Clone)]
+pub struct Region {
+    pub start: usize,
+    pub end: usize,
+    pub depth: usize,
+}
+
+// leading whitespace in screen columns; None for a blank row
+fn indent_of(row: &Row) -> Option<usize> {
+    let text = row.render.trim_start();
+    (!text.is_empty()).then(|| row.render.len() - text.len())
+}
+
+// A row followed by more deeply indented ones starts a region, which ends
+// at the last of them. Blank rows don't end anything.
+pub fn indent_regions(rows: &[Row]) -> Vec<Region> {
+    let mut regions = Vec::new();
+    let mut open: Vec<(usize, usize)> = Vec::new();     // (row, indent) of enclosing rows
+    let mut last = 0;                                   // last non-blank row
+    for (y, row) in rows.iter().enumerate() {
+        let Some(indent) = indent_of(row) else { continue };
+        while let Some(&(start, start_indent)) = open.last() {
+            if indent > start_indent {
+                break;
+            }
+            open.pop();
+            if last > start {
+                regions.push(Region { start, end: last, depth: open.len() });
+            }
+        }
+        open.push((y, indent));
+        last = y;
+    }
+    while let Some((start, _)) = open.pop() {
+        if last > start {
+            regions.push(Region { start, end: last, depth: open.len() });
+        }
+    }
+    regions
+}
+
+// From the row with a '{' to the row with its '}', ignoring braces in
+// comments and strings.
+pub fn brace_regions(rows: &[Row]) -> Vec<Region> {
+    let mut regions = Vec::new();
+    let mut open = Vec::new();
+    for (y, row) in rows.iter().enumerate() {
+        for (cx, c) in row.chars.char_indices() {
+            if c != '{' && c != '}' {
+                continue;
+            }
+            let rx = row.cx_to_rx(cx as u16) as usize;
+            if matches!(row.hl.get(rx), Some(Highlight::Comment | Highlight::String)) {
+                continue;
+            }
+            if c == '{' {
+                // a brace on a row of its own belongs to the row above
+                let own_row = y > 0 && row.chars[..cx].trim().is_empty();
+                open.push(if own_row { y - 1 } else { y });
+            } else if let Some(start) = open.pop() {
+                if y > start {
+                    regions.push(Region { start, end: y, depth: open.len() });
+                }
+            }
+        }
+    }
+    regions
+}
+
+// the innermost region around a row
+pub fn region_at(regions: &[Region], y: usize) -> Option<Region> {
+    regions.iter()
+        .filter(|region| (region.start..=region.end).contains(&y))
+        .max_by_key(|region| region.start)
+        .copied()
+}
+
+// Closed folds, in order and not overlapping.
+#[derive(Default)]
+pub struct Folds {
+    closed: Vec<Region>,
+}
+
+impl Folds {
+    pub fn clear(&mut self) {
+        self.closed.clear();
+    }
+
+    // Closing a region swallows any closed folds inside it.
+    pub fn close(&mut self, region: Region) {
+        self.closed.retain(|fold| fold.end < region.start || fold.start > region.end);
+        let at = self.closed.partition_point(|fold| fold.start < region.start);
+        self.closed.insert(at, region);
+    }
+
+    pub fn open(&mut self, y: usize) -> bool {
+        let before = self.closed.len();
+        self.closed.retain(|fold| !(fold.start..=fold.end).contains(&y));
+        self.closed.len() != before
+    }
+
+    // Opens the fold hiding row y; false if there's none.
+    pub fn open_hiding(&mut self, y: usize) -> bool {
+        let Some(start) = self.hiding(y).map(|fold| fold.start) else { return false };
+        self.open(start)
+    }
+
+    // the closed fold shown on row y
+    pub fn at(&self, y: usize) -> Option<&Region> {
+        self.closed.iter().find(|fold| fold.start == y)
+    }
+
+    // the closed fold that hides row y
+    pub fn hiding(&self, y: usize) -> Option<&Region> {
+        self.closed.iter().find(|fold| fold.start < y && y <= fold.end)
+    }
+
+    pub fn next_visible(&self, y: usize) -> usize {
+        self.at(y).map_or(y, |fold| fold.end) + 1
+    }
+
+    pub fn prev_visible(&self, y: usize) -> usize {
+        let y = y.saturating_sub(1);
+        self.hiding(y).map_or(y, |fold| fold.start)
+    }
+
+    // the row n rows on screen below row y
+    pub fn skip_visible(&self, y: usize, n: usize) -> usize {
+        (0..n).fold(y, |y, _| self.next_visible(y))
+    }
+
+    // rows on screen from `from` down to and including `to`
+    pub fn count_visible(&self, from: usize, to: usize) -> usize {
+        let mut count = 0;
+        let mut y = from;
+        while y <= to {
+            count += 1;
+            y = self.next_visible(y);
+        }
+        count
+    }
+
+    // Keeps folds on the same text when a row is inserted at `at`; a row
+    // inserted inside a fold joins it.
+    pub fn row_inserted(&mut self, at: usize) {
+        for fold in &mut self.closed {
+            if fold.start >= at {
+                fold.start += 1;
+                fold.end += 1;
+            } else if fold.end >= at {
+                fold.end += 1;
+            }
+        }
+    }
+
+    // A fold loses a deleted row, and is gone if that was its first row or
+    // it has only one left.
+    pub fn row_removed(&mut self, at: usize) {
+        self.closed.retain(|fold| fold.start != at);
+        for fold in &mut self.closed {
+            if fold.start > at {
+                fold.start -= 1;
+                fold.end -= 1;
+            } else if fold.end >= at {
+                fold.end -= 1;
+            }
+        }
+        self.closed.retain(|fold| fold.end > fold.start);
+    }
+}
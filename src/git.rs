@@ -0,0 +1,79 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+// Repository discovery and HEAD are read straight from .git; committed file
+// contents come from `git show`, since objects are compressed and packed.
+
+// the work tree containing path (a file or directory), if any
+pub fn find_root(path: &Path) -> Option<PathBuf> {
+    let start = if path.as_os_str().is_empty() { Path::new(".") } else { path };
+    let start = fs::canonicalize(start).ok()?;
+    start.ancestors()
+        .find(|dir| dir.join(".git").exists())
+        .map(Path::to_path_buf)
+}
+
+// .git is normally a directory, but a worktree or submodule has a file
+// pointing elsewhere instead
+pub fn git_dir(root: &Path) -> Option<PathBuf> {
+    let dot_git = root.join(".git");
+    if dot_git.is_dir() {
+        return Some(dot_git);
+    }
+    let text = fs::read_to_string(&dot_git).ok()?;
+    let dir = text.trim().strip_prefix("gitdir: ")?;
+    Some(root.join(dir))
+}
+
+// the checked out branch, or the short commit hash when HEAD is detached
+pub fn branch(root: &Path) -> Option<String> {
+    let head = fs::read_to_string(git_dir(root)?.join("HEAD")).ok()?;
+    let head = head.trim();
+    match head.strip_prefix("ref: ") {
+        Some(reference) => Some(reference.strip_prefix("refs/heads/").unwrap_or(reference).to_string()),
+        None => Some(head.chars().take(7).collect()),
+    }
+}
+
+// the file's contents as of HEAD; None when it isn't tracked or git fails
+pub fn head_blob(root: &Path, file: &Path) -> Option<Vec<u8>> {
+    let file = fs::canonicalize(file).ok()?;
+    let relative = file.strip_prefix(root).ok()?;
+    let output = Command::new("git")
+        .arg("-C").arg(root)
+        .arg("show").arg(format!("HEAD:{}", relative.display()))
+        .stdin(Stdio::null())
+        .stderr(Stdio::null())
+        .output()
+        .ok()?;
+    output.status.success().then_some(output.stdout)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{git, TempDir};
+
+    #[test]
+    fn reads_the_repository() {
+        let dir = TempDir::new("git");
+        let file = dir.path().join("notes.txt");
+        fs::write(&file, "one\ntwo\n").unwrap();
+        assert_eq!(head_blob(dir.path(), &file), None);
+        git(dir.path(), &["init", "-q", "-b", "main"]);
+        git(dir.path(), &["add", "notes.txt"]);
+        git(dir.path(), &["commit", "-q", "-m", "notes"]);
+        fs::write(&file, "changed\n").unwrap();
+
+        let root = find_root(dir.path()).unwrap();
+        assert_eq!(root, fs::canonicalize(dir.path()).unwrap());
+        assert_eq!(find_root(&file), Some(root.clone()));
+        assert_eq!(branch(&root).as_deref(), Some("main"));
+        assert_eq!(head_blob(&root, &file).as_deref(), Some(&b"one\ntwo\n"[..]));
+        assert_eq!(head_blob(&root, &root.join("untracked.txt")), None);
+
+        git(dir.path(), &["checkout", "-q", "--detach"]);
+        assert_eq!(branch(&root).map(|hash| hash.len()), Some(7));
+    }
+}
@@ -0,0 +1,81 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use kilo_ed::Position;
+use crate::config;
+use crate::fileio::{self, Backup};
+
+// Where each recently edited file was left, most recent first, kept in
+// $XDG_STATE_HOME/kilo-ed/files as lines of
+//
+//     ROW COLUMN ROWOFF COLOFF PATH
+
+const MAX_FILES: usize = 200;
+
+pub struct FileState {
+    pub path: PathBuf,
+    pub cursor: Position,
+    pub rowoff: u16,
+    pub coloff: u16,
+}
+
+// the absolute path a file is remembered under
+pub fn key(filename: &str) -> Option<PathBuf> {
+    if filename.is_empty() {
+        return None;
+    }
+    let path = fileio::resolve_symlinks(Path::new(filename));
+    fs::canonicalize(&path).ok().or_else(|| std::path::absolute(&path).ok())
+}
+
+fn parse(line: &str) -> Option<FileState> {
+    let mut fields = line.splitn(5, ' ');
+    let mut number = || fields.next()?.parse::<u16>().ok();
+    let (y, x, rowoff, coloff) = (number()?, number()?, number()?, number()?);
+    let path = PathBuf::from(fields.next().filter(|path| !path.is_empty())?);
+    Some(FileState { path, cursor: Position { x, y }, rowoff, coloff })
+}
+
+#[derive(Default)]
+pub struct History {
+    files: Vec<FileState>,
+}
+
+impl History {
+    // Anything unreadable is forgotten rather than reported.
+    pub fn load() -> History {
+        let text = config::state_file("files").and_then(|path| fs::read_to_string(path).ok());
+        let files = text.iter().flat_map(|text| text.lines()).filter_map(parse).collect();
+        History { files }
+    }
+
+    pub fn get(&self, path: &Path) -> Option<&FileState> {
+        self.files.iter().find(|file| file.path == path)
+    }
+
+    pub fn recent(&self) -> impl Iterator<Item = &Path> {
+        self.files.iter().map(|file| file.path.as_path())
+    }
+
+    // Moves a file to the front, on top of whatever other instances have
+    // saved since this one loaded the list.
+    pub fn remember(&mut self, state: FileState) -> io::Result<()> {
+        *self = History::load();
+        self.files.retain(|file| file.path != state.path);
+        self.files.insert(0, state);
+        self.files.truncate(MAX_FILES);
+
+        let path = config::state_file("files")
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no state directory"))?;
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let mut buf = String::new();
+        for file in &self.files {
+            buf.push_str(&format!("{} {} {} {} {}\n", file.cursor.y, file.cursor.x,
+                                  file.rowoff, file.coloff, file.path.display()));
+        }
+        fileio::write_atomic(&path, buf.as_bytes(), Backup::None)
+    }
+}
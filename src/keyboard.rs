@@ -1,22 +1,149 @@
-use crossterm::event::{read, KeyEvent};
-use crossterm::event::Event::Key;
+use std::collections::VecDeque;
+use std::time::Duration;
+use crossterm::event::{poll, read, Event, KeyEvent, MouseEvent, MouseEventKind};
 
 use kilo_ed::EditorResult;
 use kilo_ed::StdResult;
 
-pub struct Keyboard;
+pub enum Input {
+    Key(KeyEvent),
+    Mouse(MouseEvent),
+    Resize(u16, u16),
+    FocusGained,
+}
+
+pub struct Keyboard {
+    recording: Option<Vec<KeyEvent>>,
+    playback: VecDeque<KeyEvent>,
+    // keys still to be queued again and how many more times, innermost
+    // play last; one copy each however large the count
+    repeats: Vec<(Vec<KeyEvent>, usize)>,
+}
 
 impl Keyboard {
-    pub fn read(&self) -> StdResult<KeyEvent, EditorResult> {
+    pub fn new() -> Self {
+        Self {
+            recording: None,
+            playback: VecDeque::new(),
+            repeats: Vec::new(),
+        }
+    }
+
+    pub fn read(&mut self) -> StdResult<Input, EditorResult> {
+        self.refill();
+        if let Some(key_event) = self.playback.pop_front() {
+            return Ok(Input::Key(key_event));
+        }
         loop {
-            if let Ok(event) = read() {
-                if let Key(key_event) = event {
-                    return Ok(key_event);
+            match read() {
+                Ok(Event::Key(key_event)) => {
+                    if let Some(recording) = self.recording.as_mut() {
+                        recording.push(key_event);
+                    }
+                    return Ok(Input::Key(key_event));
+                }
+                // plain motion isn't worth a redraw
+                Ok(Event::Mouse(mouse_event)) if mouse_event.kind != MouseEventKind::Moved => {
+                    return Ok(Input::Mouse(mouse_event));
                 }
-            } else {
-                return Err(EditorResult::KeyReadFail);
+                Ok(Event::Resize(width, height)) => return Ok(Input::Resize(width, height)),
+                Ok(Event::FocusGained) => return Ok(Input::FocusGained),
+                Ok(_) => {}
+                Err(_) => return Err(EditorResult::KeyReadFail),
+            }
+        }
+    }
+
+    // true when read() won't block
+    pub fn poll(&self, timeout: Duration) -> bool {
+        self.is_playing() || poll(timeout).unwrap_or(true)
+    }
+
+    pub fn start_recording(&mut self) {
+        self.recording = Some(Vec::new());
+    }
+
+    // drop_trailing removes the keys that asked to stop the recording
+    pub fn stop_recording(&mut self, drop_trailing: usize) -> Vec<KeyEvent> {
+        let mut keys = self.recording.take().unwrap_or_default();
+        keys.truncate(keys.len().saturating_sub(drop_trailing));
+        keys
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.recording.is_some()
+    }
+
+    pub fn play(&mut self, keys: &[KeyEvent], times: usize) {
+        if keys.is_empty() || times == 0 {
+            return;
+        }
+        self.playback.extend(keys.iter().copied());
+        if times > 1 {
+            self.repeats.push((keys.to_vec(), times - 1));
+        }
+    }
+
+    fn refill(&mut self) {
+        if !self.playback.is_empty() {
+            return;
+        }
+        if let Some((keys, times)) = self.repeats.last_mut() {
+            self.playback.extend(keys.iter().copied());
+            *times -= 1;
+            if *times == 0 {
+                self.repeats.pop();
             }
         }
     }
+
+    pub fn is_playing(&self) -> bool {
+        !self.playback.is_empty() || !self.repeats.is_empty()
+    }
+
+    pub fn stop_playback(&mut self) {
+        self.playback.clear();
+        self.repeats.clear();
+    }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crossterm::event::KeyCode;
+
+    fn next(keyboard: &mut Keyboard) -> KeyCode {
+        match keyboard.read() {
+            Ok(Input::Key(key_event)) => key_event.code,
+            _ => panic!("expected a key"),
+        }
+    }
+
+    #[test]
+    fn repeats_without_copying_every_time() {
+        let keys = [KeyEvent::from(KeyCode::Char('a')), KeyEvent::from(KeyCode::Char('b'))];
+        let mut keyboard = Keyboard::new();
+        keyboard.play(&keys, usize::MAX);
+        assert_eq!(keyboard.playback.len(), 2);
+        for _ in 0..3 {
+            assert_eq!(next(&mut keyboard), KeyCode::Char('a'));
+            assert_eq!(next(&mut keyboard), KeyCode::Char('b'));
+        }
+        assert!(keyboard.playback.len() <= 2);
+        keyboard.stop_playback();
+        assert!(!keyboard.is_playing());
+    }
+
+    #[test]
+    fn plays_a_macro_started_by_another_one_first() {
+        let outer = [KeyEvent::from(KeyCode::Char('o'))];
+        let inner = [KeyEvent::from(KeyCode::Char('i'))];
+        let mut keyboard = Keyboard::new();
+        keyboard.play(&outer, 2);
+        assert_eq!(next(&mut keyboard), KeyCode::Char('o'));
+        keyboard.play(&inner, 2);
+        let played: Vec<KeyCode> = (0..3).map(|_| next(&mut keyboard)).collect();
+        assert_eq!(played, [KeyCode::Char('i'), KeyCode::Char('i'), KeyCode::Char('o')]);
+        assert!(!keyboard.is_playing());
+    }
+}
@@ -0,0 +1,515 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::path::Path;
+use crossterm::event::{KeyCode, KeyEvent, KeyModifiers, MediaKeyCode, ModifierKeyCode};
+
+use kilo_ed::StdResult;
+use crate::config;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Command {
+    Save,
+    Find,
+    Quit,
+    MoveLeft,
+    MoveRight,
+    MoveUp,
+    MoveDown,
+    Home,
+    End,
+    PageUp,
+    PageDown,
+    DeleteBack,
+    DeleteForward,
+    Newline,
+    InsertTab,
+    Redraw,
+    ToggleVi,
+    RecordMacro,
+    PlayMacro,
+    SaveMacro,
+    RunMacro,
+    Palette,
+    Copy,
+    Cut,
+    Paste,
+    LineEndingsLf,
+    LineEndingsCrlf,
+    NextHunk,
+    PreviousHunk,
+    RevertHunk,
+    Hover,
+    Complete,
+    GotoDefinition,
+    CompleteWord,
+    SpellSuggest,
+    NextMisspelling,
+    Fold,
+    Unfold,
+    ToggleFold,
+    FoldAll,
+    UnfoldAll,
+    SetMark,
+    GotoMark,
+    JumpBack,
+    JumpForward,
+    RecentFiles,
+    SaveSession,
+    Nop,
+}
+
+// name used in the keymap file <-> command
+const COMMANDS: &[(&str, Command)] = &[
+    ("save", Command::Save),
+    ("find", Command::Find),
+    ("quit", Command::Quit),
+    ("move-left", Command::MoveLeft),
+    ("move-right", Command::MoveRight),
+    ("move-up", Command::MoveUp),
+    ("move-down", Command::MoveDown),
+    ("home", Command::Home),
+    ("end", Command::End),
+    ("page-up", Command::PageUp),
+    ("page-down", Command::PageDown),
+    ("delete-back", Command::DeleteBack),
+    ("delete-forward", Command::DeleteForward),
+    ("newline", Command::Newline),
+    ("insert-tab", Command::InsertTab),
+    ("redraw", Command::Redraw),
+    ("toggle-vi", Command::ToggleVi),
+    ("record-macro", Command::RecordMacro),
+    ("play-macro", Command::PlayMacro),
+    ("save-macro", Command::SaveMacro),
+    ("run-macro", Command::RunMacro),
+    ("command-line", Command::Palette),
+    ("copy", Command::Copy),
+    ("cut", Command::Cut),
+    ("paste", Command::Paste),
+    ("line-endings-lf", Command::LineEndingsLf),
+    ("line-endings-crlf", Command::LineEndingsCrlf),
+    ("next-hunk", Command::NextHunk),
+    ("previous-hunk", Command::PreviousHunk),
+    ("revert-hunk", Command::RevertHunk),
+    ("hover", Command::Hover),
+    ("complete", Command::Complete),
+    ("goto-definition", Command::GotoDefinition),
+    ("complete-word", Command::CompleteWord),
+    ("spell-suggest", Command::SpellSuggest),
+    ("next-misspelling", Command::NextMisspelling),
+    ("fold", Command::Fold),
+    ("unfold", Command::Unfold),
+    ("toggle-fold", Command::ToggleFold),
+    ("fold-all", Command::FoldAll),
+    ("unfold-all", Command::UnfoldAll),
+    ("set-mark", Command::SetMark),
+    ("goto-mark", Command::GotoMark),
+    ("jump-back", Command::JumpBack),
+    ("jump-forward", Command::JumpForward),
+    ("recent-files", Command::RecentFiles),
+    ("save-session", Command::SaveSession),
+    ("nop", Command::Nop),
+];
+
+impl Command {
+    pub fn from_name(name: &str) -> Option<Command> {
+        COMMANDS.iter()
+            .find(|(n, _)| *n == name)
+            .map(|(_, command)| *command)
+    }
+
+    pub fn name(&self) -> &'static str {
+        COMMANDS.iter()
+            .find(|(_, command)| command == self)
+            .map(|(n, _)| *n)
+            .unwrap_or("nop")
+    }
+
+    pub fn all() -> impl Iterator<Item = Command> {
+        COMMANDS.iter().map(|(_, command)| *command)
+    }
+}
+
+// name in the keymap file <-> key; a key's first name is the one it's
+// printed with, and names are matched ignoring case
+const KEYS: &[(&str, KeyCode)] = &[
+    ("Left", KeyCode::Left),
+    ("Right", KeyCode::Right),
+    ("Up", KeyCode::Up),
+    ("Down", KeyCode::Down),
+    ("Home", KeyCode::Home),
+    ("End", KeyCode::End),
+    ("PageUp", KeyCode::PageUp),
+    ("PgUp", KeyCode::PageUp),
+    ("PageDown", KeyCode::PageDown),
+    ("PgDn", KeyCode::PageDown),
+    ("Delete", KeyCode::Delete),
+    ("Del", KeyCode::Delete),
+    ("Insert", KeyCode::Insert),
+    ("Ins", KeyCode::Insert),
+    ("Backspace", KeyCode::Backspace),
+    ("BS", KeyCode::Backspace),
+    ("Enter", KeyCode::Enter),
+    ("Ret", KeyCode::Enter),
+    ("Tab", KeyCode::Tab),
+    ("BackTab", KeyCode::BackTab),
+    ("Esc", KeyCode::Esc),
+    ("Escape", KeyCode::Esc),
+    ("Space", KeyCode::Char(' ')),
+    ("Spc", KeyCode::Char(' ')),
+    ("Null", KeyCode::Null),
+    ("CapsLock", KeyCode::CapsLock),
+    ("ScrollLock", KeyCode::ScrollLock),
+    ("NumLock", KeyCode::NumLock),
+    ("PrintScreen", KeyCode::PrintScreen),
+    ("Pause", KeyCode::Pause),
+    ("Menu", KeyCode::Menu),
+    ("KeypadBegin", KeyCode::KeypadBegin),
+    ("MediaPlay", KeyCode::Media(MediaKeyCode::Play)),
+    ("MediaPause", KeyCode::Media(MediaKeyCode::Pause)),
+    ("MediaPlayPause", KeyCode::Media(MediaKeyCode::PlayPause)),
+    ("MediaReverse", KeyCode::Media(MediaKeyCode::Reverse)),
+    ("MediaStop", KeyCode::Media(MediaKeyCode::Stop)),
+    ("MediaFastForward", KeyCode::Media(MediaKeyCode::FastForward)),
+    ("MediaRewind", KeyCode::Media(MediaKeyCode::Rewind)),
+    ("MediaTrackNext", KeyCode::Media(MediaKeyCode::TrackNext)),
+    ("MediaTrackPrevious", KeyCode::Media(MediaKeyCode::TrackPrevious)),
+    ("MediaRecord", KeyCode::Media(MediaKeyCode::Record)),
+    ("VolumeDown", KeyCode::Media(MediaKeyCode::LowerVolume)),
+    ("VolumeUp", KeyCode::Media(MediaKeyCode::RaiseVolume)),
+    ("VolumeMute", KeyCode::Media(MediaKeyCode::MuteVolume)),
+    ("LeftShift", KeyCode::Modifier(ModifierKeyCode::LeftShift)),
+    ("LeftControl", KeyCode::Modifier(ModifierKeyCode::LeftControl)),
+    ("LeftAlt", KeyCode::Modifier(ModifierKeyCode::LeftAlt)),
+    ("LeftSuper", KeyCode::Modifier(ModifierKeyCode::LeftSuper)),
+    ("LeftHyper", KeyCode::Modifier(ModifierKeyCode::LeftHyper)),
+    ("LeftMeta", KeyCode::Modifier(ModifierKeyCode::LeftMeta)),
+    ("RightShift", KeyCode::Modifier(ModifierKeyCode::RightShift)),
+    ("RightControl", KeyCode::Modifier(ModifierKeyCode::RightControl)),
+    ("RightAlt", KeyCode::Modifier(ModifierKeyCode::RightAlt)),
+    ("RightSuper", KeyCode::Modifier(ModifierKeyCode::RightSuper)),
+    ("RightHyper", KeyCode::Modifier(ModifierKeyCode::RightHyper)),
+    ("RightMeta", KeyCode::Modifier(ModifierKeyCode::RightMeta)),
+    ("IsoLevel3Shift", KeyCode::Modifier(ModifierKeyCode::IsoLevel3Shift)),
+    ("IsoLevel5Shift", KeyCode::Modifier(ModifierKeyCode::IsoLevel5Shift)),
+];
+
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+pub struct Key {
+    pub code: KeyCode,
+    pub modifiers: KeyModifiers,
+}
+
+impl Key {
+    pub fn new(code: KeyCode, modifiers: KeyModifiers) -> Self {
+        // the case of a character already carries shift, and terminals can't
+        // tell Ctrl-s from Ctrl-S
+        match code {
+            KeyCode::Char(c) => {
+                let modifiers = modifiers - KeyModifiers::SHIFT;
+                let c = if modifiers.contains(KeyModifiers::CONTROL) {
+                    c.to_ascii_lowercase()
+                } else {
+                    c
+                };
+                Key { code: KeyCode::Char(c), modifiers }
+            }
+            KeyCode::Tab | KeyCode::BackTab if modifiers.contains(KeyModifiers::SHIFT) || code == KeyCode::BackTab => {
+                Key { code: KeyCode::BackTab, modifiers: modifiers - KeyModifiers::SHIFT }
+            }
+            _ => Key { code, modifiers },
+        }
+    }
+
+    // character to insert when the key isn't bound to anything
+    pub fn printable(&self) -> Option<char> {
+        match self.code {
+            KeyCode::Char(c) if self.modifiers.is_empty() => Some(c),
+            _ => None,
+        }
+    }
+
+    pub fn parse(s: &str) -> StdResult<Key, String> {
+        let mut modifiers = KeyModifiers::NONE;
+        let mut rest = s;
+        loop {
+            let (prefix, modifier) = match rest.split_once('-') {
+                Some((prefix, tail)) if !tail.is_empty() => (prefix, tail),
+                _ => break,
+            };
+            modifiers |= match prefix.to_ascii_lowercase().as_str() {
+                "c" | "ctrl" => KeyModifiers::CONTROL,
+                "m" | "a" | "alt" | "meta" => KeyModifiers::ALT,
+                "s" | "shift" => KeyModifiers::SHIFT,
+                "super" => KeyModifiers::SUPER,
+                "hyper" => KeyModifiers::HYPER,
+                _ => return Err(format!("unknown modifier '{prefix}' in '{s}'")),
+            };
+            rest = modifier;
+        }
+
+        let name = rest.to_ascii_lowercase();
+        let code = match KEYS.iter().find(|(key, _)| key.eq_ignore_ascii_case(rest)) {
+            Some((_, code)) => *code,
+            None if name.len() > 1 && name.starts_with('f') => {
+                match name[1..].parse::<u8>() {
+                    Ok(n) if n > 0 => KeyCode::F(n),
+                    _ => return Err(format!("unknown key '{s}'")),
+                }
+            }
+            // a character that can't be written as itself, like U+00A0
+            None if name.len() > 2 && name.starts_with("u+") => {
+                match u32::from_str_radix(&name[2..], 16).ok().and_then(char::from_u32) {
+                    Some(c) => KeyCode::Char(c),
+                    None => return Err(format!("unknown key '{s}'")),
+                }
+            }
+            None => {
+                let mut chars = rest.chars();
+                match (chars.next(), chars.next()) {
+                    (Some(c), None) => KeyCode::Char(c),
+                    _ => return Err(format!("unknown key '{s}'")),
+                }
+            }
+        };
+        Ok(Key::new(code, modifiers))
+    }
+}
+
+impl From<KeyEvent> for Key {
+    fn from(event: KeyEvent) -> Self {
+        Key::new(event.code, event.modifiers)
+    }
+}
+
+impl fmt::Display for Key {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.modifiers.contains(KeyModifiers::CONTROL) {
+            write!(f, "Ctrl-")?;
+        }
+        if self.modifiers.contains(KeyModifiers::ALT) {
+            write!(f, "Alt-")?;
+        }
+        if self.modifiers.contains(KeyModifiers::SHIFT) {
+            write!(f, "Shift-")?;
+        }
+        if self.modifiers.contains(KeyModifiers::SUPER) {
+            write!(f, "Super-")?;
+        }
+        if self.modifiers.contains(KeyModifiers::HYPER) {
+            write!(f, "Hyper-")?;
+        }
+        if let Some((name, _)) = KEYS.iter().find(|(_, code)| *code == self.code) {
+            return write!(f, "{name}");
+        }
+        match self.code {
+            KeyCode::Char(c) if c.is_whitespace() || c.is_control() => write!(f, "U+{:04X}", c as u32),
+            KeyCode::Char(c) if self.modifiers.contains(KeyModifiers::CONTROL) => {
+                write!(f, "{}", c.to_ascii_uppercase())
+            }
+            KeyCode::Char(c) => write!(f, "{c}"),
+            KeyCode::F(n) => write!(f, "F{n}"),
+            code => write!(f, "{code:?}"),
+        }
+    }
+}
+
+pub fn chord_to_string(keys: &[Key]) -> String {
+    keys.iter()
+        .map(|key| key.to_string())
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+pub fn parse_chord(s: &str) -> StdResult<Vec<Key>, String> {
+    let keys = s.split_whitespace()
+        .map(Key::parse)
+        .collect::<StdResult<Vec<Key>, String>>()?;
+    if keys.is_empty() {
+        Err("empty key binding".to_string())
+    } else {
+        Ok(keys)
+    }
+}
+
+pub enum Lookup {
+    Command(Command),
+    Prefix,
+    Unbound,
+}
+
+pub struct Keymap {
+    bindings: HashMap<Vec<Key>, Command>,
+}
+
+impl Default for Keymap {
+    fn default() -> Self {
+        let mut keymap = Keymap { bindings: HashMap::new() };
+        for (chord, command) in [
+            ("Ctrl-Q", Command::Quit),
+            ("Ctrl-S", Command::Save),
+            ("Ctrl-F", Command::Find),
+            ("Ctrl-L", Command::Redraw),
+            ("Ctrl-H", Command::DeleteBack),
+            ("Ctrl-X Ctrl-S", Command::Save),
+            ("Ctrl-X Ctrl-C", Command::Quit),
+            ("Ctrl-P", Command::Palette),
+            ("Alt-x", Command::Palette),
+            ("Alt-w", Command::Copy),
+            ("Ctrl-W", Command::Cut),
+            ("Ctrl-Y", Command::Paste),
+            ("Ctrl-X (", Command::RecordMacro),
+            ("Ctrl-X )", Command::RecordMacro),
+            ("Ctrl-X e", Command::PlayMacro),
+            ("F3", Command::RecordMacro),
+            ("F4", Command::PlayMacro),
+            ("Alt-n", Command::NextHunk),
+            ("Alt-p", Command::PreviousHunk),
+            ("Ctrl-X r", Command::RevertHunk),
+            ("Alt-h", Command::Hover),
+            ("Ctrl-Space", Command::Complete),
+            ("F12", Command::GotoDefinition),
+            ("Alt-/", Command::CompleteWord),
+            ("Alt-s", Command::SpellSuggest),
+            ("Ctrl-X s", Command::NextMisspelling),
+            ("Ctrl-X f", Command::ToggleFold),
+            ("Ctrl-X m", Command::SetMark),
+            ("Ctrl-X '", Command::GotoMark),
+            ("Alt-,", Command::JumpBack),
+            ("Alt-.", Command::JumpForward),
+            ("Ctrl-X Ctrl-R", Command::RecentFiles),
+            ("Backspace", Command::DeleteBack),
+            ("Delete", Command::DeleteForward),
+            ("Enter", Command::Newline),
+            ("Tab", Command::InsertTab),
+            ("Esc", Command::Nop),
+            ("Home", Command::Home),
+            ("End", Command::End),
+            ("Up", Command::MoveUp),
+            ("Down", Command::MoveDown),
+            ("Left", Command::MoveLeft),
+            ("Right", Command::MoveRight),
+            ("PageUp", Command::PageUp),
+            ("PageDown", Command::PageDown),
+        ] {
+            keymap.bind(parse_chord(chord).unwrap(), command);
+        }
+        keymap
+    }
+}
+
+impl Keymap {
+    pub fn bind(&mut self, keys: Vec<Key>, command: Command) {
+        self.bindings.insert(keys, command);
+    }
+
+    pub fn unbind(&mut self, keys: &[Key]) {
+        self.bindings.remove(keys);
+    }
+
+    pub fn lookup(&self, keys: &[Key]) -> Lookup {
+        if let Some(command) = self.bindings.get(keys) {
+            Lookup::Command(*command)
+        } else if self.bindings.keys().any(|chord| chord.len() > keys.len() && chord.starts_with(keys)) {
+            Lookup::Prefix
+        } else {
+            Lookup::Unbound
+        }
+    }
+
+    pub fn keys_for(&self, command: Command) -> Option<Vec<Key>> {
+        self.bindings.iter()
+            .filter(|(_, c)| **c == command)
+            .map(|(keys, _)| keys.clone())
+            .min_by_key(|keys| keys.len())
+    }
+
+    // Overrides from a file of `Ctrl-X Ctrl-S = save` lines. A command of
+    // `unbind` removes the binding.
+    pub fn load(&mut self, path: &Path) -> StdResult<(), String> {
+        config::for_each_line(path, |line| self.load_line(line))
+    }
+
+    fn load_line(&mut self, line: &str) -> StdResult<(), String> {
+        let (chord, name) = line.rsplit_once('=').ok_or("expected 'keys = command'")?;
+        let keys = parse_chord(chord)?;
+        match name.trim() {
+            "unbind" => self.unbind(&keys),
+            name => {
+                let command = Command::from_name(name)
+                    .ok_or_else(|| format!("unknown command '{name}'"))?;
+                self.bind(keys, command);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(code: KeyCode, modifiers: KeyModifiers) -> Key {
+        Key { code, modifiers }
+    }
+
+    #[test]
+    fn parses_key_specs() {
+        assert_eq!(Key::parse("Ctrl-S"), Ok(key(KeyCode::Char('s'), KeyModifiers::CONTROL)));
+        assert_eq!(Key::parse("c-m-x"), Ok(key(KeyCode::Char('x'), KeyModifiers::CONTROL | KeyModifiers::ALT)));
+        assert_eq!(Key::parse("Alt--"), Ok(key(KeyCode::Char('-'), KeyModifiers::ALT)));
+        assert_eq!(Key::parse("Shift-a"), Ok(key(KeyCode::Char('a'), KeyModifiers::NONE)));
+        assert_eq!(Key::parse("Shift-Tab"), Ok(key(KeyCode::BackTab, KeyModifiers::NONE)));
+        assert_eq!(Key::parse("pgdn"), Ok(key(KeyCode::PageDown, KeyModifiers::NONE)));
+        assert_eq!(Key::parse("F12"), Ok(key(KeyCode::F(12), KeyModifiers::NONE)));
+        assert_eq!(Key::parse("Ctrl-Space"), Ok(key(KeyCode::Char(' '), KeyModifiers::CONTROL)));
+        assert_eq!(Key::parse("f"), Ok(key(KeyCode::Char('f'), KeyModifiers::NONE)));
+    }
+
+    #[test]
+    fn prints_keys_as_they_are_parsed() {
+        for spec in ["Ctrl-S", "Ctrl-Alt-X", "Alt-x", "Space", "F3", "F20", "PageUp", "BackTab", "é",
+                     "U+00A0", "Super-Hyper-CapsLock", "Shift-MediaPlayPause", "RightMeta"] {
+            let key = Key::parse(spec).unwrap();
+            assert_eq!(key.to_string(), spec);
+            assert_eq!(Key::parse(&key.to_string()), Ok(key));
+        }
+        for (_, code) in KEYS {
+            let key = key(*code, KeyModifiers::NONE);
+            assert_eq!(Key::parse(&key.to_string()), Ok(key));
+        }
+    }
+
+    #[test]
+    fn reports_bad_key_specs() {
+        assert_eq!(Key::parse("Win-x"), Err("unknown modifier 'Win' in 'Win-x'".to_string()));
+        assert_eq!(Key::parse("F0"), Err("unknown key 'F0'".to_string()));
+        assert_eq!(Key::parse("U+D800"), Err("unknown key 'U+D800'".to_string()));
+        assert_eq!(Key::parse("Ctrl-xy"), Err("unknown key 'Ctrl-xy'".to_string()));
+        assert_eq!(parse_chord("  "), Err("empty key binding".to_string()));
+        assert_eq!(parse_chord("Ctrl-X Foo"), Err("unknown key 'Foo'".to_string()));
+    }
+
+    #[test]
+    fn looks_up_chords() {
+        let keymap = Keymap::default();
+        let chord = parse_chord("Ctrl-X Ctrl-S").unwrap();
+        assert!(matches!(keymap.lookup(&chord), Lookup::Command(Command::Save)));
+        assert!(matches!(keymap.lookup(&chord[..1]), Lookup::Prefix));
+        assert!(matches!(keymap.lookup(&parse_chord("Ctrl-X z").unwrap()), Lookup::Unbound));
+        assert!(matches!(keymap.lookup(&parse_chord("a").unwrap()), Lookup::Unbound));
+        assert_eq!(keymap.keys_for(Command::Save), Some(parse_chord("Ctrl-S").unwrap()));
+        assert_eq!(chord_to_string(&chord), "Ctrl-X Ctrl-S");
+    }
+
+    #[test]
+    fn loads_overrides() {
+        let mut keymap = Keymap::default();
+        keymap.load_line("Ctrl-X Ctrl-S = quit").unwrap();
+        keymap.load_line("Ctrl-Q = unbind").unwrap();
+        assert!(matches!(keymap.lookup(&parse_chord("Ctrl-X Ctrl-S").unwrap()), Lookup::Command(Command::Quit)));
+        assert!(matches!(keymap.lookup(&parse_chord("Ctrl-Q").unwrap()), Lookup::Unbound));
+        assert_eq!(keymap.load_line("Ctrl-S save"), Err("expected 'keys = command'".to_string()));
+        assert_eq!(keymap.load_line("Ctrl-S = sve"), Err("unknown command 'sve'".to_string()));
+        assert_eq!(keymap.load_line(" = save"), Err("empty key binding".to_string()));
+    }
+}
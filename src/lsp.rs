@@ -0,0 +1,594 @@
+use std::collections::HashMap;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Child, ChildStdin, ChildStdout, Command, Stdio};
+use std::sync::mpsc::{self, Receiver, Sender, TryRecvError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use kilo_ed::StdResult;
+use crate::completion::CompletionItem;
+use crate::config;
+use crate::json::Json;
+
+// Language server client: JSON-RPC over the server's stdin and stdout,
+// one server per open file. A reader thread turns the server's output into
+// messages that the editor polls for between keys.
+
+// filetype (see EditorSyntax) -> command line, overridable in the `lsp`
+// config file
+pub fn servers() -> StdResult<HashMap<String, String>, String> {
+    let mut servers = HashMap::from([
+        (String::from("c"), String::from("clangd")),
+        (String::from("rust"), String::from("rust-analyzer")),
+    ]);
+    if let Some(path) = config::config_file("lsp") {
+        // `filetype = command args...`; `filetype = none` disables
+        config::for_each_line(&path, |line| {
+            let (filetype, command) = line.split_once('=').ok_or("expected filetype = command")?;
+            let (filetype, command) = (filetype.trim(), command.trim());
+            if command.is_empty() || command == "none" {
+                servers.remove(filetype);
+            } else {
+                servers.insert(filetype.to_string(), command.to_string());
+            }
+            Ok(())
+        })?;
+    }
+    Ok(servers)
+}
+
+pub fn path_to_uri(path: &Path) -> String {
+    let mut uri = String::from("file://");
+    for b in path.to_string_lossy().bytes() {
+        if b.is_ascii_alphanumeric() || b"/-_.~".contains(&b) {
+            uri.push(b as char);
+        } else {
+            uri.push_str(&format!("%{b:02X}"));
+        }
+    }
+    uri
+}
+
+pub fn uri_to_path(uri: &str) -> Option<PathBuf> {
+    let encoded = uri.strip_prefix("file://")?.as_bytes();
+    let mut bytes = Vec::new();
+    let mut i = 0;
+    while i < encoded.len() {
+        let decoded = (encoded[i] == b'%')
+            .then(|| std::str::from_utf8(encoded.get(i + 1..i + 3)?).ok())
+            .flatten()
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match decoded {
+            Some(b) => {
+                bytes.push(b);
+                i += 3;
+            }
+            None => {
+                bytes.push(encoded[i]);
+                i += 1;
+            }
+        }
+    }
+    Some(PathBuf::from(String::from_utf8_lossy(&bytes).into_owned()))
+}
+
+// The protocol counts columns in UTF-16 code units; rows are indexed by byte.
+pub fn utf16_to_byte(line: &str, column: usize) -> usize {
+    let mut units = 0;
+    for (i, c) in line.char_indices() {
+        if units >= column {
+            return i;
+        }
+        units += c.len_utf16();
+    }
+    line.len()
+}
+
+pub fn byte_to_utf16(line: &str, byte: usize) -> usize {
+    line.char_indices()
+        .take_while(|(i, _)| *i < byte)
+        .map(|(_, c)| c.len_utf16())
+        .sum()
+}
+
+#[derive(Copy, Clone, PartialEq, PartialOrd)]
+pub enum Severity {
+    Error = 1,
+    Warning = 2,
+    Information = 3,
+    Hint = 4,
+}
+
+impl Severity {
+    pub fn sign(&self) -> (char, &'static str) {
+        match self {
+            Severity::Error => ('E', "diagnostic-error"),
+            Severity::Warning => ('W', "diagnostic-warning"),
+            Severity::Information => ('I', "diagnostic-info"),
+            Severity::Hint => ('H', "diagnostic-hint"),
+        }
+    }
+}
+
+// (line, UTF-16 column) as the server sent them
+pub type LspPosition = (usize, usize);
+
+pub struct Diagnostic {
+    pub start: LspPosition,
+    pub end: LspPosition,
+    pub severity: Severity,
+    pub message: String,
+}
+
+#[derive(Copy, Clone, PartialEq)]
+pub enum Request {
+    Initialize,
+    Hover,
+    Completion,
+    Definition,
+    Shutdown,
+}
+
+pub enum Message {
+    Hover(String),
+    Completion(Vec<CompletionItem>),
+    Definition(PathBuf, LspPosition),
+    Diagnostics(Vec<Diagnostic>),
+    Error(String),
+    Exited,
+}
+
+pub struct LspClient {
+    pub name: String,
+    child: Child,
+    stdin: ChildStdin,
+    incoming: Receiver<Json>,
+    next_id: u64,
+    pending: HashMap<u64, Request>,
+    initialized: bool,
+    queued: Vec<Json>,      // sent once the server has answered initialize
+    pub uri: String,
+    version: u64,
+}
+
+impl LspClient {
+    // Starts the server and opens the document; nothing waits for the
+    // server to come up.
+    pub fn start(command: &str, root: &Path, file: &Path, language_id: &str, text: String) -> io::Result<Self> {
+        let mut words = command.split_whitespace();
+        let program = words.next().ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "empty command"))?;
+        let mut child = Command::new(program)
+            .args(words)
+            .current_dir(root)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()?;
+        let stdin = child.stdin.take().unwrap();
+        let stdout = child.stdout.take().unwrap();
+        let (sender, incoming) = mpsc::channel();
+        thread::spawn(move || read_messages(stdout, sender));
+
+        let mut client = LspClient {
+            name: program.to_string(),
+            child,
+            stdin,
+            incoming,
+            next_id: 1,
+            pending: HashMap::new(),
+            initialized: false,
+            queued: Vec::new(),
+            uri: path_to_uri(file),
+            version: 1,
+        };
+        let capabilities = Json::object([
+            ("textDocument", Json::object([
+                ("synchronization", Json::object([("didSave", true.into())])),
+                ("hover", Json::object([("contentFormat", Json::Array(vec!["plaintext".into()]))])),
+                ("completion", Json::object([
+                    ("completionItem", Json::object([("snippetSupport", false.into())])),
+                ])),
+                ("definition", Json::object([])),
+                ("publishDiagnostics", Json::object([])),
+            ])),
+        ]);
+        client.request("initialize", Json::object([
+            ("processId", (std::process::id() as u64).into()),
+            ("rootUri", path_to_uri(root).into()),
+            ("capabilities", capabilities),
+        ]), Request::Initialize)?;
+        client.notify("textDocument/didOpen", Json::object([
+            ("textDocument", Json::object([
+                ("uri", client.uri.clone().into()),
+                ("languageId", language_id.into()),
+                ("version", client.version.into()),
+                ("text", text.into()),
+            ])),
+        ]))?;
+        Ok(client)
+    }
+
+    fn write(&mut self, message: &Json) -> io::Result<()> {
+        let body = message.to_string();
+        write!(self.stdin, "Content-Length: {}\r\n\r\n{body}", body.len())?;
+        self.stdin.flush()
+    }
+
+    fn send(&mut self, message: Json) -> io::Result<()> {
+        if self.initialized {
+            self.write(&message)
+        } else {
+            self.queued.push(message);
+            Ok(())
+        }
+    }
+
+    fn request(&mut self, method: &str, params: Json, kind: Request) -> io::Result<()> {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.pending.insert(id, kind);
+        let message = Json::object([
+            ("jsonrpc", "2.0".into()),
+            ("id", id.into()),
+            ("method", method.into()),
+            ("params", params),
+        ]);
+        if kind == Request::Initialize {
+            self.write(&message)
+        } else {
+            self.send(message)
+        }
+    }
+
+    fn notify(&mut self, method: &str, params: Json) -> io::Result<()> {
+        self.send(Json::object([
+            ("jsonrpc", "2.0".into()),
+            ("method", method.into()),
+            ("params", params),
+        ]))
+    }
+
+    fn document(&self) -> Json {
+        Json::object([("uri", self.uri.clone().into())])
+    }
+
+    // the whole text every time; incremental sync isn't worth it here
+    pub fn did_change(&mut self, text: String) -> io::Result<()> {
+        self.version += 1;
+        self.notify("textDocument/didChange", Json::object([
+            ("textDocument", Json::object([
+                ("uri", self.uri.clone().into()),
+                ("version", self.version.into()),
+            ])),
+            ("contentChanges", Json::Array(vec![Json::object([("text", text.into())])])),
+        ]))
+    }
+
+    pub fn did_save(&mut self) -> io::Result<()> {
+        let params = Json::object([("textDocument", self.document())]);
+        self.notify("textDocument/didSave", params)
+    }
+
+    pub fn ask(&mut self, kind: Request, position: LspPosition) -> io::Result<()> {
+        let method = match kind {
+            Request::Hover => "textDocument/hover",
+            Request::Completion => "textDocument/completion",
+            Request::Definition => "textDocument/definition",
+            _ => return Ok(()),
+        };
+        // only the latest question of each kind is worth answering
+        self.pending.retain(|_, pending| *pending != kind);
+        let params = Json::object([
+            ("textDocument", self.document()),
+            ("position", Json::object([("line", position.0.into()), ("character", position.1.into())])),
+        ]);
+        self.request(method, params, kind)
+    }
+
+    // Everything that arrived since the last call, answering the server's
+    // own requests along the way.
+    pub fn poll(&mut self) -> Vec<Message> {
+        let mut messages = Vec::new();
+        loop {
+            let message = match self.incoming.try_recv() {
+                Ok(message) => message,
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => {
+                    messages.push(Message::Exited);
+                    break;
+                }
+            };
+            let id = message.get("id");
+            let method = message.get("method").as_str();
+            match (method, id.as_u64()) {
+                (Some(method), _) if !id.is_null() => {
+                    // we support none of the server's requests, but they
+                    // need answers; configuration wants one per item
+                    let result = if method == "workspace/configuration" {
+                        Json::Array(vec![Json::Null; message.get("params").get("items").as_array().len()])
+                    } else {
+                        Json::Null
+                    };
+                    let reply = Json::object([("jsonrpc", "2.0".into()), ("id", id.clone()), ("result", result)]);
+                    let _ = self.write(&reply);
+                }
+                (Some("textDocument/publishDiagnostics"), _) => {
+                    let params = message.get("params");
+                    if params.get("uri").as_str() == Some(self.uri.as_str()) {
+                        messages.push(Message::Diagnostics(parse_diagnostics(params.get("diagnostics"))));
+                    }
+                }
+                (None, Some(id)) => {
+                    let Some(kind) = self.pending.remove(&id) else { continue };
+                    let error = message.get("error");
+                    if !error.is_null() {
+                        if kind != Request::Shutdown {
+                            let text = error.get("message").as_str().unwrap_or("request failed");
+                            messages.push(Message::Error(format!("{}: {text}", self.name)));
+                        }
+                        continue;
+                    }
+                    let result = message.get("result");
+                    match kind {
+                        Request::Initialize => {
+                            self.initialized = true;
+                            let _ = self.write(&Json::object([
+                                ("jsonrpc", "2.0".into()),
+                                ("method", "initialized".into()),
+                                ("params", Json::object([])),
+                            ]));
+                            for queued in std::mem::take(&mut self.queued) {
+                                let _ = self.write(&queued);
+                            }
+                        }
+                        Request::Hover => messages.push(Message::Hover(hover_text(result.get("contents")))),
+                        Request::Completion => messages.push(Message::Completion(parse_completions(result))),
+                        Request::Definition => {
+                            if let Some((path, position)) = parse_location(result) {
+                                messages.push(Message::Definition(path, position));
+                            }
+                        }
+                        Request::Shutdown => {}
+                    }
+                }
+                _ => {}
+            }
+        }
+        messages
+    }
+
+    // Asks the server to shut down, giving it a moment before killing it.
+    pub fn stop(&mut self) {
+        if self.initialized && self.request("shutdown", Json::Null, Request::Shutdown).is_ok() {
+            let deadline = Instant::now() + Duration::from_millis(300);
+            while self.pending.values().any(|kind| *kind == Request::Shutdown) && Instant::now() < deadline {
+                thread::sleep(Duration::from_millis(10));
+                self.poll();
+            }
+            let _ = self.notify("exit", Json::Null);
+        }
+        let deadline = Instant::now() + Duration::from_millis(200);
+        while matches!(self.child.try_wait(), Ok(None)) && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+fn read_messages(stdout: ChildStdout, sender: Sender<Json>) -> io::Result<()> {
+    let mut reader = BufReader::new(stdout);
+    loop {
+        let mut length = None;
+        loop {
+            let mut line = String::new();
+            if reader.read_line(&mut line)? == 0 {
+                return Ok(());
+            }
+            let line = line.trim_end();
+            if line.is_empty() {
+                break;
+            }
+            if let Some(value) = line.strip_prefix("Content-Length:") {
+                length = value.trim().parse::<usize>().ok();
+            }
+        }
+        let Some(length) = length else { continue };
+        let mut body = vec![0; length];
+        reader.read_exact(&mut body)?;
+        if let Ok(message) = Json::parse(&String::from_utf8_lossy(&body)) {
+            if sender.send(message).is_err() {
+                return Ok(());
+            }
+        }
+    }
+}
+
+fn parse_position(position: &Json) -> LspPosition {
+    let get = |key| position.get(key).as_u64().unwrap_or(0) as usize;
+    (get("line"), get("character"))
+}
+
+fn parse_diagnostics(diagnostics: &Json) -> Vec<Diagnostic> {
+    diagnostics.as_array().iter()
+        .map(|diagnostic| Diagnostic {
+            start: parse_position(diagnostic.get("range").get("start")),
+            end: parse_position(diagnostic.get("range").get("end")),
+            severity: match diagnostic.get("severity").as_u64() {
+                Some(2) => Severity::Warning,
+                Some(3) => Severity::Information,
+                Some(4) => Severity::Hint,
+                _ => Severity::Error,
+            },
+            message: diagnostic.get("message").as_str().unwrap_or("").to_string(),
+        })
+        .collect()
+}
+
+// MarkupContent, a MarkedString or a list of MarkedStrings
+fn hover_text(contents: &Json) -> String {
+    match contents {
+        Json::String(s) => s.clone(),
+        Json::Array(items) => items.iter().map(hover_text).collect::<Vec<_>>().join("\n"),
+        _ => contents.get("value").as_str().unwrap_or("").to_string(),
+    }
+}
+
+// either a list of items or a CompletionList
+fn parse_completions(result: &Json) -> Vec<CompletionItem> {
+    let items = match result {
+        Json::Array(items) => items.as_slice(),
+        _ => result.get("items").as_array(),
+    };
+    items.iter()
+        .filter_map(|item| {
+            let label = item.get("label").as_str()?.to_string();
+            let insert = item.get("textEdit").get("newText").as_str()
+                .or(item.get("insertText").as_str())
+                .unwrap_or(&label)
+                .to_string();
+            let detail = item.get("detail").as_str().unwrap_or("").to_string();
+            Some(CompletionItem { label, insert, detail })
+        })
+        .collect()
+}
+
+// Location, a list of them, or a list of LocationLinks; the first one wins
+fn parse_location(result: &Json) -> Option<(PathBuf, LspPosition)> {
+    let location = match result {
+        Json::Array(items) => items.first()?,
+        _ => result,
+    };
+    let (uri, range) = match location.get("targetUri").as_str() {
+        Some(uri) => (uri, location.get("targetSelectionRange")),
+        None => (location.get("uri").as_str()?, location.get("range")),
+    };
+    Some((uri_to_path(uri)?, parse_position(range.get("start"))))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use crate::test_util::TempDir;
+
+    #[test]
+    fn converts_utf16_columns() {
+        let line = "aé😀b";
+        // a: 1 unit, é: 1 unit and 2 bytes, 😀: 2 units and 4 bytes
+        assert_eq!(utf16_to_byte(line, 0), 0);
+        assert_eq!(utf16_to_byte(line, 2), 3);
+        assert_eq!(utf16_to_byte(line, 4), 7);
+        assert_eq!(utf16_to_byte(line, 99), line.len());
+        assert_eq!(byte_to_utf16(line, 3), 2);
+        assert_eq!(byte_to_utf16(line, 7), 4);
+        assert_eq!(byte_to_utf16(line, line.len()), 5);
+        for byte in [0, 1, 3, 7, 8] {
+            assert_eq!(utf16_to_byte(line, byte_to_utf16(line, byte)), byte);
+        }
+    }
+
+    #[test]
+    fn converts_uris() {
+        assert_eq!(uri_to_path("file:///tmp/a%20b/c%C3%A9.rs"), Some(PathBuf::from("/tmp/a b/cé.rs")));
+        // a stray % is kept as it is
+        assert_eq!(uri_to_path("file:///100%/x%zz"), Some(PathBuf::from("/100%/x%zz")));
+        assert_eq!(uri_to_path("https://example.com/x"), None);
+        let path = Path::new("/src/my file#1.c");
+        assert_eq!(path_to_uri(path), "file:///src/my%20file%231.c");
+        assert_eq!(uri_to_path(&path_to_uri(path)).as_deref(), Some(path));
+    }
+
+    // A language server in sh: it logs each method it's sent, answers
+    // initialize, hover and completion, and publishes one diagnostic per
+    // didOpen or didChange, named after the notification.
+    const STUB: &str = r#"
+log=$1
+send() { printf 'Content-Length: %d\r\n\r\n%s' "${#1}" "$1"; }
+field() { printf %s "$body" | sed -n "s/.*\"$1\":\"*\([^\",}]*\).*/\1/p"; }
+while :; do
+    len=
+    while IFS= read -r line; do
+        line=$(printf %s "$line" | tr -d '\r')
+        [ -z "$line" ] && break
+        case $line in Content-Length:*) len=${line#Content-Length: } ;; esac
+    done
+    [ -z "$len" ] && exit 0
+    body=$(dd bs=1 count="$len" 2>/dev/null)
+    method=$(field method)
+    id=$(field id)
+    echo "$method" >>"$log"
+    case $method in
+    initialize) send "{\"jsonrpc\":\"2.0\",\"id\":$id,\"result\":{\"capabilities\":{}}}" ;;
+    textDocument/didOpen|textDocument/didChange)
+        send "{\"jsonrpc\":\"2.0\",\"method\":\"textDocument/publishDiagnostics\",\"params\":{\"uri\":\"$(field uri)\",\"diagnostics\":[{\"range\":{\"start\":{\"line\":0,\"character\":4},\"end\":{\"line\":0,\"character\":7}},\"severity\":2,\"message\":\"${method#textDocument/}\"}]}}" ;;
+    textDocument/hover)
+        send "{\"jsonrpc\":\"2.0\",\"id\":$id,\"result\":{\"contents\":{\"kind\":\"plaintext\",\"value\":\"int foo(void)\"}}}" ;;
+    textDocument/completion)
+        send "{\"jsonrpc\":\"2.0\",\"id\":$id,\"result\":{\"isIncomplete\":false,\"items\":[{\"label\":\"foo\",\"detail\":\"int\"},{\"label\":\"bar\",\"insertText\":\"bar()\"}]}}" ;;
+    shutdown) send "{\"jsonrpc\":\"2.0\",\"id\":$id,\"result\":null}" ;;
+    exit) exit 0 ;;
+    esac
+done
+"#;
+
+    fn poll_until(client: &mut LspClient, mut done: impl FnMut(&Message) -> bool) -> Message {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while Instant::now() < deadline {
+            if let Some(message) = client.poll().into_iter().find(|message| done(message)) {
+                return message;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        panic!("no reply from the stub server");
+    }
+
+    #[test]
+    fn talks_to_a_server() {
+        let dir = TempDir::new("lsp");
+        let dir = dir.path();
+        let (stub, log) = (dir.join("stub.sh"), dir.join("log"));
+        fs::write(&stub, STUB).unwrap();
+        let command = format!("sh {} {}", stub.display(), log.display());
+        let file = dir.join("main.c");
+        let mut client = LspClient::start(&command, dir, &file, "c", String::from("int foo;\n")).unwrap();
+
+        // nothing but initialize goes out until the server has answered it
+        client.did_change(String::from("int foo(void);\n")).unwrap();
+        assert!(!client.initialized);
+        assert_eq!(client.queued.len(), 2);
+
+        let diagnostics = poll_until(&mut client, |message| match message {
+            Message::Diagnostics(diagnostics) => diagnostics[0].message == "didChange",
+            _ => false,
+        });
+        let Message::Diagnostics(diagnostics) = diagnostics else { unreachable!() };
+        assert_eq!((diagnostics[0].start, diagnostics[0].end), ((0, 4), (0, 7)));
+        assert!(diagnostics[0].severity == Severity::Warning);
+        assert!(client.queued.is_empty());
+
+        client.ask(Request::Hover, (0, 5)).unwrap();
+        let Message::Hover(text) = poll_until(&mut client, |message| matches!(message, Message::Hover(_))) else {
+            unreachable!()
+        };
+        assert_eq!(text, "int foo(void)");
+
+        client.ask(Request::Completion, (0, 5)).unwrap();
+        let Message::Completion(items) = poll_until(&mut client, |message| matches!(message, Message::Completion(_)))
+        else {
+            unreachable!()
+        };
+        let items: Vec<_> = items.iter()
+            .map(|item| (item.label.as_str(), item.insert.as_str(), item.detail.as_str()))
+            .collect();
+        assert_eq!(items, [("foo", "foo", "int"), ("bar", "bar()", "")]);
+
+        client.stop();
+        let methods = fs::read_to_string(&log).unwrap();
+        assert_eq!(methods.lines().collect::<Vec<_>>(), [
+            "initialize", "initialized", "textDocument/didOpen", "textDocument/didChange",
+            "textDocument/hover", "textDocument/completion", "shutdown", "exit",
+        ]);
+    }
+}
@@ -0,0 +1,99 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+use crossterm::event::KeyEvent;
+
+use kilo_ed::StdResult;
+use crate::config;
+use crate::keymap::{chord_to_string, parse_chord, Key};
+
+// Keyboard macros, saved as `name = key key key` lines using the keymap's
+// key syntax, which has a name for every key a terminal can send.
+pub struct Macros {
+    pub last: Vec<KeyEvent>,
+    named: BTreeMap<String, Vec<KeyEvent>>,
+}
+
+impl Macros {
+    pub fn new() -> Self {
+        Self {
+            last: Vec::new(),
+            named: BTreeMap::new(),
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Vec<KeyEvent>> {
+        self.named.get(name)
+    }
+
+    pub fn insert(&mut self, name: String, keys: Vec<KeyEvent>) {
+        self.named.insert(name, keys);
+    }
+
+    pub fn load(&mut self, path: &Path) -> StdResult<(), String> {
+        config::for_each_line(path, |line| {
+            let (name, keys) = line.split_once('=').ok_or("expected 'name = keys'")?;
+            let keys = parse_chord(keys)?
+                .into_iter()
+                .map(|key| KeyEvent::new(key.code, key.modifiers))
+                .collect();
+            self.named.insert(name.trim().to_string(), keys);
+            Ok(())
+        })
+    }
+
+    pub fn save(&self, path: &Path) -> std::io::Result<()> {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let mut buf = String::new();
+        for (name, keys) in &self.named {
+            let keys: Vec<Key> = keys.iter().map(|key| Key::from(*key)).collect();
+            buf.push_str(&format!("{name} = {}\n", chord_to_string(&keys)));
+        }
+        std::fs::write(path, buf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crossterm::event::{KeyCode, KeyModifiers, MediaKeyCode, ModifierKeyCode};
+    use crate::test_util::TempDir;
+
+    #[test]
+    fn saves_every_key() {
+        let keys: Vec<KeyEvent> = [
+            (KeyCode::Char('a'), KeyModifiers::NONE),
+            (KeyCode::Char('A'), KeyModifiers::SHIFT),
+            (KeyCode::Char('s'), KeyModifiers::CONTROL),
+            (KeyCode::Char('='), KeyModifiers::ALT),
+            (KeyCode::Char('-'), KeyModifiers::NONE),
+            (KeyCode::Char('#'), KeyModifiers::NONE),
+            (KeyCode::Char(' '), KeyModifiers::CONTROL),
+            (KeyCode::Char('\u{a0}'), KeyModifiers::NONE),
+            (KeyCode::Char('é'), KeyModifiers::NONE),
+            (KeyCode::F(24), KeyModifiers::NONE),
+            (KeyCode::Up, KeyModifiers::SHIFT),
+            (KeyCode::CapsLock, KeyModifiers::NONE),
+            (KeyCode::NumLock, KeyModifiers::NONE),
+            (KeyCode::Null, KeyModifiers::NONE),
+            (KeyCode::Media(MediaKeyCode::Play), KeyModifiers::NONE),
+            (KeyCode::Media(MediaKeyCode::RaiseVolume), KeyModifiers::NONE),
+            (KeyCode::Modifier(ModifierKeyCode::LeftShift), KeyModifiers::SHIFT),
+            (KeyCode::Esc, KeyModifiers::SUPER | KeyModifiers::HYPER),
+        ].into_iter().map(|(code, modifiers)| KeyEvent::new(code, modifiers)).collect();
+
+        let dir = TempDir::new("macros");
+        let path = dir.path().join("macros");
+        let mut macros = Macros::new();
+        macros.insert("all".to_string(), keys.clone());
+        macros.save(&path).unwrap();
+        let mut loaded = Macros::new();
+        loaded.load(&path).unwrap();
+
+        // what the editor sees: a character's case already carries shift
+        let saved: Vec<Key> = keys.into_iter().map(Key::from).collect();
+        let loaded: Vec<Key> = loaded.get("all").unwrap().iter().map(|key| Key::from(*key)).collect();
+        assert_eq!(loaded, saved);
+    }
+}
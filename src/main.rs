@@ -6,15 +6,43 @@ mod keyboard;
 mod screen;
 mod row;
 mod editor_syntax;
+mod keymap;
+mod config;
+mod vi;
+mod macros;
+mod palette;
+mod fileio;
+mod swap;
+mod diff;
+mod fileformat;
+mod theme;
+mod git;
+mod statusline;
+mod json;
+mod lsp;
+mod completion;
+mod treesitter;
+mod spell;
+mod snippet;
+mod fold;
+mod marks;
+mod history;
+mod session;
+#[cfg(test)]
+mod test_util;
 
 use crate::editor::Editor;
 
 fn main() -> Result<()> {
-    let mut args = std::env::args();
-    let mut editor = if args.len() >= 2 {
-        Editor::with_file(args.nth(1).unwrap())?
-    } else {
-        Editor::new()?
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let mut editor = match args.as_slice() {
+        [flag, name] if flag == "--session" => Editor::with_session(name)?,
+        [flag, ..] if flag == "--session" => {
+            eprintln!("usage: kilo-ed [FILE | --session NAME]");
+            std::process::exit(1);
+        }
+        [filename, ..] => Editor::with_file(filename.clone())?,
+        [] => Editor::new()?,
     };
     editor.start()?;
     Ok(())
@@ -0,0 +1,195 @@
+use std::collections::BTreeMap;
+
+use kilo_ed::Position;
+
+// Named marks and the jump list: buffer positions that follow their text as
+// rows are inserted, removed, split and joined.
+
+const MAX_JUMPS: usize = 100;
+
+fn row_inserted(pos: &mut Position, at: usize) {
+    if pos.y as usize >= at {
+        pos.y += 1;
+    }
+}
+
+// A position on a removed row moves to the start of the row that takes its
+// place.
+fn row_removed(pos: &mut Position, at: usize) {
+    if pos.y as usize == at {
+        pos.x = 0;
+    } else if pos.y as usize > at {
+        pos.y -= 1;
+    }
+}
+
+// Row `at` was split at column `x`, after the new row was inserted below
+// it: what followed x moved to the start of the new row.
+fn row_split(pos: &mut Position, at: usize, x: u16) {
+    if pos.y as usize == at && pos.x >= x {
+        pos.y += 1;
+        pos.x -= x;
+    }
+}
+
+// Row `at` is about to be removed, its text from column `from` on appended
+// to the row above, which is `len` long. Positions on the part left behind
+// go to where the two meet.
+fn row_joined(pos: &mut Position, at: usize, len: u16, from: u16) {
+    if pos.y as usize == at {
+        pos.y -= 1;
+        pos.x = len + pos.x.saturating_sub(from);
+    }
+}
+
+#[derive(Default)]
+pub struct Marks {
+    named: BTreeMap<String, Position>,
+}
+
+impl Marks {
+    pub fn set(&mut self, name: &str, pos: Position) {
+        self.named.insert(name.to_string(), pos);
+    }
+
+    pub fn get(&self, name: &str) -> Option<Position> {
+        self.named.get(name).copied()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &Position)> {
+        self.named.iter()
+    }
+
+    pub fn clear(&mut self) {
+        self.named.clear();
+    }
+
+    pub fn row_inserted(&mut self, at: usize) {
+        self.named.values_mut().for_each(|pos| row_inserted(pos, at));
+    }
+
+    pub fn row_removed(&mut self, at: usize) {
+        self.named.values_mut().for_each(|pos| row_removed(pos, at));
+    }
+
+    pub fn row_split(&mut self, at: usize, x: u16) {
+        self.named.values_mut().for_each(|pos| row_split(pos, at, x));
+    }
+
+    pub fn row_joined(&mut self, at: usize, len: u16, from: u16) {
+        self.named.values_mut().for_each(|pos| row_joined(pos, at, len, from));
+    }
+}
+
+// Positions left by big moves, oldest first. `current` is where back and
+// forward have got to; it is past the end until back is used.
+#[derive(Default)]
+pub struct JumpList {
+    jumps: Vec<Position>,
+    current: usize,
+}
+
+impl JumpList {
+    // Records a position about to be left, dropping anything forward was
+    // still able to return to.
+    pub fn push(&mut self, pos: Position) {
+        self.jumps.truncate(self.current);
+        if self.jumps.last() != Some(&pos) {
+            self.jumps.push(pos);
+        }
+        if self.jumps.len() > MAX_JUMPS {
+            self.jumps.remove(0);
+        }
+        self.current = self.jumps.len();
+    }
+
+    // the position before the current one; `here` is kept so forward can
+    // come back to it
+    pub fn back(&mut self, here: Position) -> Option<Position> {
+        if self.current == self.jumps.len() {
+            self.push(here);
+            self.current = self.jumps.len() - 1;
+        }
+        if self.current == 0 {
+            return None;
+        }
+        self.current -= 1;
+        Some(self.jumps[self.current])
+    }
+
+    pub fn forward(&mut self) -> Option<Position> {
+        if self.current + 1 >= self.jumps.len() {
+            return None;
+        }
+        self.current += 1;
+        Some(self.jumps[self.current])
+    }
+
+    pub fn clear(&mut self) {
+        self.jumps.clear();
+        self.current = 0;
+    }
+
+    pub fn row_inserted(&mut self, at: usize) {
+        self.jumps.iter_mut().for_each(|pos| row_inserted(pos, at));
+    }
+
+    pub fn row_removed(&mut self, at: usize) {
+        self.jumps.iter_mut().for_each(|pos| row_removed(pos, at));
+    }
+
+    pub fn row_split(&mut self, at: usize, x: u16) {
+        self.jumps.iter_mut().for_each(|pos| row_split(pos, at, x));
+    }
+
+    pub fn row_joined(&mut self, at: usize, len: u16, from: u16) {
+        self.jumps.iter_mut().for_each(|pos| row_joined(pos, at, len, from));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(y: u16, x: u16) -> Position {
+        Position { x, y }
+    }
+
+    #[test]
+    fn marks_follow_split_rows() {
+        let mut marks = Marks::default();
+        marks.set("before", at(2, 1));
+        marks.set("after", at(2, 7));
+        marks.set("below", at(3, 4));
+        // Enter at (2, 5): the new row 3 is inserted, then row 2 is split
+        marks.row_inserted(3);
+        marks.row_split(2, 5);
+        assert!(marks.get("before") == Some(at(2, 1)));
+        assert!(marks.get("after") == Some(at(3, 2)));
+        assert!(marks.get("below") == Some(at(4, 4)));
+    }
+
+    #[test]
+    fn marks_follow_joined_rows() {
+        let mut marks = Marks::default();
+        marks.set("joined", at(2, 3));
+        marks.set("below", at(3, 4));
+        // Backspace at (2, 0) with row 1 ten long
+        marks.row_joined(2, 10, 0);
+        marks.row_removed(2);
+        assert!(marks.get("joined") == Some(at(1, 13)));
+        assert!(marks.get("below") == Some(at(2, 4)));
+    }
+
+    #[test]
+    fn jumps_follow_a_range_deleted_across_rows() {
+        let mut jumps = JumpList::default();
+        jumps.push(at(2, 3));   // deleted
+        jumps.push(at(2, 8));   // kept, after the end of the range
+        // delete from (1, 4) to (2, 5): row 2's text from 5 on joins row 1
+        jumps.row_joined(2, 4, 5);
+        jumps.row_removed(2);
+        assert!(jumps.back(at(0, 0)) == Some(at(1, 7)));
+        assert!(jumps.back(at(0, 0)) == Some(at(1, 4)));
+    }
+}
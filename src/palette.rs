@@ -0,0 +1,134 @@
+use std::path::Path;
+use crate::keymap::Command;
+
+// What the word after a command name is, for completion.
+#[derive(Copy, Clone, PartialEq)]
+pub enum Arg {
+    None,
+    File,
+    Setting,
+    Number,
+    Syntax,
+    Mark,
+    Session,
+}
+
+pub struct Entry {
+    pub name: &'static str,
+    pub arg: Arg,
+    pub help: &'static str,
+}
+
+const ENTRIES: &[Entry] = &[
+    Entry { name: "open", arg: Arg::File, help: "open FILE in place of the current buffer" },
+    Entry { name: "saveas", arg: Arg::File, help: "save the buffer under a new name" },
+    Entry { name: "set", arg: Arg::Setting, help: "set NAME=VALUE" },
+    Entry { name: "goto", arg: Arg::Number, help: "go to line N" },
+    Entry { name: "syntax", arg: Arg::Syntax, help: "highlight as FILETYPE (or none)" },
+    Entry { name: "foldlevel", arg: Arg::Number, help: "fold everything nested N deep" },
+    Entry { name: "mark", arg: Arg::Mark, help: "set mark NAME at the cursor" },
+    Entry { name: "jump", arg: Arg::Mark, help: "go to mark NAME" },
+    Entry { name: "session", arg: Arg::Session, help: "save the session as NAME" },
+];
+
+// every name the command line accepts: the commands above and everything
+// that can be bound in the keymap
+pub fn command_names() -> Vec<&'static str> {
+    ENTRIES.iter()
+        .map(|entry| entry.name)
+        .chain(Command::all().map(|command| command.name()))
+        .collect()
+}
+
+pub fn help(name: &str) -> &'static str {
+    ENTRIES.iter()
+        .find(|entry| entry.name == name)
+        .map_or("", |entry| entry.help)
+}
+
+pub fn arg_for(name: &str) -> Arg {
+    ENTRIES.iter()
+        .find(|entry| entry.name == name)
+        .map_or(Arg::None, |entry| entry.arg)
+}
+
+// Subsequence match: every character of the pattern must appear in order.
+// Consecutive characters and matches at the start or after a '-' score higher.
+pub fn fuzzy_score(pattern: &str, candidate: &str) -> Option<i32> {
+    let mut score = 0;
+    let mut last_match: Option<usize> = None;
+    let mut chars = candidate.char_indices();
+    let mut prev = None;
+
+    for p in pattern.chars() {
+        loop {
+            let (i, c) = chars.next()?;
+            let at_boundary = i == 0 || prev == Some('-');
+            prev = Some(c);
+            if c.eq_ignore_ascii_case(&p) {
+                score += 1;
+                if at_boundary {
+                    score += 3;
+                }
+                if last_match.is_some_and(|last| last + 1 == i) {
+                    score += 2;
+                }
+                last_match = Some(i);
+                break;
+            }
+        }
+    }
+    // shorter names win ties
+    Some(score * 100 - candidate.len() as i32)
+}
+
+pub fn fuzzy_filter<'a>(pattern: &str, candidates: &[&'a str]) -> Vec<&'a str> {
+    let mut scored: Vec<(i32, &str)> = candidates.iter()
+        .filter_map(|candidate| fuzzy_score(pattern, candidate).map(|score| (score, *candidate)))
+        .collect();
+    scored.sort_by(|a, b| b.0.cmp(&a.0).then(a.1.cmp(b.1)));
+    scored.into_iter().map(|(_, candidate)| candidate).collect()
+}
+
+// Paths starting with `partial`; directories end in '/'. Hidden files are
+// only offered once the name being completed starts with '.'.
+pub fn complete_path(partial: &str) -> Vec<String> {
+    let (dir, prefix) = match partial.rfind('/') {
+        Some(i) => (&partial[..=i], &partial[i + 1..]),
+        None => ("", partial),
+    };
+    let read_from = if dir.is_empty() { Path::new(".") } else { Path::new(dir) };
+    let Ok(entries) = std::fs::read_dir(read_from) else {
+        return Vec::new();
+    };
+
+    let mut result: Vec<String> = entries
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| {
+            let name = entry.file_name().into_string().ok()?;
+            if !name.starts_with(prefix) || (name.starts_with('.') && !prefix.starts_with('.')) {
+                return None;
+            }
+            let is_dir = entry.file_type().is_ok_and(|t| t.is_dir());
+            Some(format!("{dir}{name}{}", if is_dir { "/" } else { "" }))
+        })
+        .collect();
+    result.sort();
+    result
+}
+
+pub fn common_prefix(items: &[String]) -> String {
+    let Some(first) = items.first() else {
+        return String::new();
+    };
+    let mut len = first.len();
+    for item in &items[1..] {
+        len = first.char_indices()
+            .zip(item.chars())
+            .take_while(|((_, a), b)| a == b)
+            .last()
+            .map_or(0, |((i, a), _)| i + a.len_utf8())
+            .min(len);
+    }
+    first[..len].to_string()
+}
@@ -1,12 +1,21 @@
-use std::ops::Index;
 use std::slice::Iter;
-use crossterm::style::Color;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use crate::editor_syntax::*;
-use crate::editor_syntax::Keyword::Basic;
 use crate::row::Highlight::{Keyword1, Keyword2};
 
 const KILO_TAB_STOP : usize =  8;
 
+static TAB_STOP: AtomicUsize = AtomicUsize::new(KILO_TAB_STOP);
+
+// rows have to be rendered again after changing this
+pub fn set_tab_stop(tab_stop: usize) {
+    TAB_STOP.store(tab_stop.max(1), Ordering::Relaxed);
+}
+
+fn tab_stop() -> usize {
+    TAB_STOP.load(Ordering::Relaxed)
+}
+
 
 #[derive(Copy, Clone, PartialEq)]
 pub enum Highlight {
@@ -20,17 +29,18 @@ pub enum Highlight {
 }
 
 impl  Highlight {
-    pub fn syntax_to_color(&self) -> Color {
+    // the theme element this is drawn with
+    pub fn name(&self) -> &'static str {
         match  self {
-            Highlight::Normal => Color::White,
-            Highlight::Number => Color::Red,
-            Highlight::String => Color::Magenta,
-            Highlight::Comment => Color::Cyan,
+            Highlight::Normal => "normal",
+            Highlight::Number => "number",
+            Highlight::String => "string",
+            Highlight::Comment => "comment",
 
-            Highlight::Keyword1 => Color::Yellow,
-            Highlight::Keyword2 => Color::Green,
+            Highlight::Keyword1 => "keyword1",
+            Highlight::Keyword2 => "keyword2",
 
-            Highlight::Match => Color::Blue,
+            Highlight::Match => "match",
         }
     }
 }
@@ -57,15 +67,18 @@ impl Row {
     pub fn len(&self) -> usize {
         self.chars.len()
     }
+    // in columns, one per char
     pub fn render_len(&self) -> usize {
-        self.render.len()
+        self.hl.len()
     }
 
+    // Columns in the text (cx) are byte offsets into chars; columns on
+    // screen (rx) count chars of render.
     pub fn cx_to_rx(&self, cx: u16) -> u16 {
         let mut rx = 0;
-        for c in self.chars.chars().take(cx as usize) {
+        for (_, c) in self.chars.char_indices().take_while(|(i, _)| *i < cx as usize) {
             if c == '\t' {
-                rx += (KILO_TAB_STOP -1) - (rx % KILO_TAB_STOP);
+                rx += (tab_stop() -1) - (rx % tab_stop());
             }
             rx += 1;
         }
@@ -75,9 +88,9 @@ impl Row {
     pub fn rx_to_cx(&self, rx: usize) -> u16 {
         let mut cur_rx = 0;
 
-        for (cx, c) in self.chars.chars().enumerate() {
+        for (cx, c) in self.chars.char_indices() {
             if c == '\t' {
-                cur_rx += (KILO_TAB_STOP - 1) - (cur_rx % KILO_TAB_STOP);
+                cur_rx += (tab_stop() - 1) - (cur_rx % tab_stop());
             }
             cur_rx += 1;
             if cur_rx > rx {
@@ -87,12 +100,28 @@ impl Row {
         self.chars.len() as u16
     }
 
+    // the start of the char that byte x falls in
+    pub fn floor_boundary(&self, x: usize) -> usize {
+        let x = x.min(self.chars.len());
+        (0..=x).rev().find(|i| self.chars.is_char_boundary(*i)).unwrap_or(0)
+    }
+
+    // the start of the char before x, and of the one after it
+    pub fn prev_boundary(&self, x: usize) -> usize {
+        self.floor_boundary(x.saturating_sub(1))
+    }
+
+    pub fn next_boundary(&self, x: usize) -> usize {
+        let x = self.floor_boundary(x);
+        x + self.chars[x..].chars().next().map_or(0, char::len_utf8)
+    }
+
 
     pub fn insert_char(&mut self, at: usize, c: char, syntax: Option<&EditorSyntax>) {
         if at >= self.chars.len()   {
             self.chars.push(c)
         } else {
-            self.chars.insert(at, c);
+            self.chars.insert(self.floor_boundary(at), c);
         }
         self.render_row(syntax);
     }
@@ -101,13 +130,13 @@ impl Row {
         if at >=  self.chars.len() {
            return false;
         }
-        self.chars.remove(at);
+        self.chars.remove(self.floor_boundary(at));
         self.render_row(syntax);
         true
     }
 
     pub fn split(&mut self, at: usize, syntax:  Option<&EditorSyntax>) -> String {
-        let result = self.chars.split_off(at);
+        let result = self.chars.split_off(self.floor_boundary(at));
         self.render_row(syntax);
         result
     }
@@ -117,6 +146,19 @@ impl Row {
         self.render_row(syntax);
     }
 
+    pub fn insert_str(&mut self, at: usize, s: &str, syntax: Option<&EditorSyntax>) {
+        self.chars.insert_str(self.floor_boundary(at), s);
+        self.render_row(syntax);
+    }
+
+    pub fn delete_str(&mut self, start: usize, end: usize, syntax: Option<&EditorSyntax>) -> String {
+        let end = self.floor_boundary(end);
+        let start = self.floor_boundary(start.min(end));
+        let result = self.chars.drain(start..end).collect();
+        self.render_row(syntax);
+        result
+    }
+
     pub fn render_row(&mut self, syntax: Option<&EditorSyntax>) {
         let mut render = String::new();
         let mut idx = 0;
@@ -125,7 +167,7 @@ impl Row {
                 '\t' => {
                     render.push(' ');
                     idx += 1;
-                    while idx % KILO_TAB_STOP != 0 {
+                    while idx % tab_stop() != 0 {
                         render.push(' ');
                         idx += 1;
                     }
@@ -141,15 +183,17 @@ impl Row {
     }
 
     pub fn update_syntax(&mut self, syntax: Option<&EditorSyntax>) {
-        self.hl = vec![Highlight::Normal; self.render.len()];
+        let render: Vec<char> = self.render.chars().collect();
+        self.hl = vec![Highlight::Normal; render.len()];
         let syntax = if let Some(syntax) = syntax {
             syntax
         } else {
             return
         };
+        let starts_with = |i: usize, s: &str| s.chars().enumerate().all(|(k, c)| render.get(i + k) == Some(&c));
 
         let mut prev_sep = true;
-        let mut row_iter = self.render.chars().enumerate();
+        let mut row_iter = render.iter().copied().enumerate();
         let mut in_string: Option<char> = None;
         let scs = &syntax.singleline_comment_start;
 
@@ -163,9 +207,8 @@ impl Row {
 
             if in_string.is_none() && scs.is_some() {
                 if let Some(scs) = scs {
-                    let len = scs.len();
-                    if self.render.len() >= i+len && &self.render[i..i + len] == scs {
-                        for j in i..self.render.len() {
+                    if starts_with(i, scs) {
+                        for j in i..render.len() {
                             self.hl[j] = Highlight::Comment;
                         }
                         break;
@@ -176,7 +219,7 @@ impl Row {
             if syntax.flags & highlightflags::STRINGS != 0 {
                 if let Some(_in_string) = in_string {
                     self.hl[i] = Highlight::String;
-                    if c == '\\' && i + 1 < self.render.len() {
+                    if c == '\\' && i + 1 < render.len() {
                         self.hl[i + 1] = Highlight::String;
                         row_iter.nth(1);
                         continue;
@@ -208,20 +251,20 @@ impl Row {
                        Keyword::Basic(keyword) =>  (keyword, true),
                        Keyword::Type(keyword) => (keyword, false)
                    };
-                   let end_with_sep = if let Some(char) = self.render.chars().nth(i+ keyword.len())  {
+                   let keyword_len = keyword.chars().count();
+                   let end_with_sep = if let Some(char) = render.get(i + keyword_len)  {
                        char.is_separator()
                    } else  {
                        true
                    };
 
 
-                    if self.render.len()-i >= keyword.len() && &self.render[i..i + keyword.len()] == keyword &&
-                        end_with_sep
+                    if starts_with(i, keyword) && end_with_sep
                         {
-                        for j in i..self.chars.len() {
+                        for j in i..render.len() {
                             self.hl[j] = if is_basic { Keyword1 } else { Keyword2 };
                         }
-                        row_iter.nth(keyword.len());          //Skip keyword
+                        row_iter.nth(keyword_len);          //Skip keyword
                         prev_sep = false;
                         continue 'outer;
 
@@ -240,9 +283,24 @@ impl Row {
         }
     }
 
+    pub fn clear_highlight(&mut self) {
+        self.hl = vec![Highlight::Normal; self.render.chars().count()];
+    }
+
+    // Highlights chars[start..end], by byte offset, for backends that work
+    // on the text rather than the rendered row.
+    pub fn set_highlight(&mut self, start: usize, end: usize, hl: Highlight) {
+        let to_rx = |byte: usize| (self.cx_to_rx(byte.min(self.chars.len()) as u16) as usize).min(self.hl.len());
+        let (start, end) = (to_rx(start), to_rx(end));
+        if start < end {
+            self.hl[start..end].fill(hl);
+        }
+    }
+
     pub fn highlight_match(&mut self, start: usize, len: usize ) {
         self.saved_hl = self.hl.clone();
-        for c in self.hl[start..start+len].iter_mut() {
+        let end = (start + len).min(self.hl.len());
+        for c in self.hl[start.min(end)..end].iter_mut() {
             *c = Highlight::Match;
         }
     }
@@ -252,8 +310,9 @@ impl Row {
         self.saved_hl.clear();
     }
 
-    pub fn iter_highlight(&self, start: usize, end: usize) ->  Iter<Highlight>  {
-        self.hl[start..end].iter()
+    pub fn iter_highlight(&self, start: usize, end: usize) ->  Iter<'_, Highlight>  {
+        let end = end.min(self.hl.len());
+        self.hl[start.min(end)..end].iter()
     }
 }
 
@@ -267,4 +326,64 @@ impl Separator for char {
         matches!(self, ' ' | ',' | '.' | '(' | ')' | '+' | '-' | '/' | '*' | '=' | '~' |
           '%' | '<' | '>' | '[' | ']' | '{' | '}' | ';')
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn converts_between_bytes_and_columns() {
+        let row = Row::new(String::from("é\tb"), None);
+        // é is two bytes and one column; the tab reaches column 8
+        assert_eq!(row.render_len(), 9);
+        assert_eq!(row.cx_to_rx(2), 1);
+        assert_eq!(row.cx_to_rx(3), 8);
+        assert_eq!(row.rx_to_cx(0), 0);
+        assert_eq!(row.rx_to_cx(1), 2);
+        assert_eq!(row.rx_to_cx(5), 2);
+        assert_eq!(row.rx_to_cx(8), 3);
+        assert_eq!(row.rx_to_cx(20), 4);
+    }
+
+    #[test]
+    fn steps_over_whole_chars() {
+        let row = Row::new(String::from("a€b"), None);
+        assert_eq!(row.next_boundary(1), 4);
+        assert_eq!(row.next_boundary(2), 4);
+        assert_eq!(row.prev_boundary(4), 1);
+        assert_eq!(row.floor_boundary(3), 1);
+        assert_eq!(row.floor_boundary(9), 5);
+        assert_eq!(row.next_boundary(5), 5);
+        assert_eq!(row.prev_boundary(0), 0);
+    }
+
+    #[test]
+    fn edits_at_char_boundaries() {
+        let mut row = Row::new(String::from("café"), None);
+        row.insert_char(4, '!', None);     // inside é: goes before it
+        assert_eq!(row.chars, "caf!é");
+        assert!(row.del_char(5, None));     // inside é again
+        assert_eq!(row.chars, "caf!");
+        row.insert_str(3, "ñ", None);
+        assert_eq!(row.split(4, None), "ñ!");   // inside ñ
+        row.append_string("ñ", None);
+        assert_eq!(row.delete_str(2, 4, None), "f");
+        assert_eq!(row.chars, "cañ");
+    }
+
+    #[test]
+    fn highlights_rows_with_non_ascii_text() {
+        let syntax = EditorSyntax {
+            filetype: String::from("c"),
+            filematch: vec![String::from("c")],
+            keywords: vec![Keyword::Basic(String::from("if")), Keyword::Type(String::from("int"))],
+            singleline_comment_start: Some(String::from("//")),
+            flags: highlightflags::NUMBERS | highlightflags::STRINGS,
+        };
+        let row = Row::new(String::from("\"é\" 1; // ü"), Some(&syntax));
+        assert_eq!(row.hl.len(), row.render.chars().count());
+        assert!(row.hl[..3] == [Highlight::String; 3]);
+        assert!(row.hl[4] == Highlight::Number);
+        assert!(row.hl[7..] == [Highlight::Comment; 4]);
+    }
+}
@@ -1,50 +1,190 @@
 use std::io::{stdout, Stdout, Write};
 use std::io::Result;
-use crossterm::{cursor, style, terminal, QueueableCommand};
-use crossterm::style::{Color, Colors, Print, ResetColor, SetAttribute, SetAttributes, SetForegroundColor};
-use crossterm::style::Attribute::{Reset, Reverse};
+use crossterm::{cursor, event, terminal, QueueableCommand};
+use crossterm::style::{Print, SetAttribute, SetBackgroundColor, SetForegroundColor};
+use crossterm::style::Attribute::{Bold, Italic, Reset, Reverse, Underlined};
 use kilo_ed::*;
 use crate::row::*;
+use crate::theme::{ColorDepth, Style, Theme};
+use crate::fold::Folds;
 
+const POPUP_MAX_ITEMS: usize = 10;
+
+// A list drawn over the text, e.g. command line or completion candidates.
+// x and y are the screen cell of its top left corner.
+pub struct Popup {
+    pub items: Vec<(String, String)>,    // (value, detail)
+    pub selected: Option<usize>,
+    pub x: u16,
+    pub y: u16,
+}
+
+impl Popup {
+    pub fn height(&self) -> u16 {
+        self.items.len().min(POPUP_MAX_ITEMS) as u16
+    }
+
+    pub fn selected_value(&self) -> Option<&str> {
+        self.selected
+            .and_then(|i| self.items.get(i))
+            .map(|(value, _)| value.as_str())
+    }
+
+    pub fn select_next(&mut self) {
+        if !self.items.is_empty() {
+            self.selected = Some(self.selected.map_or(0, |i| (i + 1) % self.items.len()));
+        }
+    }
+
+    pub fn select_previous(&mut self) {
+        if !self.items.is_empty() {
+            let last = self.items.len() - 1;
+            self.selected = Some(self.selected.map_or(last, |i| if i == 0 { last } else { i - 1 }));
+        }
+    }
+}
+
+// A mark in the gutter: a character drawn in a theme element's style.
+#[derive(Copy, Clone)]
+pub struct Sign {
+    pub ch: char,
+    pub element: &'static str,
+}
+
+// A range of buffer text, [start, end), drawn with a theme element on top
+// of its highlighting.
+pub struct Mark {
+    pub start: Position,
+    pub end: Position,
+    pub element: &'static str,
+}
+
+#[derive(Copy, Clone, PartialEq)]
+struct Cell {
+    ch: char,
+    style: Style,
+}
+
+impl Default for Cell {
+    fn default() -> Self {
+        Self { ch: ' ', style: Style::default() }
+    }
+}
+
+// Frames are drawn into `back`; flush sends only the cells that differ
+// from `front`, which mirrors what the terminal shows. An empty `front`
+// means the terminal contents are unknown and everything is redrawn.
 pub struct Screen {
     stdout: Stdout,
     width: u16,
     height: u16,
+    gutter: u16,                       // columns left of the text
+    back: Vec<Cell>,
+    front: Vec<Cell>,
+    cursor: (u16, u16),
+    shown_cursor: Option<(u16, u16)>,
+    theme: Theme,
+    depth: ColorDepth,
 }
 
 impl Screen {
     pub fn new() -> Result<Self> {
         let (width, height) = crossterm::terminal::size()?;
-        Ok(Self {
+        Ok(Screen::with_size(width, height))
+    }
+
+    // a screen that has nothing to do with the terminal until flushed
+    pub fn with_size(width: u16, height: u16) -> Self {
+        let mut screen = Self {
             stdout: stdout(),
-            width,
-            height: height-2,
-        })
+            width: 0,
+            height: 0,
+            gutter: 0,
+            back: Vec::new(),
+            front: Vec::new(),
+            cursor: (0, 0),
+            shown_cursor: None,
+            theme: Theme::default(),
+            depth: ColorDepth::detect(),
+        };
+        screen.resize(width, height);
+        screen
+    }
+
+    // two lines are kept for the status bar and message line
+    pub fn resize(&mut self, width: u16, height: u16) {
+        self.width = width;
+        self.height = height.saturating_sub(2);
+        self.gutter = self.gutter.min(width);
+        self.back = vec![Cell::default(); width as usize * height as usize];
+        self.invalidate();
+    }
+
+    pub fn set_gutter(&mut self, width: u16) {
+        self.gutter = width.min(self.width);
+    }
+
+    pub fn gutter_width(&self) -> u16 {
+        self.gutter
+    }
+
+    pub fn set_theme(&mut self, theme: Theme) {
+        self.theme = theme;
+    }
+
+    // forget what the terminal shows so the next flush redraws everything
+    pub fn invalidate(&mut self) {
+        self.front.clear();
+        self.shown_cursor = None;
     }
 
-    pub fn draw_row(&mut self, rows: &[Row], rowoff: u16, coloff: u16) -> Result<()> {
+    fn put(&mut self, x: usize, y: u16, ch: char, style: Style) {
+        if x < self.width as usize && y < self.height + 2 {
+            self.back[y as usize * self.width as usize + x] = Cell { ch, style };
+        }
+    }
+
+    fn put_str(&mut self, x: usize, y: u16, s: &str, style: Style) {
+        for (i, ch) in s.chars().enumerate() {
+            self.put(x + i, y, ch, style);
+        }
+    }
+
+    // selection is an inclusive (start, end) range of buffer positions;
+    // signs holds the gutter mark, if any, of each buffer row; rows inside
+    // closed folds are skipped and the fold's first row summarises them
+    #[allow(clippy::too_many_arguments)]
+    pub fn draw_row(&mut self, rows: &[Row], rowoff: u16, coloff: u16,
+                    selection: Option<(Position, Position)>, signs: &[Option<Sign>],
+                    marks: &[Mark], folds: &Folds) -> Result<()> {
         const VERSION: &str = env!("CARGO_PKG_VERSION");
+        let gutter = self.gutter as usize;
+        let text_width = (self.width - self.gutter) as usize;
+        let mut next_row = rowoff as usize;
         for row in 0..self.height {
-            let filerow = (row  + rowoff) as usize;
+            let filerow = next_row;
+            next_row = folds.next_visible(filerow);
+            if gutter > 0 {
+                let style = self.theme.style("normal").patch(self.theme.style("gutter"));
+                self.put_str(0, row, &" ".repeat(gutter), style);
+                if let Some(Some(sign)) = signs.get(filerow) {
+                    self.put(0, row, sign.ch, style.patch(self.theme.style(sign.element)));
+                }
+            }
             if filerow >= rows.len() {
+                let normal = self.theme.style("normal");
+                self.put(gutter, row, '~', normal);
                 if rows.is_empty() && row == self.height /3 {
                     let mut welcome = format!("Kilo Editor -- version {VERSION}");
-                    welcome.truncate(self.width as usize);
-                    if welcome.len() < self.width as usize {
-                        let leftmost = (self.width as usize - welcome.len())/2;
-                        self.stdout.queue(cursor::MoveTo(0,row))?
-                            .queue(style::Print('~'))?
-                            .queue(cursor::MoveTo(leftmost as u16,row))?
-                            .queue(style::Print(welcome))?;
-                    } else {
-                        self.stdout
-                        .queue(cursor::MoveTo(0,row))?
-                        .queue(style::Print(welcome))?;
-                    }
-                } else {
-                    self.stdout
-                        .queue(cursor::MoveTo(0, row))?
-                        .queue(style::Print('~'))?;
+                    welcome.truncate(text_width);
+                    let leftmost = (text_width - welcome.len())/2;
+                    self.put_str(gutter + leftmost, row, &welcome, normal);
+                }
+            } else if let Some(fold) = folds.at(filerow) {
+                let summary = format!("{} ... {} lines", rows[filerow].render.trim_end(), fold.end - fold.start + 1);
+                let style = self.theme.style("normal").patch(self.theme.style("fold"));
+                for (i, c) in summary.chars().skip(coloff as usize).take(text_width).enumerate() {
+                    self.put(gutter + i, row, c, style);
                 }
             } else {
                 let mut len = rows[filerow].render_len();
@@ -53,113 +193,296 @@ impl Screen {
                 }
                 len -= coloff as usize;
                 let start = coloff as usize;
-                let end = start +  if len > self.width as usize {
-                    self.width as usize
+                let end = start +  if len > text_width {
+                    text_width
                 } else {
                     len
                 };
 
-                self.stdout
-                    .queue(cursor::MoveTo(0,row))?;
-
-                let mut hl_iter = rows[filerow].iter_highlight(start, end);
-                let mut hl = hl_iter.next();
-                let mut current_color = Color::Reset;
-
-                for c in rows[filerow].render[start..end].chars() {
-                    let highlight = *hl.unwrap();
-                    if c.is_ascii_control() {
-                        let  sym = if c as u8 <= 26 { (b'@' + c as u8) as char } else { '?' };
-                        self.stdout
-                            .queue(SetAttribute(Reverse))?
-                            .queue(Print(sym))?
-                            .queue(SetAttribute(Reset))?;
-                        if current_color != Color::Reset {
-                            self.stdout
-                                .queue(SetForegroundColor(current_color))?;
-
-                        }
+                let (sel_start, sel_end) = Screen::selected_columns(&rows[filerow], filerow, selection);
+                let selection_style = self.theme.style("selection");
+                let row_marks: Vec<((usize, usize), Style)> = marks.iter()
+                    .filter(|mark| (mark.start.y as usize..=mark.end.y as usize).contains(&filerow))
+                    .map(|mark| (Screen::mark_columns(&rows[filerow], filerow, mark),
+                                 self.theme.style(mark.element)))
+                    .collect();
+                let cells = rows[filerow].render.chars().skip(start).take(end - start)
+                    .zip(rows[filerow].iter_highlight(start, end));
 
-                    } else if highlight == Highlight::Normal {
-                        if current_color != Color::Reset {
-                            self.stdout
-                                .queue(SetForegroundColor(Color::Reset))?;
-                            current_color = Color::Reset;
+                for (i, (c, highlight)) in cells.enumerate() {
+                    let mut style = self.theme.style("normal").patch(self.theme.style(highlight.name()));
+                    for ((from, to), mark_style) in &row_marks {
+                        if (*from..*to).contains(&(start + i)) {
+                            style = style.patch(*mark_style);
                         }
+                    }
+                    if (sel_start..sel_end).contains(&(start + i)) {
+                        style = style.patch(selection_style);
+                    }
+                    if c.is_ascii_control() {
+                        style.reverse = true;
+                        self.put(gutter + i, row, Screen::control_symbol(c), style);
                     } else {
-                        let color = highlight.syntax_to_color();
-                        if current_color != color {
-                            self.stdout
-                                .queue(SetForegroundColor(color))?;
-                        }
-                        current_color = color;
+                        self.put(gutter + i, row, c, style);
                     }
-                    self.stdout
-                        .queue(Print(c))?;
-                    hl = hl_iter.next();
                 }
-                self.stdout
-                    .queue(SetForegroundColor(Color::Reset))?;
             }
         }
         Ok(())
     }
 
+    // render columns [start, end) of the row covered by a mark; at least
+    // one, so empty ranges still show
+    fn mark_columns(row: &Row, filerow: usize, mark: &Mark) -> (usize, usize) {
+        let from = if filerow == mark.start.y as usize { row.cx_to_rx(mark.start.x) } else { 0 };
+        let to = if filerow == mark.end.y as usize {
+            row.cx_to_rx(mark.end.x.min(row.len() as u16))
+        } else {
+            row.render_len() as u16
+        };
+        (from as usize, to.max(from + 1) as usize)
+    }
+
+    // a control char takes one column, drawn reversed as the letter of its
+    // ^X notation (? past ^Z); the raw char itself is never printed, since it
+    // would move the terminal cursor off the cell
+    fn control_symbol(c: char) -> char {
+        if c as u8 <= 26 { (b'@' + c as u8) as char } else { '?' }
+    }
+
+    // render columns [start, end) of the row covered by the selection
+    fn selected_columns(row: &Row, filerow: usize, selection: Option<(Position, Position)>) -> (usize, usize) {
+        let (start, end) = match selection {
+            Some(selection) => selection,
+            None => return (0, 0),
+        };
+        if filerow < start.y as usize || filerow > end.y as usize {
+            return (0, 0);
+        }
+        let from = if filerow == start.y as usize { row.cx_to_rx(start.x) } else { 0 };
+        let to = if filerow == end.y as usize {
+            row.cx_to_rx(row.next_boundary(end.x as usize) as u16).max(from + 1)
+        } else {
+            row.render_len() as u16 + 1
+        };
+        (from as usize, to as usize)
+    }
+
     pub fn draw_status_bar<T: Into<String>>(&mut self, left: T, right: T, help: T) -> Result<()> {
         let left = left.into();
         let right = right.into();
-        let left_width = left.len();
-        let right_width = right.len();
-        let screen_width = self.width;
-
-        let status = format!("{left:0$}", left_width.min(screen_width as usize));
-        let mut rstatus = String::new();
-        if status.len() < screen_width as usize - right_width {
-            let mut len = status.len() as u16;
-            while len < screen_width {
-                if screen_width - len  == right_width as u16 {
-                    rstatus.push_str(right.as_str());
-                    break;
+        let screen_width = self.width as usize;
+
+        // left aligned, then right aligned if both fit with a space between
+        let left: String = left.chars().take(screen_width).collect();
+        let left_width = left.chars().count();
+        let right_width = right.chars().count();
+        let status = if left_width + 1 + right_width <= screen_width {
+            format!("{left}{right:>0$}", screen_width - left_width)
+        } else {
+            format!("{left:screen_width$}")
+        };
+
+        let status_line = self.height;
+        self.put_str(0, status_line, &status, self.theme.style("status-bar"));
+        self.put_str(0, status_line + 1, &format!("{:1$}", help.into(), screen_width),
+                     self.theme.style("message-line"));
+        Ok(())
+    }
+
+
+    pub fn draw_popup(&mut self, popup: &Popup) -> Result<()> {
+        let height = popup.height() as usize;
+        if height == 0 || popup.x >= self.width {
+            return Ok(());
+        }
+        // scroll so the selected item is visible
+        let first = popup.selected.map_or(0, |i| (i + 1).saturating_sub(height));
+        let value_width = popup.items.iter().map(|(value, _)| value.len()).max().unwrap_or(0);
+        let lines: Vec<String> = popup.items[first..first + height].iter()
+            .map(|(value, detail)| {
+                if detail.is_empty() {
+                    format!(" {value} ")
                 } else {
-                    rstatus.push(' ');
-                    len += 1;
+                    format!(" {value:value_width$}  {detail} ")
                 }
+            })
+            .collect();
+        let room = (self.width - popup.x) as usize;
+        let width = lines.iter().map(|line| line.len()).max().unwrap_or(0).min(room);
+
+        for (i, line) in lines.iter().enumerate() {
+            let y = popup.y + i as u16;
+            if y >= self.height {
+                break;
             }
+            let line: String = format!("{line:width$}").chars().take(width).collect();
+            let style = self.theme.style(if Some(first + i) == popup.selected { "popup-selected" } else { "popup" });
+            self.put_str(popup.x as usize, y, &line, style);
         }
-
-        (self.stdout
-            .queue(cursor::MoveTo(0,self.height))?
-            .queue(SetAttribute(Reverse))?
-            .queue(style::Print(format!("{status}{rstatus}")))?
-            .queue(cursor::MoveTo(0,self.height+1))?)
-            .queue(style::Print(format!("{:1$}",help.into(), screen_width as usize)))?
-            .queue(SetAttribute(Reset))?;
         Ok(())
     }
 
-
+    // start a new frame
     pub fn clear(&mut self) -> Result<()> {
-        self.stdout
-            .queue(terminal::Clear(terminal::ClearType::All))?
-            .queue(cursor::MoveTo(0,0))?;
+        self.back.fill(Cell { ch: ' ', style: self.theme.style("normal") });
         Ok(())
     }
 
+    // Takes over the terminal: raw mode, the alternate screen (so the
+    // user's scrollback is left alone), mouse events and focus changes.
+    pub fn enter(&mut self) -> Result<()> {
+        terminal::enable_raw_mode()?;
+        self.stdout
+            .queue(terminal::EnterAlternateScreen)?
+            .queue(event::EnableMouseCapture)?
+            .queue(event::EnableFocusChange)?;
+        self.stdout.flush()
+    }
+
+    // Sends the cells that changed since the last frame, with the cursor
+    // hidden while they're drawn. Nothing is written when the frame and
+    // the cursor position are the same as before.
     pub fn flush(&mut self) -> Result<()> {
+        if self.front.len() != self.back.len() {
+            self.stdout.queue(terminal::Clear(terminal::ClearType::All))?;
+            self.front = vec![Cell::default(); self.back.len()];
+        }
+
+        let width = self.width as usize;
+        let mut drawing = false;
+        let mut pen: Option<usize> = None;     // where the next Print lands
+        let mut style: Option<Style> = None;
+        for i in 0..self.back.len() {
+            let cell = self.back[i];
+            if cell == self.front[i] {
+                continue;
+            }
+            if !drawing {
+                self.stdout.queue(cursor::Hide)?;
+                drawing = true;
+            }
+            if pen != Some(i) {
+                self.stdout.queue(cursor::MoveTo((i % width) as u16, (i / width) as u16))?;
+            }
+            if style != Some(cell.style) {
+                Screen::queue_style(&mut self.stdout, cell.style, self.depth)?;
+                style = Some(cell.style);
+            }
+            self.stdout.queue(Print(cell.ch))?;
+            // the terminal may wrap or not after the last column
+            pen = if (i + 1) % width == 0 { None } else { Some(i + 1) };
+            self.front[i] = cell;
+        }
+
+        if drawing {
+            self.stdout.queue(SetAttribute(Reset))?;
+        }
+        if drawing || self.shown_cursor != Some(self.cursor) {
+            self.stdout.queue(cursor::MoveTo(self.cursor.0, self.cursor.1))?;
+            self.shown_cursor = Some(self.cursor);
+        }
+        if drawing {
+            self.stdout.queue(cursor::Show)?;
+        }
         self.stdout.flush()
     }
 
-    pub fn move_to(&mut self, pos: &Position, render_x:u16,  rowoff: u16, coloff: u16) -> Result<()> {
-        self.stdout
-            .queue(cursor::MoveTo(render_x - coloff, pos.y - rowoff))?;
+    fn queue_style(stdout: &mut Stdout, style: Style, depth: ColorDepth) -> Result<()> {
+        stdout
+            .queue(SetAttribute(Reset))?
+            .queue(SetForegroundColor(depth.adapt(style.fg)))?
+            .queue(SetBackgroundColor(depth.adapt(style.bg)))?;
+        for (on, attribute) in [(style.bold, Bold), (style.italic, Italic),
+                                (style.underline, Underlined), (style.reverse, Reverse)] {
+            if on {
+                stdout.queue(SetAttribute(attribute))?;
+            }
+        }
+        Ok(())
+    }
+
+    // row is the cursor's row on screen
+    pub fn move_to(&mut self, row: u16, render_x:u16, coloff: u16) -> Result<()> {
+        self.cursor = (self.gutter + render_x - coloff, row);
         Ok(())
     }
 
     pub fn bounds(&self) -> Position {
         Position {
-            x: self.width,
+            x: self.width - self.gutter,
             y: self.height
         }
     }
 }
 
+
+// Undoes Screen::enter. A free function so the panic hook can call it;
+// errors are ignored since there's nothing better to do on the way out.
+pub fn restore_terminal() {
+    let mut stdout = stdout();
+    let _ = stdout
+        .queue(event::DisableMouseCapture)
+        .and_then(|out| out.queue(event::DisableFocusChange))
+        .and_then(|out| out.queue(terminal::LeaveAlternateScreen))
+        .and_then(|out| out.queue(cursor::Show))
+        .and_then(|out| out.flush());
+    let _ = terminal::disable_raw_mode();
+}
+
+// Restores the terminal before the default hook prints the panic message,
+// which would otherwise be lost on the alternate screen.
+pub fn install_panic_hook() {
+    let default_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        restore_terminal();
+        default_hook(info);
+    }));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(screen: &Screen, y: u16) -> String {
+        let width = screen.width as usize;
+        screen.back[y as usize * width..(y as usize + 1) * width].iter().map(|cell| cell.ch).collect()
+    }
+
+    fn draw(screen: &mut Screen, rows: &[Row], coloff: u16, selection: Option<(Position, Position)>) {
+        screen.draw_row(rows, 0, coloff, selection, &[], &[], &Folds::default()).unwrap();
+    }
+
+    #[test]
+    fn cuts_rows_between_chars() {
+        let mut screen = Screen::with_size(80, 5);
+        let rows = [Row::new(format!("{}éb", "a".repeat(79)), None), Row::new(String::from("ü\tx"), None)];
+        draw(&mut screen, &rows, 0, None);
+        assert_eq!(text(&screen, 0), format!("{}é", "a".repeat(79)));
+        assert_eq!(text(&screen, 1).trim_end(), "ü       x");
+        let mut screen = Screen::with_size(80, 5);
+        draw(&mut screen, &rows, 79, None);
+        assert_eq!(text(&screen, 0).trim_end(), "éb");
+    }
+
+    #[test]
+    fn selects_whole_chars() {
+        let mut screen = Screen::with_size(20, 3);
+        let rows = [Row::new(String::from("añb"), None)];
+        // an inclusive selection ending on ñ, which is two bytes
+        let selection = (Position { x: 1, y: 0 }, Position { x: 1, y: 0 });
+        assert_eq!(Screen::selected_columns(&rows[0], 0, Some(selection)), (1, 2));
+        draw(&mut screen, &rows, 0, Some(selection));
+        assert_eq!(text(&screen, 0).trim_end(), "añb");
+    }
+
+    #[test]
+    fn draws_control_chars_as_one_reversed_symbol() {
+        let mut screen = Screen::with_size(20, 3);
+        let rows = [Row::new(String::from("a\u{1}b\u{1b}c"), None)];
+        draw(&mut screen, &rows, 0, None);
+        assert_eq!(text(&screen, 0).trim_end(), "aAb?c");
+        assert!(screen.back[1].style.reverse && screen.back[3].style.reverse);
+        assert!(!screen.back[2].style.reverse);
+    }
+}
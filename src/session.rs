@@ -0,0 +1,123 @@
+use std::fs;
+use std::path::PathBuf;
+
+use kilo_ed::{Position, StdResult};
+use crate::config;
+use crate::fileio::{self, Backup};
+
+// A saved working set, in $XDG_STATE_HOME/kilo-ed/sessions/NAME:
+//
+//     file PATH
+//     cursor ROW COLUMN
+//     scroll ROWOFF COLOFF
+//     mark ROW COLUMN NAME
+//     search QUERY
+//
+// cursor, scroll and mark lines belong to the file above them; searches are
+// oldest first. The editor has one buffer in one window: the first file
+// is the one it opens, and there is no window layout to save.
+
+#[derive(Clone)]
+pub struct Buffer {
+    pub path: PathBuf,
+    pub cursor: Position,
+    pub rowoff: u16,
+    pub coloff: u16,
+    pub marks: Vec<(String, Position)>,
+}
+
+#[derive(Default)]
+pub struct Session {
+    pub buffers: Vec<Buffer>,
+    pub searches: Vec<String>,
+}
+
+fn session_path(name: &str) -> StdResult<PathBuf, String> {
+    if name.is_empty() || name.contains('/') {
+        return Err(format!("'{name}' isn't a session name"));
+    }
+    config::state_file("sessions")
+        .map(|dir| dir.join(name))
+        .ok_or_else(|| String::from("no state directory"))
+}
+
+// saved sessions, for completion
+pub fn names() -> Vec<String> {
+    let Some(Ok(entries)) = config::state_file("sessions").map(fs::read_dir) else { return Vec::new() };
+    let mut names: Vec<String> = entries.flatten()
+        .map(|entry| entry.file_name().to_string_lossy().into_owned())
+        .collect();
+    names.sort();
+    names
+}
+
+fn position(fields: &str) -> Option<(Position, &str)> {
+    let mut fields = fields.splitn(3, ' ');
+    let y = fields.next()?.parse().ok()?;
+    let x = fields.next()?.parse().ok()?;
+    Some((Position { x, y }, fields.next().unwrap_or("")))
+}
+
+impl Session {
+    // None when there's no session of that name yet
+    pub fn load(name: &str) -> StdResult<Option<Session>, String> {
+        let path = session_path(name)?;
+        let text = match fs::read_to_string(&path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(format!("{}: {e}", path.display())),
+        };
+        let mut session = Session::default();
+        for (lineno, line) in text.lines().enumerate() {
+            let error = || format!("{}:{}: can't read '{line}'", path.display(), lineno + 1);
+            let (key, rest) = line.split_once(' ').unwrap_or((line, ""));
+            if key == "file" {
+                session.buffers.push(Buffer {
+                    path: PathBuf::from(rest),
+                    cursor: Position::default(),
+                    rowoff: 0,
+                    coloff: 0,
+                    marks: Vec::new(),
+                });
+                continue;
+            }
+            if key == "search" {
+                session.searches.push(rest.to_string());
+                continue;
+            }
+            if line.trim().is_empty() {
+                continue;
+            }
+            let buffer = session.buffers.last_mut().ok_or_else(error)?;
+            let (pos, name) = position(rest).ok_or_else(error)?;
+            match key {
+                "cursor" => buffer.cursor = pos,
+                "scroll" => (buffer.rowoff, buffer.coloff) = (pos.y, pos.x),
+                "mark" if !name.is_empty() => buffer.marks.push((name.to_string(), pos)),
+                _ => return Err(error()),
+            }
+        }
+        Ok(Some(session))
+    }
+
+    pub fn save(&self, name: &str) -> StdResult<(), String> {
+        let path = session_path(name)?;
+        let mut buf = String::new();
+        for buffer in &self.buffers {
+            buf.push_str(&format!("file {}\n", buffer.path.display()));
+            buf.push_str(&format!("cursor {} {}\n", buffer.cursor.y, buffer.cursor.x));
+            buf.push_str(&format!("scroll {} {}\n", buffer.rowoff, buffer.coloff));
+            for (name, pos) in &buffer.marks {
+                buf.push_str(&format!("mark {} {} {name}\n", pos.y, pos.x));
+            }
+        }
+        for search in &self.searches {
+            buf.push_str(&format!("search {search}\n"));
+        }
+        let error = |e: std::io::Error| format!("{}: {e}", path.display());
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).map_err(error)?;
+        }
+        fileio::write_atomic(&path, buf.as_bytes(), Backup::None).map_err(error)
+    }
+}
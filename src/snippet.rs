@@ -0,0 +1,182 @@
+use std::iter::Peekable;
+use std::str::Chars;
+
+use kilo_ed::{Position, StdResult};
+use crate::config;
+
+// Snippets in the snipMate format, from snippets/FILETYPE and snippets/all
+// in the config directory:
+//
+//     snippet for  counting loop
+//     	for (${1:i} = 0; $1 < ${2:n}; $1++) {
+//     		$0
+//     	}
+//
+// Body lines start with a tab, which is removed. $1, ${1} and ${1:text} are
+// tab stops, visited in order and then $0; a number used again mirrors the
+// first. $FILENAME, $FILEPATH, $DATE and $YEAR are filled in.
+
+pub struct Snippet {
+    pub trigger: String,
+    pub body: Vec<String>,
+}
+
+pub fn load(filetype: Option<&str>) -> StdResult<Vec<Snippet>, String> {
+    let mut snippets = Vec::new();
+    for name in filetype.into_iter().chain(["all"]) {
+        let Some(path) = config::config_file("snippets").map(|dir| dir.join(name)) else { continue };
+        let text = match std::fs::read_to_string(&path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(format!("{}: {e}", path.display())),
+        };
+        for (lineno, line) in text.lines().enumerate() {
+            if let Some(body) = line.strip_prefix('\t') {
+                let snippet: &mut Snippet = snippets.last_mut()
+                    .ok_or_else(|| format!("{}:{}: body line outside a snippet", path.display(), lineno + 1))?;
+                snippet.body.push(body.to_string());
+            } else if let Some(rest) = line.strip_prefix("snippet ") {
+                let trigger = rest.split_whitespace().next().unwrap_or("");
+                if trigger.is_empty() {
+                    return Err(format!("{}:{}: snippet needs a trigger", path.display(), lineno + 1));
+                }
+                snippets.push(Snippet { trigger: trigger.to_string(), body: Vec::new() });
+            } else if !(line.trim().is_empty() || line.starts_with('#')) {
+                return Err(format!("{}:{}: expected 'snippet TRIGGER' or a tab-indented line",
+                                   path.display(), lineno + 1));
+            }
+        }
+    }
+    Ok(snippets)
+}
+
+// A placeholder and its mirrors, by byte offset in the expanded text.
+pub struct Stop {
+    pub number: u32,
+    pub ranges: Vec<(usize, usize)>,
+}
+
+struct Expander<'a> {
+    out: String,
+    stops: Vec<Stop>,
+    variable: &'a dyn Fn(&str) -> Option<String>,
+}
+
+impl Expander<'_> {
+    fn add_stop(&mut self, number: u32, start: usize) {
+        let range = (start, self.out.len());
+        match self.stops.iter_mut().find(|stop| stop.number == number) {
+            Some(stop) => stop.ranges.push(range),
+            None => self.stops.push(Stop { number, ranges: vec![range] }),
+        }
+    }
+
+    // the text of a stop seen before, for its mirrors
+    fn first_text(&self, number: u32) -> Option<String> {
+        let stop = self.stops.iter().find(|stop| stop.number == number)?;
+        let (start, end) = stop.ranges[0];
+        Some(self.out[start..end].to_string())
+    }
+
+    // Copies template text to the output up to an unmatched '}' when
+    // nested, or the end.
+    fn template(&mut self, chars: &mut Peekable<Chars>, nested: bool) {
+        while let Some(c) = chars.next() {
+            match c {
+                '\\' if matches!(chars.peek(), Some('$' | '}' | '\\')) => self.out.push(chars.next().unwrap()),
+                '}' if nested => return,
+                '$' => self.dollar(chars),
+                c => self.out.push(c),
+            }
+        }
+    }
+
+    fn dollar(&mut self, chars: &mut Peekable<Chars>) {
+        let braced = chars.next_if_eq(&'{').is_some();
+        let mut name = String::new();
+        while let Some(c) = chars.next_if(|c| c.is_ascii_alphanumeric() || *c == '_') {
+            name.push(c);
+        }
+        let start = self.out.len();
+        if let Ok(number) = name.parse::<u32>() {
+            if braced && chars.next_if_eq(&':').is_some() {
+                self.template(chars, true);
+            } else {
+                if braced {
+                    chars.next_if_eq(&'}');
+                }
+                if let Some(text) = self.first_text(number) {
+                    self.out.push_str(&text);
+                }
+            }
+            self.add_stop(number, start);
+        } else if name.is_empty() {
+            self.out.push('$');
+            if braced {
+                self.out.push('{');
+            }
+        } else {
+            if braced {
+                chars.next_if_eq(&'}');
+            }
+            // unknown variables are left as they are
+            let value = (self.variable)(&name).unwrap_or(name);
+            self.out.push_str(&value);
+        }
+    }
+}
+
+// The snippet's text indented like the row it goes into, and its stops in
+// the order Tab visits them: 1, 2, ... and finally 0 (the end of the text
+// when the snippet has no $0).
+pub fn expand(body: &[String], indent: &str, variable: &dyn Fn(&str) -> Option<String>) -> (String, Vec<Stop>) {
+    let template = body.join(&format!("\n{indent}"));
+    let mut expander = Expander { out: String::new(), stops: Vec::new(), variable };
+    expander.template(&mut template.chars().peekable(), false);
+    let Expander { out, mut stops, .. } = expander;
+    if !stops.iter().any(|stop| stop.number == 0) {
+        stops.push(Stop { number: 0, ranges: vec![(out.len(), out.len())] });
+    }
+    stops.sort_by_key(|stop| if stop.number == 0 { u32::MAX } else { stop.number });
+    (out, stops)
+}
+
+// where a byte offset into inserted text lands in the buffer
+pub fn position_in(text: &str, at: Position, offset: usize) -> Position {
+    let before = &text[..offset];
+    match before.rfind('\n') {
+        Some(newline) => Position {
+            x: (offset - newline - 1) as u16,
+            y: at.y + before.matches('\n').count() as u16,
+        },
+        None => Position { x: at.x + offset as u16, y: at.y },
+    }
+}
+
+// An expanded snippet whose stops Tab is still visiting. The positions are
+// kept up to date as the current stop and its mirrors are edited.
+pub struct Session {
+    pub stops: Vec<Vec<(Position, Position)>>,
+    pub current: usize,
+    pub version: u64,       // buffer version the positions are right for
+    pub row_len: usize,     // length of the current stop's row then
+}
+
+impl Session {
+    // Moves positions on `row` at or after `x` along by `delta`, apart from
+    // the range being edited.
+    pub fn shift(&mut self, row: u16, x: u16, delta: isize, except: (usize, usize)) {
+        for (i, ranges) in self.stops.iter_mut().enumerate() {
+            for (j, (start, end)) in ranges.iter_mut().enumerate() {
+                if (i, j) == except {
+                    continue;
+                }
+                for pos in [start, end] {
+                    if pos.y == row && pos.x >= x {
+                        pos.x = (pos.x as isize + delta).max(0) as u16;
+                    }
+                }
+            }
+        }
+    }
+}
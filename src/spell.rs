@@ -0,0 +1,317 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
+
+use kilo_ed::StdResult;
+use crate::config;
+
+// Spell checking against a Hunspell dictionary, LANG.aff and LANG.dic. Only
+// the parts of the affix file plain word lists need are understood:
+// prefixes and suffixes (strip, add and condition) and their cross products.
+
+// searched after dict/ in the config directory
+const DICT_DIRS: &[&str] = &["/usr/share/hunspell", "/usr/share/myspell", "/usr/share/myspell/dicts"];
+const MAX_SUGGESTIONS: usize = 5;
+
+#[derive(Copy, Clone)]
+enum FlagMode {
+    Char,
+    Long,   // two characters
+    Num,    // comma separated numbers
+}
+
+fn parse_flags(flags: &str, mode: FlagMode) -> Vec<String> {
+    match mode {
+        FlagMode::Char => flags.chars().map(String::from).collect(),
+        FlagMode::Long => {
+            let chars: Vec<char> = flags.chars().collect();
+            chars.chunks(2).map(|pair| pair.iter().collect()).collect()
+        }
+        FlagMode::Num => flags.split(',').map(|flag| flag.trim().to_string()).collect(),
+    }
+}
+
+// one character of an affix condition
+enum Cond {
+    Any,
+    Char(char),
+    Set(bool, Vec<char>),   // [abc], or [^abc] when negated
+}
+
+impl Cond {
+    fn parse(condition: &str) -> Vec<Cond> {
+        let mut conds = Vec::new();
+        let mut chars = condition.chars();
+        while let Some(c) = chars.next() {
+            conds.push(match c {
+                '.' => Cond::Any,
+                '[' => {
+                    let set: Vec<char> = chars.by_ref().take_while(|c| *c != ']').collect();
+                    match set.split_first() {
+                        Some(('^', rest)) => Cond::Set(true, rest.to_vec()),
+                        _ => Cond::Set(false, set),
+                    }
+                }
+                c => Cond::Char(c),
+            });
+        }
+        conds
+    }
+
+    fn matches(&self, c: char) -> bool {
+        match self {
+            Cond::Any => true,
+            Cond::Char(want) => c == *want,
+            Cond::Set(negated, set) => set.contains(&c) != *negated,
+        }
+    }
+}
+
+struct Affix {
+    flag: String,
+    strip: String,
+    add: String,
+    condition: Vec<Cond>,
+    cross: bool,
+}
+
+impl Affix {
+    // the stem `word` is made from by adding this suffix
+    fn unsuffix(&self, word: &str) -> Option<String> {
+        let base = word.strip_suffix(self.add.as_str()).filter(|base| !base.is_empty())?;
+        let stem = format!("{base}{}", self.strip);
+        let chars: Vec<char> = stem.chars().collect();
+        let tail = chars.len().checked_sub(self.condition.len())?;
+        self.condition.iter().zip(&chars[tail..])
+            .all(|(cond, c)| cond.matches(*c))
+            .then_some(stem)
+    }
+
+    fn unprefix(&self, word: &str) -> Option<String> {
+        let base = word.strip_prefix(self.add.as_str()).filter(|base| !base.is_empty())?;
+        let stem = format!("{}{base}", self.strip);
+        (stem.chars().count() >= self.condition.len() &&
+            self.condition.iter().zip(stem.chars()).all(|(cond, c)| cond.matches(c)))
+            .then_some(stem)
+    }
+}
+
+fn capitalize(word: &str) -> String {
+    let mut chars = word.chars();
+    match chars.next() {
+        Some(first) => first.to_uppercase().chain(chars).collect(),
+        None => String::new(),
+    }
+}
+
+// Byte ranges of the words in a line worth checking. Identifiers that end
+// up in comments and strings (snake_case, camelCase, anything with digits)
+// are left alone.
+pub fn words(line: &str) -> Vec<(usize, usize)> {
+    let mut words = Vec::new();
+    let mut start = None;
+    for (i, c) in line.char_indices().chain([(line.len(), ' ')]) {
+        let in_word = c.is_alphanumeric() || c == '_' || c == '\'';
+        match (start, in_word) {
+            (None, true) => start = Some(i),
+            (Some(s), false) => {
+                start = None;
+                let word = line[s..i].trim_matches('\'');
+                let s = s + line[s..i].find(word).unwrap_or(0);
+                let camel = word.chars().skip(1).any(char::is_uppercase) && word.chars().any(char::is_lowercase);
+                if !word.is_empty() && !camel && word.chars().all(|c| c.is_alphabetic() || c == '\'') {
+                    words.push((s, s + word.len()));
+                }
+            }
+            _ => {}
+        }
+    }
+    words
+}
+
+pub struct Dictionary {
+    pub lang: String,
+    words: HashMap<String, Vec<String>>,    // word -> flags
+    prefixes: Vec<Affix>,
+    suffixes: Vec<Affix>,
+    try_chars: Vec<char>,   // tried first when guessing suggestions
+    personal: HashSet<String>,
+    checked: HashMap<String, bool>,
+}
+
+impl Dictionary {
+    pub fn load(lang: &str) -> StdResult<Dictionary, String> {
+        let dir = config::config_file("dict").into_iter()
+            .chain(DICT_DIRS.iter().map(PathBuf::from))
+            .find(|dir| dir.join(format!("{lang}.dic")).exists())
+            .ok_or_else(|| format!("no dictionary for '{lang}'"))?;
+        let read = |ext: &str| {
+            let path = dir.join(format!("{lang}.{ext}"));
+            fs::read(&path)
+                .map(|bytes| String::from_utf8_lossy(&bytes).into_owned())
+                .map_err(|e| format!("{}: {e}", path.display()))
+        };
+        let mut dictionary = Dictionary {
+            lang: lang.to_string(),
+            words: HashMap::new(),
+            prefixes: Vec::new(),
+            suffixes: Vec::new(),
+            try_chars: Vec::new(),
+            personal: HashSet::new(),
+            checked: HashMap::new(),
+        };
+        let mode = dictionary.parse_aff(&read("aff").unwrap_or_default());
+        dictionary.parse_dic(&read("dic")?, mode);
+        if let Some(path) = Dictionary::personal_path() {
+            if let Ok(text) = fs::read_to_string(path) {
+                dictionary.personal.extend(text.lines().map(|line| line.trim().to_string()));
+            }
+        }
+        Ok(dictionary)
+    }
+
+    fn personal_path() -> Option<PathBuf> {
+        config::config_file("dict").map(|dir| dir.join("personal"))
+    }
+
+    fn parse_aff(&mut self, text: &str) -> FlagMode {
+        let mut mode = FlagMode::Char;
+        let mut cross = HashMap::new();
+        for line in text.lines() {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            match fields.as_slice() {
+                ["FLAG", "long", ..] => mode = FlagMode::Long,
+                ["FLAG", "num", ..] => mode = FlagMode::Num,
+                ["TRY", chars, ..] => self.try_chars = chars.chars().collect(),
+                [kind @ ("PFX" | "SFX"), flag, yes_no @ ("Y" | "N"), count]
+                    if count.parse::<usize>().is_ok() => {
+                    cross.insert((*kind, flag.to_string()), *yes_no == "Y");
+                }
+                [kind @ ("PFX" | "SFX"), flag, strip, add, rest @ ..] => {
+                    let empty = |s: &str| if s == "0" { String::new() } else { s.to_string() };
+                    // continuation flags after the '/' aren't supported
+                    let add = add.split('/').next().unwrap_or("");
+                    let affix = Affix {
+                        flag: flag.to_string(),
+                        strip: empty(strip),
+                        add: empty(add),
+                        condition: Cond::parse(rest.first().unwrap_or(&".")),
+                        cross: cross.get(&(*kind, flag.to_string())).copied().unwrap_or(false),
+                    };
+                    if *kind == "PFX" {
+                        self.prefixes.push(affix);
+                    } else {
+                        self.suffixes.push(affix);
+                    }
+                }
+                _ => {}
+            }
+        }
+        mode
+    }
+
+    fn parse_dic(&mut self, text: &str, mode: FlagMode) {
+        // the first line is the number of words
+        for line in text.lines().skip_while(|line| line.trim().parse::<usize>().is_ok()) {
+            let Some(entry) = line.split_whitespace().next() else { continue };
+            let (word, flags) = entry.split_once('/').unwrap_or((entry, ""));
+            self.words.entry(word.to_string()).or_default().extend(parse_flags(flags, mode));
+        }
+    }
+
+    fn has_flag(&self, word: &str, flag: &str) -> bool {
+        self.words.get(word).is_some_and(|flags| flags.iter().any(|f| f == flag))
+    }
+
+    fn known(&self, word: &str) -> bool {
+        if self.words.contains_key(word) || self.personal.contains(word) {
+            return true;
+        }
+        if self.suffixes.iter().any(|sfx| sfx.unsuffix(word).is_some_and(|stem| self.has_flag(&stem, &sfx.flag))) {
+            return true;
+        }
+        self.prefixes.iter().any(|pfx| {
+            let Some(stem) = pfx.unprefix(word) else { return false };
+            self.has_flag(&stem, &pfx.flag) || pfx.cross && self.suffixes.iter()
+                .filter(|sfx| sfx.cross)
+                .any(|sfx| sfx.unsuffix(&stem)
+                    .is_some_and(|root| self.has_flag(&root, &pfx.flag) && self.has_flag(&root, &sfx.flag)))
+        })
+    }
+
+    // Accepts a word as it is, or with its case lowered the way it would be
+    // at the start of a sentence or in a heading.
+    fn known_any_case(&self, word: &str) -> bool {
+        let lower = word.to_lowercase();
+        self.known(word) || lower != word && (self.known(&lower) || self.known(&capitalize(&lower)))
+    }
+
+    pub fn check(&mut self, word: &str) -> bool {
+        if let Some(ok) = self.checked.get(word) {
+            return *ok;
+        }
+        let ok = self.known_any_case(word);
+        self.checked.insert(word.to_string(), ok);
+        ok
+    }
+
+    // Known words one edit away, or the word split in two.
+    pub fn suggest(&self, word: &str) -> Vec<String> {
+        let lower = word.to_lowercase();
+        let chars: Vec<char> = lower.chars().collect();
+        let mut alphabet: Vec<char> = Vec::new();
+        for c in self.try_chars.iter().flat_map(|c| c.to_lowercase()).chain('a'..='z') {
+            if !alphabet.contains(&c) {
+                alphabet.push(c);
+            }
+        }
+
+        let mut candidates = Vec::new();
+        for i in 0..chars.len() {
+            let (before, after) = (&chars[..i], &chars[i + 1..]);
+            if i + 1 < chars.len() {
+                let mut swapped = chars.clone();
+                swapped.swap(i, i + 1);
+                candidates.push(swapped.iter().collect::<String>());
+            }
+            for c in &alphabet {
+                candidates.push(before.iter().chain([c]).chain(after).collect());
+            }
+            candidates.push(before.iter().chain(after).collect());
+        }
+        for i in 0..=chars.len() {
+            for c in &alphabet {
+                candidates.push(chars[..i].iter().chain([c]).chain(&chars[i..]).collect());
+            }
+        }
+        for i in 1..chars.len() {
+            let (first, second): (String, String) = (chars[..i].iter().collect(), chars[i..].iter().collect());
+            if self.known(&first) && self.known(&second) {
+                candidates.push(format!("{first} {second}"));
+            }
+        }
+
+        let mut seen = HashSet::new();
+        let capitalized = word.chars().next().is_some_and(char::is_uppercase);
+        candidates.into_iter()
+            .filter(|candidate| *candidate != lower && seen.insert(candidate.clone()))
+            .filter(|candidate| candidate.contains(' ') || self.known_any_case(candidate))
+            .map(|candidate| if capitalized { capitalize(&candidate) } else { candidate })
+            .take(MAX_SUGGESTIONS)
+            .collect()
+    }
+
+    pub fn add(&mut self, word: &str) -> StdResult<(), String> {
+        let path = Dictionary::personal_path().ok_or("no config directory")?;
+        let error = |e: std::io::Error| format!("{}: {e}", path.display());
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).map_err(error)?;
+        }
+        let mut file = OpenOptions::new().create(true).append(true).open(&path).map_err(error)?;
+        writeln!(file, "{word}").map_err(error)?;
+        self.personal.insert(word.to_string());
+        self.checked.clear();
+        Ok(())
+    }
+}
@@ -0,0 +1,63 @@
+use kilo_ed::StdResult;
+
+// Status bar templates: literal text with `{segment}` or `{segment:WIDTH}`
+// placeholders, the latter padded to at least WIDTH. `{{` is a literal '{'.
+// Segments that are often empty (mode, recording, selection, branch) carry
+// their own trailing separator so nothing dangles when they are.
+pub const SEGMENTS: &[&str] = &[
+    "mode", "recording", "filename", "modified", "lines", "filetype", "line", "column",
+    "percentage", "encoding", "eol", "selection", "branch",
+];
+
+pub const DEFAULT_LEFT: &str = "{mode}{recording}{filename:20} - {lines} lines {modified}";
+pub const DEFAULT_RIGHT: &str = "{selection}{branch}{filetype} | {encoding} {eol} | {line}:{column} {percentage}";
+
+enum Part<'a> {
+    Text(&'a str),
+    Segment(&'a str, usize),
+}
+
+fn parse(template: &str) -> StdResult<Vec<Part<'_>>, String> {
+    let mut parts = Vec::new();
+    let mut rest = template;
+    while let Some(open) = rest.find('{') {
+        if rest[open..].starts_with("{{") {
+            parts.push(Part::Text(&rest[..open + 1]));
+            rest = &rest[open + 2..];
+            continue;
+        }
+        parts.push(Part::Text(&rest[..open]));
+        let close = rest[open..].find('}').ok_or("unclosed '{' in status template")? + open;
+        let (name, width) = match rest[open + 1..close].split_once(':') {
+            Some((name, width)) => (name, width.parse().map_err(|_| format!("bad width '{width}'"))?),
+            None => (&rest[open + 1..close], 0),
+        };
+        if !SEGMENTS.contains(&name) {
+            return Err(format!("unknown status segment '{name}'"));
+        }
+        parts.push(Part::Segment(name, width));
+        rest = &rest[close + 1..];
+    }
+    parts.push(Part::Text(rest));
+    Ok(parts)
+}
+
+pub fn validate(template: &str) -> StdResult<(), String> {
+    parse(template).map(|_| ())
+}
+
+// Fills in a template, asking `segment` for the value of each placeholder.
+// Templates are validated when set, so a bad one just comes out empty.
+pub fn expand<F: Fn(&str) -> String>(template: &str, segment: F) -> String {
+    let Ok(parts) = parse(template) else {
+        return String::new();
+    };
+    let mut out = String::new();
+    for part in parts {
+        match part {
+            Part::Text(text) => out.push_str(text),
+            Part::Segment(name, width) => out.push_str(&format!("{:width$}", segment(name))),
+        }
+    }
+    out
+}
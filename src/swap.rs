@@ -0,0 +1,69 @@
+use std::io;
+use std::path::{Path, PathBuf};
+
+use kilo_ed::Position;
+use crate::fileio::{self, Backup};
+
+// Swap files hold the unsaved buffer so it can be recovered after a crash:
+// a few header lines, a separator, then the text itself.
+const MAGIC: &str = "kilo-ed swap";
+const SEPARATOR: &str = "---";
+
+pub struct SwapFile {
+    pub pid: u32,
+    pub cursor: Position,
+    pub text: String,
+}
+
+// dir/.name.kilo-swp next to the file being edited
+pub fn swap_path(filename: &str) -> Option<PathBuf> {
+    if filename.is_empty() {
+        return None;
+    }
+    let path = fileio::resolve_symlinks(Path::new(filename));
+    let name = path.file_name()?.to_string_lossy();
+    Some(path.with_file_name(format!(".{name}.kilo-swp")))
+}
+
+pub fn write(path: &Path, cursor: Position, text: &str) -> io::Result<()> {
+    let buf = format!("{MAGIC}\npid: {}\ncursor: {} {}\n{SEPARATOR}\n{text}",
+                      std::process::id(), cursor.y, cursor.x);
+    fileio::write_atomic(path, buf.as_bytes(), Backup::None)
+}
+
+pub fn read(path: &Path) -> io::Result<SwapFile> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "not a kilo-ed swap file");
+    let contents = std::fs::read_to_string(path)?;
+    let (header, text) = contents.split_once(&format!("\n{SEPARATOR}\n")).ok_or_else(invalid)?;
+    let mut lines = header.lines();
+    if lines.next() != Some(MAGIC) {
+        return Err(invalid());
+    }
+
+    let mut swap = SwapFile { pid: 0, cursor: Position::default(), text: text.to_string() };
+    for line in lines {
+        match line.split_once(": ") {
+            Some(("pid", pid)) => swap.pid = pid.parse().map_err(|_| invalid())?,
+            Some(("cursor", cursor)) => {
+                if let Some((y, x)) = cursor.split_once(' ') {
+                    swap.cursor = Position {
+                        x: x.parse().map_err(|_| invalid())?,
+                        y: y.parse().map_err(|_| invalid())?,
+                    };
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(swap)
+}
+
+pub fn remove(path: &Path) {
+    let _ = std::fs::remove_file(path);
+}
+
+// Whether the process that wrote a swap file is still alive. Only Linux can
+// answer this cheaply; elsewhere every swap file is treated as stale.
+pub fn is_running(pid: u32) -> bool {
+    pid != std::process::id() && Path::new("/proc").join(pid.to_string()).exists()
+}
@@ -0,0 +1,260 @@
+use std::collections::HashMap;
+use std::env;
+use crossterm::style::Color;
+
+use kilo_ed::StdResult;
+use crate::config;
+
+// Everything a theme can style: the highlight classes (see Highlight::name)
+// and parts of the UI.
+pub const ELEMENTS: &[&str] = &[
+    "normal", "number", "string", "comment", "keyword1", "keyword2", "match",
+    "selection", "status-bar", "message-line", "gutter", "popup", "popup-selected",
+    "git-added", "git-modified", "git-deleted",
+    "diagnostic-error", "diagnostic-warning", "diagnostic-info", "diagnostic-hint", "diagnostic-underline",
+    "misspelled", "fold",
+];
+
+#[derive(Copy, Clone, PartialEq)]
+pub struct Style {
+    pub fg: Color,
+    pub bg: Color,
+    pub bold: bool,
+    pub italic: bool,
+    pub underline: bool,
+    pub reverse: bool,
+}
+
+impl Default for Style {
+    fn default() -> Self {
+        Self {
+            fg: Color::Reset,
+            bg: Color::Reset,
+            bold: false,
+            italic: false,
+            underline: false,
+            reverse: false,
+        }
+    }
+}
+
+impl Style {
+    fn fg(fg: Color) -> Self {
+        Self { fg, ..Style::default() }
+    }
+
+    fn reverse() -> Self {
+        Self { reverse: true, ..Style::default() }
+    }
+
+    // `other` drawn on top of self: its colours win where it has any
+    pub fn patch(self, other: Style) -> Style {
+        Style {
+            fg: if other.fg == Color::Reset { self.fg } else { other.fg },
+            bg: if other.bg == Color::Reset { self.bg } else { other.bg },
+            bold: self.bold || other.bold,
+            italic: self.italic || other.italic,
+            underline: self.underline || other.underline,
+            reverse: self.reverse || other.reverse,
+        }
+    }
+
+    // `[fg] [on bg] [bold] [italic] [underline] [reverse]`
+    fn parse(spec: &str) -> StdResult<Style, String> {
+        let mut style = Style::default();
+        let mut words = spec.split_whitespace();
+        let mut first = true;
+        while let Some(word) = words.next() {
+            match word {
+                "bold" => style.bold = true,
+                "italic" => style.italic = true,
+                "underline" => style.underline = true,
+                "reverse" => style.reverse = true,
+                "on" => {
+                    let bg = words.next().ok_or("expected a colour after 'on'")?;
+                    style.bg = parse_color(bg)?;
+                }
+                _ if first => style.fg = parse_color(word)?,
+                _ => return Err(format!("unexpected '{word}'")),
+            }
+            first = false;
+        }
+        Ok(style)
+    }
+}
+
+// #rrggbb, a 256-colour index or a name like red or dark-blue
+fn parse_color(s: &str) -> StdResult<Color, String> {
+    if s == "default" {
+        return Ok(Color::Reset);
+    }
+    if let Some(hex) = s.strip_prefix('#') {
+        let value = u32::from_str_radix(hex, 16).ok().filter(|_| hex.len() == 6)
+            .ok_or_else(|| format!("bad colour '{s}'"))?;
+        return Ok(Color::Rgb { r: (value >> 16) as u8, g: (value >> 8) as u8, b: value as u8 });
+    }
+    if let Ok(index) = s.parse::<u8>() {
+        return Ok(Color::AnsiValue(index));
+    }
+    Color::try_from(s.replace('-', "_").as_str()).map_err(|_| format!("unknown colour '{s}'"))
+}
+
+#[derive(Copy, Clone, PartialEq)]
+pub enum ColorDepth {
+    TrueColor,
+    Ansi256,
+    Ansi16,
+}
+
+impl ColorDepth {
+    pub fn detect() -> ColorDepth {
+        let colorterm = env::var("COLORTERM").unwrap_or_default();
+        let term = env::var("TERM").unwrap_or_default();
+        if colorterm == "truecolor" || colorterm == "24bit" {
+            ColorDepth::TrueColor
+        } else if term.contains("256color") {
+            ColorDepth::Ansi256
+        } else {
+            ColorDepth::Ansi16
+        }
+    }
+
+    // the closest colour the terminal can show
+    pub fn adapt(&self, color: Color) -> Color {
+        match (self, color) {
+            (ColorDepth::TrueColor, _) => color,
+            (ColorDepth::Ansi256, Color::Rgb { r, g, b }) => Color::AnsiValue(rgb_to_256(r, g, b)),
+            (ColorDepth::Ansi16, Color::Rgb { r, g, b }) => nearest_16(r, g, b),
+            (ColorDepth::Ansi16, Color::AnsiValue(n)) => {
+                let (r, g, b) = ansi_to_rgb(n);
+                nearest_16(r, g, b)
+            }
+            _ => color,
+        }
+    }
+}
+
+// xterm's 16 colours, in crossterm's order for indexes 0-15
+const ANSI_16: [(Color, (u8, u8, u8)); 16] = [
+    (Color::Black, (0, 0, 0)),
+    (Color::DarkRed, (205, 0, 0)),
+    (Color::DarkGreen, (0, 205, 0)),
+    (Color::DarkYellow, (205, 205, 0)),
+    (Color::DarkBlue, (0, 0, 238)),
+    (Color::DarkMagenta, (205, 0, 205)),
+    (Color::DarkCyan, (0, 205, 205)),
+    (Color::Grey, (229, 229, 229)),
+    (Color::DarkGrey, (127, 127, 127)),
+    (Color::Red, (255, 0, 0)),
+    (Color::Green, (0, 255, 0)),
+    (Color::Yellow, (255, 255, 0)),
+    (Color::Blue, (92, 92, 255)),
+    (Color::Magenta, (255, 0, 255)),
+    (Color::Cyan, (0, 255, 255)),
+    (Color::White, (255, 255, 255)),
+];
+
+const CUBE_LEVELS: [u8; 6] = [0, 95, 135, 175, 215, 255];
+
+fn distance(a: (u8, u8, u8), b: (u8, u8, u8)) -> u32 {
+    let d = |x: u8, y: u8| (x as i32 - y as i32).pow(2) as u32;
+    d(a.0, b.0) + d(a.1, b.1) + d(a.2, b.2)
+}
+
+fn ansi_to_rgb(n: u8) -> (u8, u8, u8) {
+    match n {
+        0..=15 => ANSI_16[n as usize].1,
+        16..=231 => {
+            let n = n - 16;
+            (CUBE_LEVELS[(n / 36) as usize], CUBE_LEVELS[(n / 6 % 6) as usize], CUBE_LEVELS[(n % 6) as usize])
+        }
+        _ => {
+            let level = 8 + (n - 232) * 10;
+            (level, level, level)
+        }
+    }
+}
+
+// best of the 6x6x6 cube and the grey ramp
+fn rgb_to_256(r: u8, g: u8, b: u8) -> u8 {
+    let level = |v: u8| CUBE_LEVELS.iter()
+        .enumerate()
+        .min_by_key(|(_, l)| (v as i32 - **l as i32).abs())
+        .map_or(0, |(i, _)| i as u8);
+    let cube = 16 + 36 * level(r) + 6 * level(g) + level(b);
+    let average = ((r as u16 + g as u16 + b as u16) / 3) as u8;
+    let grey = 232 + (average.saturating_sub(3) / 10).min(23);
+    if distance((r, g, b), ansi_to_rgb(grey)) < distance((r, g, b), ansi_to_rgb(cube)) {
+        grey
+    } else {
+        cube
+    }
+}
+
+fn nearest_16(r: u8, g: u8, b: u8) -> Color {
+    ANSI_16.iter()
+        .min_by_key(|(_, rgb)| distance((r, g, b), *rgb))
+        .map_or(Color::Reset, |(color, _)| *color)
+}
+
+pub struct Theme {
+    styles: HashMap<&'static str, Style>,
+}
+
+impl Default for Theme {
+    fn default() -> Self {
+        let styles = HashMap::from([
+            ("number", Style::fg(Color::Red)),
+            ("string", Style::fg(Color::Magenta)),
+            ("comment", Style::fg(Color::Cyan)),
+            ("keyword1", Style::fg(Color::Yellow)),
+            ("keyword2", Style::fg(Color::Green)),
+            ("match", Style::fg(Color::Blue)),
+            ("selection", Style::reverse()),
+            ("status-bar", Style::reverse()),
+            ("message-line", Style::reverse()),
+            ("popup", Style::reverse()),
+            ("git-added", Style::fg(Color::Green)),
+            ("git-modified", Style::fg(Color::Yellow)),
+            ("git-deleted", Style::fg(Color::Red)),
+            ("diagnostic-error", Style::fg(Color::Red)),
+            ("diagnostic-warning", Style::fg(Color::Yellow)),
+            ("diagnostic-info", Style::fg(Color::Blue)),
+            ("diagnostic-hint", Style::fg(Color::DarkGrey)),
+            ("diagnostic-underline", Style { underline: true, ..Style::default() }),
+            ("misspelled", Style { underline: true, ..Style::fg(Color::Red) }),
+            ("fold", Style::fg(Color::DarkGrey)),
+        ]);
+        Self { styles }
+    }
+}
+
+impl Theme {
+    pub fn style(&self, element: &str) -> Style {
+        self.styles.get(element).copied().unwrap_or_default()
+    }
+
+    // Themes live in themes/NAME under the config directory, one
+    // `element = style` per line. Elements they leave out keep the
+    // default theme's style.
+    pub fn load(name: &str) -> StdResult<Theme, String> {
+        let mut theme = Theme::default();
+        if name == "default" {
+            return Ok(theme);
+        }
+        let path = config::config_file("themes")
+            .map(|dir| dir.join(name))
+            .filter(|path| path.exists())
+            .ok_or_else(|| format!("no theme named '{name}'"))?;
+        config::for_each_line(&path, |line| {
+            let (element, spec) = line.split_once('=').ok_or("expected element = style")?;
+            let element = element.trim();
+            let element = ELEMENTS.iter()
+                .find(|e| **e == element)
+                .ok_or_else(|| format!("unknown element '{element}'"))?;
+            theme.styles.insert(element, Style::parse(spec)?);
+            Ok(())
+        })?;
+        Ok(theme)
+    }
+}
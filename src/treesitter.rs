@@ -0,0 +1,215 @@
+use std::ops::Range;
+use streaming_iterator::StreamingIterator;
+use tree_sitter::{InputEdit, Language, Parser, Point, Query, QueryCursor, Tree};
+
+use crate::row::{Highlight, Row};
+
+// Highlighting from a tree-sitter parse of the whole buffer. The captures of
+// the grammar's highlight query are mapped onto Highlight classes; filetypes
+// without a grammar keep the keyword scanner in Row::update_syntax.
+
+fn grammar(filetype: &str) -> Option<(Language, &'static str)> {
+    match filetype {
+        "c" => Some((tree_sitter_c::LANGUAGE.into(), tree_sitter_c::HIGHLIGHT_QUERY)),
+        "rust" => Some((tree_sitter_rust::LANGUAGE.into(), tree_sitter_rust::HIGHLIGHTS_QUERY)),
+        _ => None,
+    }
+}
+
+// Capture names are dotted, most general part first. Anything not listed
+// here (variables, functions, punctuation...) is drawn as normal text.
+fn class(capture: &str) -> Option<Highlight> {
+    match capture.split('.').next()? {
+        "comment" => Some(Highlight::Comment),
+        "string" | "escape" | "char" => Some(Highlight::String),
+        "number" | "float" | "boolean" => Some(Highlight::Number),
+        "constant" if capture == "constant.builtin" => Some(Highlight::Number),
+        "keyword" => Some(Highlight::Keyword1),
+        "type" | "constructor" => Some(Highlight::Keyword2),
+        _ => None,
+    }
+}
+
+fn line_start(text: &str, line: usize) -> usize {
+    if line == 0 {
+        return 0;
+    }
+    text.match_indices('\n').nth(line - 1).map_or(text.len(), |(i, _)| i + 1)
+}
+
+// where text inserted at start ends
+fn advance(start: Point, text: &str) -> Point {
+    match text.rfind('\n') {
+        Some(last) => Point { row: start.row + text.matches('\n').count(), column: text.len() - last - 1 },
+        None => Point { row: start.row, column: start.column + text.len() },
+    }
+}
+
+pub struct SyntaxTree {
+    parser: Parser,
+    query: Query,
+    classes: Vec<Option<Highlight>>,    // by capture index
+    tree: Option<Tree>,
+    edited: Option<Range<usize>>,       // lines changed since the last parse
+}
+
+impl SyntaxTree {
+    pub fn for_filetype(filetype: &str) -> Option<SyntaxTree> {
+        let (language, source) = grammar(filetype)?;
+        let mut parser = Parser::new();
+        parser.set_language(&language).ok()?;
+        let query = Query::new(&language, source).ok()?;
+        let classes = query.capture_names().iter().map(|name| class(name)).collect();
+        Some(SyntaxTree { parser, query, classes, tree: None, edited: None })
+    }
+
+    pub fn is_parsed(&self) -> bool {
+        self.tree.is_some()
+    }
+
+    // false while the last parse still matches the buffer
+    pub fn needs_update(&self) -> bool {
+        self.tree.is_none() || self.edited.is_some()
+    }
+
+    // Tells the tree that `removed`, starting at byte `byte` and (line,
+    // byte column) `at`, was replaced by `inserted`. Edits before the first
+    // parse don't matter.
+    pub fn edit(&mut self, byte: usize, at: (usize, usize), removed: &str, inserted: &str) {
+        let Some(tree) = self.tree.as_mut() else { return };
+        let start = Point { row: at.0, column: at.1 };
+        let (old_end, new_end) = (advance(start, removed), advance(start, inserted));
+        tree.edit(&InputEdit {
+            start_byte: byte,
+            old_end_byte: byte + removed.len(),
+            new_end_byte: byte + inserted.len(),
+            start_position: start,
+            old_end_position: old_end,
+            new_end_position: new_end,
+        });
+        let lines = self.edited.get_or_insert(start.row..start.row + 1);
+        // lines edited earlier below this edit have moved with it
+        if lines.end > old_end.row + 1 {
+            lines.end = lines.end + new_end.row - old_end.row;
+        }
+        lines.start = lines.start.min(start.row);
+        lines.end = lines.end.max(new_end.row + 1);
+    }
+
+    // Reparses the buffer, reusing the edited tree, and highlights the
+    // lines that were edited plus any whose parse changed because of them.
+    pub fn update(&mut self, text: String, rows: &mut [Row]) {
+        let mut lines = match (&self.tree, self.edited.take()) {
+            (Some(_), None) => return,
+            (Some(_), Some(lines)) => lines,
+            (None, _) => 0..rows.len(),
+        };
+        let Some(tree) = self.parser.parse(&text, self.tree.as_ref()) else { return };
+        if let Some(old) = &self.tree {
+            for range in old.changed_ranges(&tree) {
+                lines.start = lines.start.min(range.start_point.row);
+                lines.end = lines.end.max(range.end_point.row + 1);
+            }
+        }
+        self.highlight(&tree, &text, rows, lines);
+        self.tree = Some(tree);
+    }
+
+    fn highlight(&self, tree: &Tree, text: &str, rows: &mut [Row], lines: Range<usize>) {
+        let lines = lines.start.min(rows.len())..lines.end.min(rows.len());
+        for row in &mut rows[lines.clone()] {
+            row.clear_highlight();
+        }
+        let mut cursor = QueryCursor::new();
+        cursor.set_byte_range(line_start(text, lines.start)..line_start(text, lines.end));
+        // later captures win: they are inner nodes or more specific patterns
+        let mut captures = cursor.captures(&self.query, tree.root_node(), text.as_bytes());
+        while let Some((found, i)) = captures.next() {
+            let capture = found.captures[*i];
+            let Some(hl) = self.classes[capture.index as usize] else { continue };
+            let (start, end) = (capture.node.start_position(), capture.node.end_position());
+            let spanned = rows.iter_mut().enumerate()
+                .take((end.row + 1).min(lines.end))
+                .skip(start.row.max(lines.start));
+            for (line, row) in spanned {
+                let from = if line == start.row { start.column } else { 0 };
+                let to = if line == end.row { end.column } else { usize::MAX };
+                row.set_highlight(from, to, hl);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(rows: &[Row]) -> String {
+        rows.iter().map(|row| row.chars.clone() + "\n").collect()
+    }
+
+    // what a parse from scratch makes of the rows
+    fn assert_fresh(rows: &[Row]) {
+        let mut fresh: Vec<Row> = rows.iter().map(|row| Row::new(row.chars.clone(), None)).collect();
+        SyntaxTree::for_filetype("c").unwrap().update(text(rows), &mut fresh);
+        for (row, fresh) in rows.iter().zip(&fresh) {
+            assert!(row.hl == fresh.hl, "{:?} highlighted differently", row.chars);
+        }
+    }
+
+    // replaces removed at (y, x) with inserted, as the editor does: only
+    // the rows it touches are made again, without highlighting
+    fn edit(tree: &mut SyntaxTree, rows: &mut Vec<Row>, (y, x): (usize, usize), removed: &str, inserted: &str) {
+        let all = text(rows);
+        let byte = line_start(&all, y) + x;
+        tree.edit(byte, (y, x), removed, inserted);
+        let edited = format!("{}{inserted}{}", &all[..byte], &all[byte + removed.len()..]);
+        let (old_end, new_end) = (y + removed.matches('\n').count(), y + inserted.matches('\n').count());
+        let made = edited.lines().skip(y).take(new_end + 1 - y).map(|line| Row::new(line.to_string(), None));
+        rows.splice(y..=old_end, made);
+    }
+
+    #[test]
+    fn follows_edits_without_diffing() {
+        let mut rows: Vec<Row> = ["int a;", "char *s = \"x\";", "int b; /* end */"]
+            .iter().map(|line| Row::new(line.to_string(), None)).collect();
+        let mut tree = SyntaxTree::for_filetype("c").unwrap();
+        assert!(tree.needs_update());
+        tree.update(text(&rows), &mut rows);
+        assert!(!tree.needs_update());
+        assert_fresh(&rows);
+
+        // nothing edited, nothing redone
+        let hl = std::mem::take(&mut rows[2].hl);
+        tree.update(text(&rows), &mut rows);
+        assert!(rows[2].hl.is_empty());
+        rows[2].hl = hl;
+
+        // opening a comment reaches lines that weren't edited
+        edit(&mut tree, &mut rows, (0, 0), "", "/* ");
+        assert!(tree.needs_update());
+        tree.update(text(&rows), &mut rows);
+        assert!(rows[2].hl[0] == Highlight::Comment);
+        assert_fresh(&rows);
+
+        // several edits between updates: join two lines, then split one
+        edit(&mut tree, &mut rows, (0, 9), "\nchar *s = ", "");
+        edit(&mut tree, &mut rows, (1, 6), "", " int c;\n");
+        tree.update(text(&rows), &mut rows);
+        assert_fresh(&rows);
+
+        edit(&mut tree, &mut rows, (0, 0), "/* ", "");
+        edit(&mut tree, &mut rows, (1, 0), "", "\n");
+        tree.update(text(&rows), &mut rows);
+        assert_fresh(&rows);
+    }
+
+    #[test]
+    fn advances_over_inserted_text() {
+        let start = Point { row: 2, column: 4 };
+        assert_eq!(advance(start, ""), start);
+        assert_eq!(advance(start, "abc"), Point { row: 2, column: 7 });
+        assert_eq!(advance(start, "ab\n\ncd"), Point { row: 4, column: 2 });
+        assert_eq!(advance(start, "ab\n"), Point { row: 3, column: 0 });
+    }
+}
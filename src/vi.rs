@@ -0,0 +1,281 @@
+use crossterm::event::{KeyCode, KeyModifiers};
+use crate::keymap::Key;
+
+// handled by Editor::ex_command before the command line proper
+pub const EX_COMMANDS: &[&str] = &["w", "q", "q!", "wq", "x"];
+
+#[derive(Copy, Clone, PartialEq)]
+pub enum Mode {
+    Normal,
+    Insert,
+    Visual,
+    CommandLine,
+}
+
+impl Mode {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Mode::Normal => "NORMAL",
+            Mode::Insert => "INSERT",
+            Mode::Visual => "VISUAL",
+            Mode::CommandLine => "COMMAND",
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq)]
+pub enum Motion {
+    Left,
+    Right,
+    Up,
+    Down,
+    WordForward,
+    WordBackward,
+    WordEnd,
+    LineStart,
+    LineEnd,
+    FirstLine,
+    LastLine,
+    GotoLine(usize),
+    Line,            // the current line(s), for dd / cc / yy
+}
+
+impl Motion {
+    pub fn is_linewise(&self) -> bool {
+        matches!(self, Motion::Up | Motion::Down | Motion::FirstLine |
+            Motion::LastLine | Motion::GotoLine(_) | Motion::Line)
+    }
+}
+
+#[derive(Copy, Clone, PartialEq)]
+pub enum Operator {
+    Delete,
+    Change,
+    Yank,
+}
+
+#[derive(Copy, Clone, PartialEq)]
+pub enum InsertAt {
+    Cursor,           // i
+    After,            // a
+    LineStart,        // I
+    LineEnd,          // A
+    LineBelow,        // o
+    LineAbove,        // O
+}
+
+pub enum Action {
+    None,
+    PassThrough,                       // not a vi key, hand it to the keymap
+    Move(Motion, usize),
+    Operate(Operator, Motion, usize),
+    OperateSelection(Operator),
+    DeleteChar(usize),
+    Insert(InsertAt),
+    ExitInsert,
+    Paste { after: bool, count: usize },
+    EnterVisual,
+    ExitVisual,
+    CommandLine,
+}
+
+pub struct Vi {
+    pub mode: Mode,
+    count: Option<usize>,
+    operator: Option<(Operator, usize)>,  // operator and the count typed before it
+    pending_g: bool,
+}
+
+impl Vi {
+    pub fn new() -> Self {
+        Self {
+            mode: Mode::Normal,
+            count: None,
+            operator: None,
+            pending_g: false,
+        }
+    }
+
+    fn reset(&mut self) {
+        self.count = None;
+        self.operator = None;
+        self.pending_g = false;
+    }
+
+    // count typed so far, including one given before an operator (2d3w = 6)
+    fn take_count(&mut self) -> usize {
+        let count = self.count.take().unwrap_or(1);
+        match self.operator {
+            Some((_, op_count)) => count * op_count,
+            None => count,
+        }
+    }
+
+    pub fn feed(&mut self, key: Key) -> Action {
+        match self.mode {
+            Mode::Insert => {
+                if key.code == KeyCode::Esc {
+                    self.mode = Mode::Normal;
+                    Action::ExitInsert
+                } else {
+                    Action::PassThrough
+                }
+            }
+            Mode::CommandLine => Action::None,
+            Mode::Normal | Mode::Visual => self.feed_normal(key),
+        }
+    }
+
+    fn feed_normal(&mut self, key: Key) -> Action {
+        if key.code == KeyCode::Esc {
+            self.reset();
+            if self.mode == Mode::Visual {
+                self.mode = Mode::Normal;
+                return Action::ExitVisual;
+            }
+            return Action::None;
+        }
+
+        let c = match key.code {
+            KeyCode::Char(c) if key.modifiers == KeyModifiers::NONE => c,
+            _ => {
+                self.reset();
+                return Action::PassThrough;
+            }
+        };
+
+        if self.pending_g {
+            self.pending_g = false;
+            if c == 'g' {
+                let motion = match self.count {
+                    Some(n) => Motion::GotoLine(n),
+                    None => Motion::FirstLine,
+                };
+                self.count = None;
+                return self.motion(motion);
+            }
+            self.reset();
+            return Action::None;
+        }
+
+        match c {
+            '1'..='9' | '0' if c != '0' || self.count.is_some() => {
+                let digit = c.to_digit(10).unwrap() as usize;
+                self.count = Some(self.count.unwrap_or(0).saturating_mul(10).saturating_add(digit));
+                Action::None
+            }
+            'h' => self.motion(Motion::Left),
+            'j' => self.motion(Motion::Down),
+            'k' => self.motion(Motion::Up),
+            'l' => self.motion(Motion::Right),
+            'w' => {
+                // cw changes to the end of the word, like vim
+                if matches!(self.operator, Some((Operator::Change, _))) {
+                    self.motion(Motion::WordEnd)
+                } else {
+                    self.motion(Motion::WordForward)
+                }
+            }
+            'b' => self.motion(Motion::WordBackward),
+            'e' => self.motion(Motion::WordEnd),
+            '0' => self.motion(Motion::LineStart),
+            '$' => self.motion(Motion::LineEnd),
+            'G' => {
+                let motion = match self.count.take() {
+                    Some(n) => Motion::GotoLine(n),
+                    None => Motion::LastLine,
+                };
+                self.motion(motion)
+            }
+            'g' => {
+                self.pending_g = true;
+                Action::None
+            }
+            'd' | 'c' | 'y' => {
+                let op = match c {
+                    'd' => Operator::Delete,
+                    'c' => Operator::Change,
+                    _ => Operator::Yank,
+                };
+                if self.mode == Mode::Visual {
+                    self.reset();
+                    self.mode = if op == Operator::Change { Mode::Insert } else { Mode::Normal };
+                    return Action::OperateSelection(op);
+                }
+                match self.operator {
+                    Some((pending, _)) if pending == op => self.motion(Motion::Line),
+                    Some(_) => {
+                        self.reset();
+                        Action::None
+                    }
+                    None => {
+                        let count = self.count.take().unwrap_or(1);
+                        self.operator = Some((op, count));
+                        Action::None
+                    }
+                }
+            }
+            'x' => {
+                if self.mode == Mode::Visual {
+                    self.reset();
+                    self.mode = Mode::Normal;
+                    return Action::OperateSelection(Operator::Delete);
+                }
+                let count = self.take_count();
+                self.reset();
+                Action::DeleteChar(count)
+            }
+            'p' | 'P' => {
+                let count = self.take_count();
+                self.reset();
+                Action::Paste { after: c == 'p', count }
+            }
+            'i' | 'a' | 'I' | 'A' | 'o' | 'O' if self.mode == Mode::Normal => {
+                self.reset();
+                self.mode = Mode::Insert;
+                Action::Insert(match c {
+                    'i' => InsertAt::Cursor,
+                    'a' => InsertAt::After,
+                    'I' => InsertAt::LineStart,
+                    'A' => InsertAt::LineEnd,
+                    'o' => InsertAt::LineBelow,
+                    _ => InsertAt::LineAbove,
+                })
+            }
+            'v' => {
+                self.reset();
+                if self.mode == Mode::Visual {
+                    self.mode = Mode::Normal;
+                    Action::ExitVisual
+                } else {
+                    self.mode = Mode::Visual;
+                    Action::EnterVisual
+                }
+            }
+            ':' => {
+                self.reset();
+                self.mode = Mode::CommandLine;
+                Action::CommandLine
+            }
+            _ => {
+                self.reset();
+                Action::None
+            }
+        }
+    }
+
+    fn motion(&mut self, motion: Motion) -> Action {
+        let count = self.take_count();
+        let action = match self.operator {
+            Some((op, _)) => {
+                if op == Operator::Change {
+                    self.mode = Mode::Insert;
+                }
+                Action::Operate(op, motion, count)
+            }
+            None => Action::Move(motion, count),
+        };
+        self.reset();
+        action
+    }
+}